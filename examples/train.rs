@@ -36,6 +36,7 @@ fn load_initial_level(mut commands: Commands) {
                 (36.0, 2.2, 28.0),
                 (60.0, 2.2, 60.0),
             ],
+            earthworks: true,
         ),
     }
 )
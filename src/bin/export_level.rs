@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use bevy::log::{error, info};
+use bevy::math::{Rect, Vec2, Vec3};
+use thiserror::Error;
+
+use rreng::export::{export_range, write_geotiff, ExportError, GlbWriter};
+use rreng::level::datafile::{DataFile, TrackToLoad};
+use rreng::terrain::rendering::{create_block_meshes, layer_material};
+use rreng::terrain::tiles::{decode_elevation, ElevationFileLoaderError, TileSets};
+use rreng::terrain::{Terrain, TerrainData, TerrainLayer};
+use rreng::track::bridge::{bridge_material, create_deck_mesh, create_pillar_mesh, find_bridge_spans, span_transforms, BridgeParams};
+use rreng::track::curve::SegmentCurve;
+use rreng::track::earthworks::{curve_earthworks, CorridorProfile};
+use rreng::track::point::point_rotation;
+use rreng::track::rendering::{create_bed_mesh, create_rail_mesh, create_sleeper_mesh, cut_normal, segment_path, sleeper_transforms, to_segment_space};
+use rreng::track::segment::segment_transform;
+use rreng::track::style::TrackStyle;
+
+const ASSETS_PATH: &str = "assets";
+const TILESETS_PATH: &str = "data/tiles.ron";
+const DEFAULT_LEVEL: usize = 2;
+
+#[derive(Error, Debug)]
+enum LoadError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Ron(#[from] ron::de::SpannedError),
+    #[error(transparent)]
+    Elevation(#[from] ElevationFileLoaderError),
+}
+
+fn load_ron<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T, LoadError> {
+    let str = std::fs::read_to_string(path)?;
+    Ok(ron::from_str(&str)?)
+}
+
+/**
+ * Load the terrain for a level straight from its tiles, the same way the game does.
+ */
+fn load_terrain(datafile: &DataFile) -> Result<(Terrain, TerrainData), LoadError> {
+    let mut terrain = Terrain::default();
+    let mut terrain_data = TerrainData::default();
+    terrain.reset(datafile);
+    terrain_data.reset(&terrain, datafile);
+
+    let tilesets_path = Path::new(ASSETS_PATH).join(TILESETS_PATH);
+    let tilesets: TileSets = load_ron(&tilesets_path)?;
+
+    for tileset in tilesets.0.values() {
+        if !datafile.layers.contains(&tileset.layer) { continue; }
+
+        let tileset_path = tilesets_path.parent().unwrap().join(&tileset.root);
+        for (name, tile) in &tileset.files {
+            if terrain.bounds.intersect(tile.bounds).is_empty() { continue; }
+
+            info!("Loading {name}");
+            let bytes = std::fs::read(tileset_path.join(name))?;
+            let elevation_file = decode_elevation(&bytes)?;
+            let offset = terrain.coord_to_offset(Vec2::new(tile.bounds.min.x, tile.bounds.max.y));
+            terrain_data.set_elevation(offset, elevation_file.heights.view(), tileset.layer);
+        }
+    }
+
+    Ok((terrain, terrain_data))
+}
+
+/**
+ * The curves of a track's segments, as laid out when the level is loaded.
+ */
+fn track_curves(track: &TrackToLoad) -> Vec<SegmentCurve> {
+    track.points.windows(2).zip(track.segment_shapes())
+        .map(|(w, shape)| SegmentCurve::new(w[0], w[1], shape))
+        .collect()
+}
+
+/**
+ * Dig the earthworks along the tracks that have them, as the game does once they are
+ * loaded, so the terrain and bridges match it.
+ */
+fn dig_earthworks(terrain_data: &TerrainData, datafile: &DataFile) {
+    let Some(elevation) = terrain_data.layers.get(&TerrainLayer::Elevation).cloned()
+    else { return; };
+
+    let profile = CorridorProfile::default();
+    let mut elevation = elevation.writer();
+    for (name, track) in datafile.tracks.iter().filter(|(_, track)| track.earthworks) {
+        for curve in track_curves(track) {
+            curve_earthworks(&curve, &profile, &mut elevation, &terrain_data.soil);
+        }
+        info!("Dug earthworks for {name}");
+    }
+}
+
+/**
+ * Add a level's terrain, at one level of detail, and its tracks and their bridges to a
+ * glTF file.
+ */
+fn build_scene(terrain: &Terrain, terrain_data: &TerrainData, datafile: &DataFile, styles: &HashMap<String, TrackStyle>, level: usize) -> Result<GlbWriter, ExportError> {
+    let mut glb = GlbWriter::default();
+
+    for (layer, data) in &terrain_data.layers {
+        let material = layer_material(*layer);
+        for (block_id, transform, mesh) in create_block_meshes(terrain, data, *layer, level) {
+            let name = format!("{:?} {},{}@{}", layer, block_id.row, block_id.col, block_id.level);
+            glb.add_mesh(&name, &mesh, transform.compute_matrix(), &material)?;
+        }
+    }
+
+    let ground = |pos: Vec2| terrain_data.elevation_at(pos);
+    let bridge_params = BridgeParams::default();
+    let (deck_mesh, pillar_mesh, bridge_material) = (create_deck_mesh(), create_pillar_mesh(), bridge_material());
+
+    for (name, track) in &datafile.tracks {
+        let style = track.style.as_ref().and_then(|path| styles.get(path)).cloned().unwrap_or_default();
+        let (rail_material, sleeper_material, bed_material) = (style.rail_material.material(), style.sleeper_material.material(), style.bed_material.material());
+        let sleeper_mesh = create_sleeper_mesh(style.sleeper_dims);
+
+        let points = &track.points;
+        let transforms: Vec<_> = points.windows(2).map(|w| segment_transform(w[0], w[1])).collect();
+        let curves = track_curves(track);
+
+        /* Each point is angled between the segments it joins */
+        let point_rotations: Vec<_> = (0..points.len()).map(|i| {
+            let before = i.checked_sub(1).and_then(|s| curves.get(s)).map(|c| -c.direction_at(c.length()));
+            let after = curves.get(i).map(|c| -c.direction_at(0.0));
+            point_rotation(before.into_iter().chain(after).sum::<Vec3>())
+        }).collect();
+
+        for (i, (transform, curve)) in transforms.iter().zip(&curves).enumerate() {
+            let path = segment_path(curve, transform);
+            let open_start = i > 0;
+            let open_end = i + 1 < transforms.len();
+            let start_normal = cut_normal(curve.transform_at(0.0).rotation, point_rotations[i]);
+            let end_normal = cut_normal(curve.transform_at(curve.length()).rotation, point_rotations[i + 1]);
+            let matrix = transform.compute_matrix();
+            let name = format!("Track:{name} {i}");
+
+            let rail_mesh = create_rail_mesh(&style, &path, open_start, open_end, start_normal, end_normal);
+            glb.add_mesh(&name, &rail_mesh, matrix, &rail_material)?;
+            let bed_mesh = create_bed_mesh(&style, &path, open_start, open_end, start_normal, end_normal);
+            glb.add_mesh(&name, &bed_mesh, matrix, &bed_material)?;
+            let frame_at = |distance| to_segment_space(transform, curve.transform_at(distance));
+            for sleeper_transform in sleeper_transforms(&style, curve.length(), frame_at) {
+                glb.add_mesh(&name, &sleeper_mesh, matrix * sleeper_transform.compute_matrix(), &sleeper_material)?;
+            }
+
+            for span in find_bridge_spans(curve, ground, &bridge_params) {
+                let (decks, pillars) = span_transforms(curve, &span);
+                for deck in decks {
+                    glb.add_mesh(&name, &deck_mesh, deck.compute_matrix(), &bridge_material)?;
+                }
+                for pillar in pillars {
+                    glb.add_mesh(&name, &pillar_mesh, pillar.compute_matrix(), &bridge_material)?;
+                }
+            }
+        }
+    }
+
+    Ok(glb)
+}
+
+fn export(datafile_path: &Path, output_path: &Path, level: usize, region: Option<Rect>) -> Result<(), Box<dyn std::error::Error>> {
+    let datafile: DataFile = load_ron(datafile_path)?;
+    let (terrain, terrain_data) = load_terrain(&datafile)?;
+    dig_earthworks(&terrain_data, &datafile);
+
+    std::fs::create_dir_all(output_path)?;
+
+    for (layer, data) in &terrain_data.layers {
+        let path = output_path.join(format!("{layer:?}.tif").to_lowercase());
+        let f = std::io::BufWriter::new(std::fs::File::create(&path)?);
+        write_geotiff(f, &terrain, data, export_range(&terrain, data, region))?;
+        info!("Exported {layer:?} to {path:?}");
+    }
+
+    let mut styles = HashMap::new();
+    for path in datafile.tracks.values().filter_map(|track| track.style.as_ref()) {
+        let style: TrackStyle = load_ron(&Path::new(ASSETS_PATH).join(path))?;
+        style.validate()?;
+        styles.insert(path.clone(), style);
+    }
+
+    let glb = build_scene(&terrain, &terrain_data, &datafile, &styles, level)?;
+    let path = output_path.join("level.glb");
+    glb.write(std::io::BufWriter::new(std::fs::File::create(&path)?))?;
+    info!("Exported scene to {path:?}");
+
+    Ok(())
+}
+
+/**
+ * Region of the terrain to export, as "min x,min y,max x,max y" in the coordinate
+ * system of its bounds.
+ */
+fn parse_region(s: &str) -> Option<Rect> {
+    let values: Vec<f32> = s.split(',').map(|v| v.trim().parse().ok()).collect::<Option<_>>()?;
+    let [x0, y0, x1, y1] = values[..] else { return None; };
+    Some(Rect::new(x0, y0, x1, y1))
+}
+
+fn main() {
+    tracing_subscriber::fmt::init();
+
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 2 {
+        eprintln!("Usage: {} <datafile.ron> [output directory] [level of detail] [min x,min y,max x,max y]", args[0]);
+        std::process::exit(1);
+    }
+
+    let datafile_path = PathBuf::from(&args[1]);
+    let output_path = PathBuf::from(args.get(2).map_or("export", |s| s.as_str()));
+    let level = args.get(3).and_then(|s| s.parse().ok()).unwrap_or(DEFAULT_LEVEL);
+    let region = match args.get(4).map(|s| parse_region(s)) {
+        Some(None) => {
+            eprintln!("Region should be given as min x,min y,max x,max y");
+            std::process::exit(1);
+        }
+        Some(region) => region,
+        None => None,
+    };
+
+    if let Err(err) = export(&datafile_path, &output_path, level, region) {
+        error!("Export failed: {err}");
+        std::process::exit(1);
+    }
+}
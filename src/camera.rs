@@ -11,6 +11,8 @@
 use crate::level::LevelLabel;
 use crate::screens::Screen;
 use crate::terrain::TerrainData;
+use crate::track::curve::SegmentCurve;
+use crate::track::tunnel::{self, Tunnel};
 
 pub struct CameraPlugin;
 
@@ -147,6 +149,7 @@ fn camera_movement(time: Res<Time<Real>>,
 fn update_camera_position(
     mut camera: Single<(&CameraState, &mut Transform), Changed<CameraState>>,
     terrain_data: Option<Single<&TerrainData, With<LevelLabel>>>,
+    tunnels: Query<(&Tunnel, &SegmentCurve)>,
     mut events: EventWriter<GraphicsEvent>,
 ) {
     let (state, transform) = &mut *camera;
@@ -154,9 +157,16 @@ fn update_camera_position(
     transform.rotation = Quat::from_axis_angle(Vec3::Y, state.yaw)
         * Quat::from_axis_angle(Vec3::X, state.pitch);
     let up_to_camera = transform.rotation.mul_vec3(Vec3::Z);
-    let mut focus = state.focus;
-    focus.y = terrain_data.map_or(0.0, |td| td.elevation_at(focus.xz()));
-    transform.translation = focus + state.distance * up_to_camera;
+
+    /* Over a tunnel, look at the track in it rather than the ground above */
+    let (focus, distance) = match tunnel::bore_frame(state.focus.xz(), tunnels.iter()) {
+        Some(frame) => tunnel::camera_in_bore(&frame, state.focus.xz(), up_to_camera, state.distance),
+        None => {
+            let height = terrain_data.map_or(0.0, |td| td.elevation_at(state.focus.xz()));
+            (state.focus.with_y(height), state.distance)
+        }
+    };
+    transform.translation = focus + distance * up_to_camera;
 
     events.write(GraphicsEvent::MoveCamera);
 }
@@ -35,6 +35,7 @@
 use crate::terrain::Terrain;
 use crate::terrain::rendering::{LayerLabel, TerrainMesh};
 use crate::track::bridge::Bridge;
+use crate::track::tunnel::Tunnel;
 use crate::track::point::Point;
 use crate::track::segment::Segment;
 use crate::train::TrainCar;
@@ -197,6 +198,7 @@ fn row(ui: &mut egui::Ui, name: &str, value: usize) {
                     row(ui, "Points", world.query::<&Point>().iter(&world).count());
                     row(ui, "Segments", world.query::<&Segment>().iter(&world).count());
                     row(ui, "Bridges", world.query::<&Bridge>().iter(&world).count());
+                    row(ui, "Tunnels", world.query::<&Tunnel>().iter(world).count());
 
                     ui.heading("Trains");
                     ui.end_row();
@@ -0,0 +1,401 @@
+use std::io::{Seek, Write};
+use std::path::Path;
+
+use bevy::input::common_conditions::input_just_pressed;
+use bevy::prelude::*;
+use bevy::render::mesh::{Indices, PrimitiveTopology};
+use serde_json::{json, Value};
+use thiserror::Error;
+use tiff::encoder::{colortype, TiffEncoder};
+use tiff::tags::Tag;
+
+use crate::level::LevelLabel;
+use crate::screens::Screen;
+use crate::terrain::chunks::ChunkedArray;
+use crate::terrain::rendering::TerrainMesh;
+use crate::terrain::utils::Range2;
+use crate::terrain::{Terrain, TerrainData};
+use crate::track::segment::Segment;
+
+const EXPORT_DIR: &str = "export";
+
+/**
+ * Coordinate system of the terrain tiles, and so of exported terrain: NZGD2000 / New
+ * Zealand Transverse Mercator 2000.
+ */
+pub const PROJECTED_CRS: u16 = 2193;
+
+pub struct ExportPlugin;
+
+impl Plugin for ExportPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .register_type::<ExportRegion>()
+            .init_resource::<ExportRegion>()
+            .add_systems(Update, export_level
+                .run_if(in_state(Screen::Playing).and(input_just_pressed(KeyCode::F9))));
+    }
+}
+
+/**
+ * Part of the terrain exported to GeoTIFFs, in the coordinate system of its bounds, or
+ * all of it if none.
+ */
+#[derive(Default, Reflect, Resource)]
+#[reflect(Resource)]
+pub struct ExportRegion(pub Option<Rect>);
+
+#[non_exhaustive]
+#[derive(Debug, Error)]
+pub enum ExportError {
+    #[error("Could not write file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Could not encode TIF: {0}")]
+    Tiff(#[from] tiff::TiffError),
+    #[error("Could not encode glTF: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Could not export mesh: {0}")]
+    Mesh(String),
+    #[error("Nothing to export")]
+    Empty,
+}
+
+/**
+ * Write each terrain layer, or the part of it in the export region, to a GeoTIFF, and the
+ * visible terrain and tracks to a glTF binary file, in the export directory.
+ */
+pub fn export_level(
+    level: Single<(&Terrain, &TerrainData), With<LevelLabel>>,
+    scene_meshes: Query<(Entity, &Mesh3d, &MeshMaterial3d<StandardMaterial>, &GlobalTransform, &InheritedVisibility)>,
+    terrain_meshes: Query<&TerrainMesh>,
+    segments: Query<(), With<Segment>>,
+    parents: Query<&ChildOf>,
+    meshes: Res<Assets<Mesh>>,
+    materials: Res<Assets<StandardMaterial>>,
+    region: Res<ExportRegion>,
+) {
+    let (terrain, terrain_data) = *level;
+    let _span = info_span!("export").entered();
+
+    let dir = Path::new(EXPORT_DIR);
+    if let Err(err) = std::fs::create_dir_all(dir) {
+        error!("Could not create {dir:?}: {err}");
+        return;
+    }
+
+    for (layer, data) in &terrain_data.layers {
+        let path = dir.join(format!("{layer:?}.tif").to_lowercase());
+        let range = export_range(terrain, data, region.0);
+        let result = std::fs::File::create(&path).map_err(ExportError::from)
+            .and_then(|f| write_geotiff(std::io::BufWriter::new(f), terrain, data, range));
+        match result {
+            Ok(()) => info!("Exported {layer:?} to {path:?}"),
+            Err(err) => error!("Could not export {layer:?}: {err}"),
+        }
+    }
+
+    let mut glb = GlbWriter::default();
+    for (entity, mesh, material, transform, visibility) in &scene_meshes {
+        if !visibility.get() { continue; }
+
+        let name = if let Ok(terrain_mesh) = terrain_meshes.get(entity) {
+            let block_id = terrain_mesh.block_id;
+            format!("{:?} {},{}@{}", terrain_mesh.layer, block_id.row, block_id.col, block_id.level)
+        } else if parents.iter_ancestors(entity).any(|a| segments.contains(a)) {
+            "Track".to_owned()
+        } else {
+            continue;
+        };
+
+        let (Some(mesh), Some(material)) = (meshes.get(&mesh.0), materials.get(&material.0))
+        else { continue; };
+
+        if let Err(err) = glb.add_mesh(&name, mesh, transform.compute_matrix(), material) {
+            warn!("Skipping {name}: {err}");
+        }
+    }
+
+    let path = dir.join("level.glb");
+    let result = std::fs::File::create(&path).map_err(ExportError::from)
+        .and_then(|f| glb.write(std::io::BufWriter::new(f)));
+    match result {
+        Ok(()) => info!("Exported scene to {path:?}"),
+        Err(err) => error!("Could not export scene: {err}"),
+    }
+}
+
+/**
+ * Range of a terrain layer's points covering a region, in the coordinate system of the
+ * terrain's bounds, or the whole layer if no region is given.
+ */
+pub fn export_range(terrain: &Terrain, data: &ChunkedArray, region: Option<Rect>) -> Range2 {
+    let (rows, cols) = data.dim();
+    let Some(region) = region else { return Range2(0..rows, 0..cols); };
+
+    /* Row 0 of the terrain is at the top (northern) edge of its bounds */
+    let to_row = |y: f32| terrain.size[0] as f32 - (y - terrain.bounds.min.y) / terrain.resolution.z;
+    let to_col = |x: f32| (x - terrain.bounds.min.x) / terrain.resolution.x;
+    let index = |value: f32, limit: usize| (value.max(0.0) as usize).min(limit);
+    Range2(
+        index(to_row(region.max.y).floor(), rows)..index(to_row(region.min.y).ceil(), rows),
+        index(to_col(region.min.x).floor(), cols)..index(to_col(region.max.x).ceil(), cols),
+    )
+}
+
+/**
+ * Write a region of a terrain layer as a single band of 32-bit floats, georeferenced so
+ * that it lines up with the tiles the terrain was loaded from.
+ */
+pub fn write_geotiff<W: Write + Seek>(
+    writer: W,
+    terrain: &Terrain,
+    data: &ChunkedArray,
+    range: Range2,
+) -> Result<(), ExportError> {
+    const MODEL_TYPE_GEO_KEY: u16 = 1024;
+    const RASTER_TYPE_GEO_KEY: u16 = 1025;
+    const PROJECTED_CS_TYPE_GEO_KEY: u16 = 3072;
+    const MODEL_TYPE_PROJECTED: u16 = 1;
+    const RASTER_PIXEL_IS_AREA: u16 = 1;
+
+    let region = data.slice(range.0.clone(), range.1.clone(), 1);
+    let (rows, cols) = region.dim();
+    if rows == 0 || cols == 0 {
+        return Err(ExportError::Empty);
+    }
+
+    /* Row 0 of the terrain is at the top (northern) edge of its bounds */
+    let left = terrain.bounds.min.x as f64 + range.1.start as f64 * terrain.resolution.x as f64;
+    let top = terrain.bounds.min.y as f64 + (terrain.size[0] as f64 - range.0.start as f64) * terrain.resolution.z as f64;
+
+    let mut encoder = TiffEncoder::new(writer)?;
+    let mut image = encoder.new_image::<colortype::Gray32Float>(cols as u32, rows as u32)?;
+    image.encoder().write_tag(Tag::ModelPixelScaleTag, &[terrain.resolution.x as f64, terrain.resolution.z as f64, 0.0][..])?;
+    image.encoder().write_tag(Tag::ModelTiepointTag, &[0.0, 0.0, 0.0, left, top, 0.0][..])?;
+    image.encoder().write_tag(Tag::GeoKeyDirectoryTag, &[
+        1, 1, 0, 3,
+        MODEL_TYPE_GEO_KEY, 0, 1, MODEL_TYPE_PROJECTED,
+        RASTER_TYPE_GEO_KEY, 0, 1, RASTER_PIXEL_IS_AREA,
+        PROJECTED_CS_TYPE_GEO_KEY, 0, 1, PROJECTED_CRS,
+    ][..])?;
+
+    let region = region.as_standard_layout();
+    image.write_data(region.as_slice().unwrap_or_default())?;
+
+    Ok(())
+}
+
+/**
+ * Collects meshes into a single glTF binary (.glb) file.
+ *
+ * Each mesh becomes a node with its own transform; materials with the same properties
+ * are shared.  Only triangle lists with positions are supported.
+ */
+#[derive(Default)]
+pub struct GlbWriter {
+    bin: Vec<u8>,
+    buffer_views: Vec<Value>,
+    accessors: Vec<Value>,
+    meshes: Vec<Value>,
+    nodes: Vec<Value>,
+    materials: Vec<Value>,
+}
+
+impl GlbWriter {
+    pub fn add_mesh(&mut self, name: &str, mesh: &Mesh, transform: Mat4, material: &StandardMaterial) -> Result<(), ExportError> {
+        if mesh.primitive_topology() != PrimitiveTopology::TriangleList {
+            return Err(ExportError::Mesh("Not a triangle list".to_owned()));
+        }
+
+        let Some(positions) = mesh.attribute(Mesh::ATTRIBUTE_POSITION).and_then(|a| a.as_float3())
+        else { return Err(ExportError::Mesh("No positions".to_owned())); };
+        if positions.is_empty() { return Ok(()); }
+
+        let indices: Vec<u32> = match mesh.indices() {
+            Some(Indices::U16(inds)) => inds.iter().map(|i| *i as u32).collect(),
+            Some(Indices::U32(inds)) => inds.clone(),
+            None => (0..positions.len() as u32).collect(),
+        };
+
+        let min = positions.iter().fold(Vec3::INFINITY, |m, p| m.min(Vec3::from(*p)));
+        let max = positions.iter().fold(Vec3::NEG_INFINITY, |m, p| m.max(Vec3::from(*p)));
+        let position_accessor = self.add_accessor(
+            positions.iter().flatten().flat_map(|f| f.to_le_bytes()),
+            positions.len(), 5126, "VEC3", 34962,
+            Some((min.to_array().to_vec(), max.to_array().to_vec())));
+
+        let mut attributes = json!({ "POSITION": position_accessor });
+        if let Some(normals) = mesh.attribute(Mesh::ATTRIBUTE_NORMAL).and_then(|a| a.as_float3()) {
+            attributes["NORMAL"] = json!(self.add_accessor(
+                normals.iter().flatten().flat_map(|f| f.to_le_bytes()),
+                normals.len(), 5126, "VEC3", 34962, None));
+        }
+
+        let index_accessor = self.add_accessor(
+            indices.iter().flat_map(|i| i.to_le_bytes()),
+            indices.len(), 5125, "SCALAR", 34963, None);
+
+        let material = self.add_material(material);
+
+        self.meshes.push(json!({
+            "name": name,
+            "primitives": [{ "attributes": attributes, "indices": index_accessor, "material": material }],
+        }));
+        self.nodes.push(json!({
+            "name": name,
+            "mesh": self.meshes.len() - 1,
+            "matrix": transform.to_cols_array(),
+        }));
+
+        Ok(())
+    }
+
+    fn add_accessor(
+        &mut self,
+        bytes: impl Iterator<Item=u8>,
+        count: usize,
+        component_type: u32,
+        accessor_type: &str,
+        target: u32,
+        bounds: Option<(Vec<f32>, Vec<f32>)>,
+    ) -> usize {
+        let offset = self.bin.len();
+        self.bin.extend(bytes);
+        self.buffer_views.push(json!({
+            "buffer": 0,
+            "byteOffset": offset,
+            "byteLength": self.bin.len() - offset,
+            "target": target,
+        }));
+
+        let mut accessor = json!({
+            "bufferView": self.buffer_views.len() - 1,
+            "componentType": component_type,
+            "count": count,
+            "type": accessor_type,
+        });
+        if let Some((min, max)) = bounds {
+            accessor["min"] = json!(min);
+            accessor["max"] = json!(max);
+        }
+        self.accessors.push(accessor);
+        self.accessors.len() - 1
+    }
+
+    fn add_material(&mut self, material: &StandardMaterial) -> usize {
+        let material = json!({
+            "pbrMetallicRoughness": {
+                "baseColorFactor": material.base_color.to_linear().to_f32_array(),
+                "metallicFactor": material.metallic,
+                "roughnessFactor": material.perceptual_roughness,
+            },
+            "alphaMode": if material.base_color.alpha() < 1.0 { "BLEND" } else { "OPAQUE" },
+        });
+
+        if let Some(ix) = self.materials.iter().position(|m| *m == material) {
+            return ix;
+        }
+        self.materials.push(material);
+        self.materials.len() - 1
+    }
+
+    pub fn write(self, mut writer: impl Write) -> Result<(), ExportError> {
+        const MAGIC: u32 = 0x46546C67;
+        const JSON_CHUNK: u32 = 0x4E4F534A;
+        const BIN_CHUNK: u32 = 0x004E4942;
+
+        let document = json!({
+            "asset": { "version": "2.0", "generator": "rreng" },
+            "scene": 0,
+            "scenes": [{ "nodes": (0..self.nodes.len()).collect::<Vec<_>>() }],
+            "nodes": self.nodes,
+            "meshes": self.meshes,
+            "materials": self.materials,
+            "accessors": self.accessors,
+            "bufferViews": self.buffer_views,
+            "buffers": [{ "byteLength": self.bin.len() }],
+        });
+
+        /* Both chunks must be padded to a multiple of 4 bytes */
+        let mut json = serde_json::to_vec(&document)?;
+        json.resize(json.len().next_multiple_of(4), b' ');
+        let mut bin = self.bin;
+        bin.resize(bin.len().next_multiple_of(4), 0);
+
+        let total_length = 12 + 8 + json.len() + 8 + bin.len();
+        writer.write_all(&MAGIC.to_le_bytes())?;
+        writer.write_all(&2u32.to_le_bytes())?;
+        writer.write_all(&(total_length as u32).to_le_bytes())?;
+        writer.write_all(&(json.len() as u32).to_le_bytes())?;
+        writer.write_all(&JSON_CHUNK.to_le_bytes())?;
+        writer.write_all(&json)?;
+        writer.write_all(&(bin.len() as u32).to_le_bytes())?;
+        writer.write_all(&BIN_CHUNK.to_le_bytes())?;
+        writer.write_all(&bin)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use ndarray::Array2;
+
+    use super::*;
+    use crate::terrain::tiles::decode_elevation;
+
+    #[test]
+    fn test_geotiff() {
+        let terrain = Terrain {
+            bounds: Rect::new(1000.0, 2000.0, 1008.0, 2008.0),
+            size: [8, 8],
+            block_size: 4,
+            resolution: Vec3::ONE,
+            num_blocks: [2, 2],
+            point_dims: [9, 9],
+        };
+        let data = ChunkedArray::new([9, 9], 4);
+        let heights = Array2::from_shape_fn((9, 9), |(r, c)| (r * 10 + c) as f32);
+        data.assign((0, 0), heights.view());
+
+        let mut bytes = Cursor::new(Vec::new());
+        write_geotiff(&mut bytes, &terrain, &data, Range2(2..6, 1..4)).unwrap();
+
+        let range = export_range(&terrain, &data, Some(Rect::new(1001.0, 2002.0, 1004.0, 2006.0)));
+        assert_eq!((range.0.clone(), range.1.clone()), (2..6, 1..4));
+        let range = export_range(&terrain, &data, Some(Rect::new(900.0, 2004.0, 1002.0, 3000.0)));
+        assert_eq!((range.0, range.1), (0..4, 0..2));
+
+        let decoded = decode_elevation(bytes.get_ref()).unwrap();
+        assert_eq!(decoded.heights, heights.slice(ndarray::s![2..6, 1..4]));
+
+        bytes.set_position(0);
+        let tiff = geotiff::GeoTiff::read(bytes).unwrap();
+        let extent = tiff.model_extent();
+        assert_eq!((extent.min().x, extent.max().y), (1001.0, 2006.0));
+        assert_eq!((extent.max().x, extent.min().y), (1004.0, 2002.0));
+    }
+
+    #[test]
+    fn test_glb() {
+        let mesh = Mesh::from(Cuboid::default());
+        let mut glb = GlbWriter::default();
+        glb.add_mesh("Cube", &mesh, Mat4::IDENTITY, &StandardMaterial::default()).unwrap();
+        glb.add_mesh("Cube", &mesh, Mat4::from_translation(Vec3::X), &StandardMaterial::default()).unwrap();
+        assert_eq!(glb.materials.len(), 1);
+
+        let mut bytes = Vec::new();
+        glb.write(&mut bytes).unwrap();
+
+        assert_eq!(&bytes[0..4], b"glTF");
+        assert_eq!(u32::from_le_bytes(bytes[8..12].try_into().unwrap()) as usize, bytes.len());
+        assert_eq!(bytes.len() % 4, 0);
+
+        let json_length = u32::from_le_bytes(bytes[12..16].try_into().unwrap()) as usize;
+        let document: Value = serde_json::from_slice(&bytes[20..20 + json_length]).unwrap();
+        assert_eq!(document["nodes"].as_array().unwrap().len(), 2);
+        assert_eq!(document["nodes"][1]["matrix"][12], 1.0);
+    }
+}
@@ -6,12 +6,61 @@
 use serde::Deserialize;
 use thiserror::Error;
 
+use crate::terrain::soil::Soil;
 use crate::terrain::TerrainLayer;
 use crate::terrain::tiles::TileSets;
+use crate::track::curve::{smooth_shapes, SegmentShape};
+use crate::track::station::PlatformSide;
+use crate::track::validation::TrackLimits;
 
 #[derive(Clone, Debug, Default, Deserialize)]
 pub struct TrackToLoad {
     pub points: Vec<Vec3>,
+    #[serde(default)]
+    pub earthworks: bool,
+    /** Path of the track's style asset, or the default style if none. */
+    #[serde(default)]
+    pub style: Option<String>,
+    /** Shapes for segments, numbered from the start of the track, in place of smoothed ones. */
+    #[serde(default)]
+    pub shapes: HashMap<usize, SegmentShape>,
+}
+
+impl TrackToLoad {
+    /**
+     * Shapes of the track's segments: those given, and smoothed curves between the points
+     * for the rest.
+     */
+    pub fn segment_shapes(&self) -> Vec<SegmentShape> {
+        smooth_shapes(&self.points, false).into_iter().enumerate()
+            .map(|(i, shape)| self.shapes.get(&i).copied().unwrap_or(shape))
+            .collect()
+    }
+}
+
+/**
+ * A platform beside a track, on one of its segments, numbered from the start of the
+ * track, between two distances along it.
+ */
+#[derive(Clone, Debug, Deserialize)]
+pub struct PlatformToLoad {
+    pub track: String,
+    pub segment: usize,
+    pub start: f32,
+    pub end: f32,
+    #[serde(default)]
+    pub side: PlatformSide,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct StationToLoad {
+    pub platforms: Vec<PlatformToLoad>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct SoilArea {
+    pub soil: u8,
+    pub bounds: Rect,
 }
 
 #[derive(Asset, Clone, Debug, Default, Deserialize, TypePath)]
@@ -20,6 +69,14 @@ pub struct DataFile {
     pub layers: Vec<TerrainLayer>,
     pub bounds: Rect,
     pub tracks: HashMap<String, TrackToLoad>,
+    #[serde(default)]
+    pub stations: HashMap<String, StationToLoad>,
+    #[serde(default)]
+    pub soils: Vec<Soil>,
+    #[serde(default)]
+    pub soil_areas: Vec<SoilArea>,
+    #[serde(default)]
+    pub track_limits: TrackLimits,
 }
 
 #[non_exhaustive]
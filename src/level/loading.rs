@@ -2,17 +2,19 @@
 
 use bevy::prelude::*;
 
-use crate::events::{GameEvent, GraphicsEvent};
-use crate::level::datafile::{DataFile, TrackToLoad};
+use crate::events::GameEvent;
+use crate::level::datafile::{DataFile, StationToLoad, TrackToLoad};
 use crate::level::LevelLabel;
 use crate::screens::Screen;
 use crate::terrain::{Terrain, TerrainData, TerrainLayer};
-use crate::terrain::rendering::{LayerLabel, MeshTaskQueue};
 use crate::terrain::rendering::mesh_tree::MeshTree;
 use crate::terrain::rendering::water::WaterLabel;
 use crate::terrain::tiles::{ElevationFile, Tile, TileSets};
-use crate::track::create_track;
-use crate::train::create_train;
+use crate::track::{create_shaped_track, TrackJoins};
+use crate::track::earthworks::Earthworks;
+use crate::track::station::{create_station, Platform};
+use crate::track::style::TrackStyleHandle;
+use crate::train::{create_train, TrainStop};
 
 const TILESETS_ASSET_PATH: &str = "data/tiles.ron";
 
@@ -159,9 +161,24 @@ pub fn check_loading_state(
             let Some(datafile) = datafile_assets.get(&loading_state.datafile_handle)
             else { return; };
 
+            commands.insert_resource(datafile.track_limits.clone());
+
             /* Create existing tracks */
-            for (name, TrackToLoad {points }) in datafile.tracks.iter() {
-                let (track_id, _, segment_ids) = create_track(name, points, false, &mut commands);
+            let mut track_segments = HashMap::new();
+            let mut trains = HashMap::new();
+            for (name, track) in datafile.tracks.iter() {
+                let TrackToLoad { points, earthworks, style, .. } = track;
+                let (track_id, _, segment_ids) = create_shaped_track(name, points, &track.segment_shapes(), false, TrackJoins::default(), &mut commands);
+
+                if let Some(style) = style {
+                    commands.entity(track_id).insert(TrackStyleHandle(asset_server.load(style)));
+                }
+
+                if *earthworks {
+                    for segment_id in &segment_ids {
+                        commands.entity(*segment_id).insert(Earthworks::default());
+                    }
+                }
 
                 /* Put a train at the start of the first segment */
                 let first_segment_id = segment_ids[0];
@@ -169,6 +186,35 @@ pub fn check_loading_state(
 
                 commands.entity(track_id).insert(ChildOf(*level_id));
                 commands.entity(train_id).insert(ChildOf(*level_id));
+                track_segments.insert(name.as_str(), segment_ids);
+                trains.insert(name.as_str(), train_id);
+            }
+
+            /* Create stations, and send each train to a platform on its track */
+            for (name, StationToLoad { platforms }) in datafile.stations.iter() {
+                let platforms: Vec<_> = platforms.iter().filter_map(|platform| {
+                    let segment = track_segments.get(platform.track.as_str()).and_then(|ids| ids.get(platform.segment));
+                    if segment.is_none() {
+                        warn!("Station {name} has a platform on missing segment {} of track {}", platform.segment, platform.track);
+                    }
+                    Some((platform.track.as_str(), Platform {
+                        segment: *segment?,
+                        start: platform.start,
+                        end: platform.end,
+                        side: platform.side,
+                        rendered_id: None,
+                    }))
+                }).collect();
+
+                for (track, platform) in &platforms {
+                    if let Some(train_id) = trains.remove(track) {
+                        commands.entity(train_id).insert(TrainStop::new(platform.stop_position()));
+                    }
+                }
+
+                let platforms: Vec<_> = platforms.into_iter().map(|(_, platform)| platform).collect();
+                let (station_id, _) = create_station(name, &platforms, &mut commands);
+                commands.entity(station_id).insert(ChildOf(*level_id));
             }
 
             crate::worker::create_workers(*level_id, terrain, &mut commands, 1);
@@ -6,6 +6,7 @@
 pub mod camera;
 pub mod debug;
 pub mod events;
+pub mod export;
 pub mod level;
 pub mod screens;
 pub mod sky;
@@ -42,6 +43,9 @@ fn build(&self, app: &mut App) {
             .add_plugins(worker::WorkerPlugin)
             .add_plugins(debug::DebugPlugin)
             .add_plugins(screens::ScreensPlugin)
+            .add_plugins(export::ExportPlugin)
+            .add_plugins(ui::minimap::MinimapPlugin)
+            .add_plugins(ui::validation::ValidationPanelPlugin)
             .add_systems(Update, utils::fix_apparent_size)
             .add_event::<events::GameEvent>()
             .add_event::<events::GraphicsEvent>();
@@ -15,10 +15,9 @@
 use rand::{thread_rng, Rng, seq::SliceRandom};
 
 use crate::camera::{CameraMode, CameraState};
-use crate::track::bridge::Bridge;
-use crate::track::create_track;
+use crate::track::{create_track, TrackJoins};
 use crate::train::create_train;
-use crate::{camera, level, screens, tools, utils};
+use crate::{camera, level, screens, terrain, tools, ui, utils};
 use crate::events::GameEvent;
 use crate::level::loading::{LoadingStage, LoadingStageLabel};
 use crate::level::selection;
@@ -49,7 +48,7 @@ fn load_level(mut commands: Commands) {
     commands.send_event(GameEvent::LoadLevel("data/jvl.ron".to_owned()));
 }
 
-fn exit_level(mut commands: Commands) {
+pub(crate) fn exit_level(mut commands: Commands) {
     commands.send_event(GameEvent::ExitLevel);
 }
 
@@ -162,23 +161,16 @@ fn make_figure_8(radius: f32, height: f32, segments: usize, minus_segments: usiz
 
     let layouts = [
         vec![
-            (make_circle(100.0,0.0, 72), 2, 1.0, false),
-            (make_circle(90.0,5.0, 68), 2, -1.0, false),
+            (make_circle(100.0,0.0, 72), 2, 1.0),
+            (make_circle(90.0,5.0, 68), 2, -1.0),
         ],
         vec![
-            (make_figure_8(60.0, 10.0, 52, 6), 1, -1.0, true),
+            (make_figure_8(60.0, 10.0, 52, 6), 1, -1.0),
         ],
     ];
     if let Some(parts) = layouts.choose(&mut thread_rng()) {
-        for (points, trains, spd, bridge) in parts {
-            let (track_id, _, segment_ids) = create_track("Title", points, true, &mut commands);
-
-            if *bridge {
-                let bridge_id = segment_ids[segment_ids.len() / 2 - 1];
-                commands.entity(bridge_id).insert(
-                    Bridge { pillars: 4 },
-                );
-            }
+        for (points, trains, spd) in parts {
+            let (track_id, _, segment_ids) = create_track("Title", points, true, TrackJoins::default(), &mut commands);
 
             commands.entity(track_id).insert(StateScoped(Screen::Title));
 
@@ -306,9 +298,13 @@ pub fn setup_playing(
         commands.run_system_cached(tools::create_tools);
         commands.run_system_cached(tools::create_terraform_tools);
         commands.run_system_cached(tools::create_track_tools);
+        commands.run_system_cached(tools::create_overlay_tools);
+        commands.run_system_cached(terrain::erosion::create_erosion_progress_text);
+        commands.run_system_cached(ui::validation::create_validation_panel);
     }
 
     commands.run_system_cached(camera::create_camera_position_text);
     commands.run_system_cached(selection::create_marker);
     commands.run_system_cached(selection::create_cursor_position_text);
+    commands.run_system_cached(ui::minimap::create_minimap);
 }
@@ -0,0 +1,214 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::{Arc, RwLock, RwLockWriteGuard};
+
+use ndarray::{s, Array2, ArrayView2};
+
+/**
+ * Something that heights can be read from and written to, one point at a time.
+ */
+pub trait HeightGrid {
+    fn dim(&self) -> (usize, usize);
+    fn get(&self, index: (usize, usize)) -> f32;
+    fn set(&mut self, index: (usize, usize), value: f32);
+}
+
+impl HeightGrid for Array2<f32> {
+    fn dim(&self) -> (usize, usize) {
+        Array2::dim(self)
+    }
+
+    fn get(&self, index: (usize, usize)) -> f32 {
+        self[index]
+    }
+
+    fn set(&mut self, index: (usize, usize), value: f32) {
+        self[index] = value;
+    }
+}
+
+/**
+ * A 2D array of heights, split into square chunks that are locked separately.
+ *
+ * Cloning the array is cheap, and the clone shares the same chunks.  Each operation
+ * only locks the chunks it touches, and readers only ever hold one lock at a time, so
+ * different parts of the terrain can be edited, meshed and loaded at the same time.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct ChunkedArray {
+    dims: (usize, usize),
+    chunk_size: usize,
+    chunks: Array2<Arc<RwLock<Array2<f32>>>>,
+}
+
+impl ChunkedArray {
+    pub fn new(dims: [usize; 2], chunk_size: usize) -> Self {
+        let chunk_dims = (dims[0].div_ceil(chunk_size), dims[1].div_ceil(chunk_size));
+        let chunks = Array2::from_shape_fn(chunk_dims, |(i, j)| {
+            let rows = chunk_size.min(dims[0] - i * chunk_size);
+            let cols = chunk_size.min(dims[1] - j * chunk_size);
+            Arc::new(RwLock::new(Array2::zeros((rows, cols))))
+        });
+
+        ChunkedArray {
+            dims: (dims[0], dims[1]),
+            chunk_size,
+            chunks,
+        }
+    }
+
+    pub fn dim(&self) -> (usize, usize) {
+        self.dims
+    }
+
+    pub fn get(&self, (row, col): (usize, usize)) -> Option<f32> {
+        if row >= self.dims.0 || col >= self.dims.1 { return None; }
+
+        let cs = self.chunk_size;
+        let chunk = self.chunks[(row / cs, col / cs)].read().unwrap();
+        Some(chunk[(row % cs, col % cs)])
+    }
+
+    /**
+     * Copy out every `step`th point in a range of rows and columns.
+     */
+    pub fn slice(&self, rows: Range<usize>, cols: Range<usize>, step: usize) -> Array2<f32> {
+        let rows = rows.start.min(self.dims.0)..rows.end.min(self.dims.0);
+        let cols = cols.start.min(self.dims.1)..cols.end.min(self.dims.1);
+        let mut result = Array2::zeros((rows.len().div_ceil(step), cols.len().div_ceil(step)));
+        if result.is_empty() { return result; }
+
+        let cs = self.chunk_size;
+        for ci in rows.start / cs..=(rows.end - 1) / cs {
+            for cj in cols.start / cs..=(cols.end - 1) / cs {
+                let chunk_rows = rows.start.max(ci * cs)..rows.end.min((ci + 1) * cs);
+                let chunk_cols = cols.start.max(cj * cs)..cols.end.min((cj + 1) * cs);
+                let first_row = align_to_step(chunk_rows.start, rows.start, step);
+                let first_col = align_to_step(chunk_cols.start, cols.start, step);
+
+                let chunk = self.chunks[(ci, cj)].read().unwrap();
+                for r in (first_row..chunk_rows.end).step_by(step) {
+                    for c in (first_col..chunk_cols.end).step_by(step) {
+                        result[((r - rows.start) / step, (c - cols.start) / step)] = chunk[(r - ci * cs, c - cj * cs)];
+                    }
+                }
+            }
+        }
+
+        result
+    }
+
+    pub fn to_array(&self) -> Array2<f32> {
+        self.slice(0..self.dims.0, 0..self.dims.1, 1)
+    }
+
+    /**
+     * Copy some data into the array, with its top-left corner at the given offset.
+     * The data must fit within the array.
+     */
+    pub fn assign(&self, offset: (usize, usize), data: ArrayView2<f32>) {
+        let (rows, cols) = data.dim();
+        if rows == 0 || cols == 0 { return; }
+
+        let cs = self.chunk_size;
+        for ci in offset.0 / cs..=(offset.0 + rows - 1) / cs {
+            for cj in offset.1 / cs..=(offset.1 + cols - 1) / cs {
+                let chunk_rows = offset.0.max(ci * cs)..(offset.0 + rows).min((ci + 1) * cs);
+                let chunk_cols = offset.1.max(cj * cs)..(offset.1 + cols).min((cj + 1) * cs);
+
+                let src = data.slice(s!(
+                    chunk_rows.start - offset.0..chunk_rows.end - offset.0,
+                    chunk_cols.start - offset.1..chunk_cols.end - offset.1));
+
+                let mut chunk = self.chunks[(ci, cj)].write().unwrap();
+                chunk.slice_mut(s!(
+                    chunk_rows.start - ci * cs..chunk_rows.end - ci * cs,
+                    chunk_cols.start - cj * cs..chunk_cols.end - cj * cs))
+                    .assign(&src);
+            }
+        }
+    }
+
+    /**
+     * Get a writer for changing individual points.  Chunks are locked as they are
+     * first touched, and stay locked until the writer is dropped.
+     */
+    pub fn writer(&self) -> ChunkedArrayWriter<'_> {
+        ChunkedArrayWriter {
+            array: self,
+            guards: RefCell::new(HashMap::new()),
+        }
+    }
+}
+
+fn align_to_step(pos: usize, start: usize, step: usize) -> usize {
+    start + (pos - start).div_ceil(step) * step
+}
+
+pub struct ChunkedArrayWriter<'a> {
+    array: &'a ChunkedArray,
+    guards: RefCell<HashMap<(usize, usize), RwLockWriteGuard<'a, Array2<f32>>>>,
+}
+
+impl ChunkedArrayWriter<'_> {
+    fn with_chunk<T>(&self, (row, col): (usize, usize), f: impl FnOnce(&mut Array2<f32>, (usize, usize)) -> T) -> T {
+        let cs = self.array.chunk_size;
+        let chunk_index = (row / cs, col / cs);
+        let mut guards = self.guards.borrow_mut();
+        let guard = guards.entry(chunk_index)
+            .or_insert_with(|| self.array.chunks[chunk_index].write().unwrap());
+        f(guard, (row % cs, col % cs))
+    }
+}
+
+impl HeightGrid for ChunkedArrayWriter<'_> {
+    fn dim(&self) -> (usize, usize) {
+        self.array.dims
+    }
+
+    fn get(&self, index: (usize, usize)) -> f32 {
+        self.with_chunk(index, |chunk, index| chunk[index])
+    }
+
+    fn set(&mut self, index: (usize, usize), value: f32) {
+        self.with_chunk(index, |chunk, index| chunk[index] = value);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_assign_and_slice() {
+        let array = ChunkedArray::new([10, 9], 4);
+        assert_eq!(array.chunks.dim(), (3, 3));
+
+        let data = Array2::from_shape_fn((10, 9), |(r, c)| (r * 100 + c) as f32);
+        array.assign((0, 0), data.view());
+        assert_eq!(array.to_array(), data);
+        assert_eq!(array.get((9, 8)), Some(908.0));
+        assert_eq!(array.get((10, 0)), None);
+
+        let sliced = array.slice(1..10, 2..9, 3);
+        assert_eq!(sliced, data.slice(s!(1..10;3, 2..9;3)));
+
+        array.assign((3, 3), Array2::zeros((2, 2)).view());
+        assert_eq!(array.get((3, 3)), Some(0.0));
+        assert_eq!(array.get((4, 4)), Some(0.0));
+        assert_eq!(array.get((5, 5)), Some(505.0));
+    }
+
+    #[test]
+    fn test_writer() {
+        let array = ChunkedArray::new([8, 8], 4);
+        let mut writer = array.writer();
+        writer.set((3, 4), 1.0);
+        writer.set((4, 3), writer.get((3, 4)) + 1.0);
+        drop(writer);
+
+        assert_eq!(array.get((3, 4)), Some(1.0));
+        assert_eq!(array.get((4, 3)), Some(2.0));
+    }
+}
@@ -1,13 +1,14 @@
 use std::collections::VecDeque;
-use std::ops::DerefMut;
 
 use bevy::input::ButtonInput;
 use bevy::log::info_span;
 use bevy::prelude::{Color, Gizmos, Local, MouseButton, Res, Single, With};
-use ndarray::{Array2, Ix, Ixs};
+use ndarray::{Ix, Ixs};
 
 use crate::level::LevelLabel;
 use crate::level::selection::SelectedPoint;
+use crate::terrain::chunks::HeightGrid;
+use crate::terrain::soil::SoilMap;
 use crate::terrain::{TerrainData, TerrainLayer};
 use crate::terrain::utils::Range2;
 
@@ -19,9 +20,6 @@ pub fn click_point(
     let Some(elevation) = terrain_data.layers.get(&TerrainLayer::Elevation)
     else { return; };
 
-    let mut _guard = elevation.write().unwrap();
-    let elevation = _guard.deref_mut();
-
     let left = buttons.pressed(MouseButton::Left);
     let right = buttons.pressed(MouseButton::Right);
 
@@ -36,12 +34,13 @@ pub fn click_point(
 
     let _span = info_span!("terraform.height").entered();
 
-    if left && !right { elevation[(row, col)] += 1.0; }
-    if right && !left { elevation[(row, col)] -= 1.0; }
+    let mut elevation = elevation.writer();
+    if left && !right { elevation.set((row, col), elevation.get((row, col)) + 1.0); }
+    if right && !left { elevation.set((row, col), elevation.get((row, col)) - 1.0); }
 
-    let range = propagate(row, col, elevation);
+    let range = propagate(row, col, &mut elevation, &terrain_data.soil);
 
-    drop(_guard);
+    drop(elevation);
 
     terrain_data.dirty_range(range);
 }
@@ -56,8 +55,7 @@ pub fn drag_point(
     let Some(elevation) = terrain_data.layers.get(&TerrainLayer::Elevation)
     else { return; };
 
-    let mut _guard = elevation.write().unwrap();
-    let elevation = _guard.deref_mut();
+    let mut elevation = elevation.writer();
 
     if buttons.just_pressed(MouseButton::Left) {
         start_point.point = selected_point.point;
@@ -75,7 +73,7 @@ pub fn drag_point(
             return;
         }
 
-        let start_h = elevation[(row, col)];
+        let start_h = elevation.get((row, col));
 
         let row = selected_point.point.z as Ix;
         let col = selected_point.point.x as Ix;
@@ -93,25 +91,30 @@ pub fn drag_point(
             let row = point.z as Ix;
             let col = point.x as Ix;
 
-            elevation[(row, col)] = start_h;
+            elevation.set((row, col), start_h);
 
-            let range = propagate(row, col, elevation);
+            let range = propagate(row, col, &mut elevation, &terrain_data.soil);
             ranges_to_dirty.push(range);
         }
     }
 
-    drop(_guard);
+    drop(elevation);
 
     for range in ranges_to_dirty {
         terrain_data.dirty_range(range);
     }
 }
 
-fn propagate(crow: Ix, ccol: Ix, data: &mut Array2<f32>) -> Range2 {
+/**
+ * Adjust the terrain around a changed point so that no slope exceeds what the soil there
+ * will hold.  Points that are too low are filled, and points that are too high are cut,
+ * each using the corresponding slope of the soil at that point.
+ */
+fn propagate(crow: Ix, ccol: Ix, data: &mut impl HeightGrid, soil: &SoilMap) -> Range2 {
     let mut queue = VecDeque::new();
     queue.push_back((crow, ccol));
 
-    let cheight = data[(crow, ccol)];
+    let cheight = data.get((crow, ccol));
 
     let mut range = Range2::default();
 
@@ -121,14 +124,16 @@ fn propagate(crow: Ix, ccol: Ix, data: &mut Array2<f32>) -> Range2 {
 
         for (nrow, ncol) in neighbours(row, col, data.dim()) {
             let dist = ((nrow.abs_diff(crow) * nrow.abs_diff(crow) + ncol.abs_diff(ccol) * ncol.abs_diff(ccol)) as f32).sqrt();
-            let min_h = data[(row, col)].min(cheight - dist);
-            let max_h = data[(row, col)].max(cheight + dist);
+            let h = data.get((row, col));
+            let min_h = h.min(cheight - dist * soil.fill_slope(nrow, ncol));
+            let max_h = h.max(cheight + dist * soil.cut_slope(nrow, ncol));
 
-            if data[(nrow, ncol)] < min_h {
-                data[(nrow, ncol)] = min_h;
+            let nh = data.get((nrow, ncol));
+            if nh < min_h {
+                data.set((nrow, ncol), min_h);
                 queue.push_back((nrow, ncol));
-            } else if data[(nrow, ncol)] > max_h {
-                data[(nrow, ncol)] = max_h;
+            } else if nh > max_h {
+                data.set((nrow, ncol), max_h);
                 queue.push_back((nrow, ncol));
             }
         }
@@ -151,3 +156,28 @@ fn neighbours(row: Ix, col: Ix, dims: (Ix, Ix)) -> impl Iterator<Item=(Ix, Ix)>
         .filter(move |(r, c)| row_range.contains(r) && col_range.contains(c))
         .map(|(r, c)| (r as Ix, c as Ix))
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ndarray::Array2;
+    use crate::terrain::soil::Soil;
+
+    #[test]
+    fn test_propagate_slopes() {
+        let soils = [Soil { name: "Test".to_owned(), cut_slope: 2.0, fill_slope: 0.5 }];
+        let soil = SoilMap::new([9, 9], &soils);
+
+        let mut data = Array2::zeros((9, 9));
+        data[(4, 4)] = 4.0;
+        propagate(4, 4, &mut data, &soil);
+        assert_eq!(data[(4, 5)], 3.5);
+        assert_eq!(data[(4, 8)], 2.0);
+
+        let mut data = Array2::zeros((9, 9));
+        data[(4, 4)] = -4.0;
+        propagate(4, 4, &mut data, &soil);
+        assert_eq!(data[(4, 5)], -2.0);
+        assert_eq!(data[(4, 6)], 0.0);
+    }
+}
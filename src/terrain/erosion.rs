@@ -0,0 +1,380 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use bevy::color::palettes::basic::GRAY;
+use bevy::prelude::*;
+use bevy::tasks::{block_on, AsyncComputeTaskPool, Task};
+use bevy::tasks::futures_lite::future;
+use ndarray::{Array2, Ix};
+
+use crate::level::LevelLabel;
+use crate::level::selection::SelectedPoint;
+use crate::screens::Screen;
+use crate::terrain::{TerrainData, TerrainLayer};
+use crate::terrain::utils::Range2;
+use crate::theme::Theme;
+
+/**
+ * Settings for the erosion simulation.
+ *
+ * Each iteration rains on every point, lets the water run downhill carrying sediment,
+ * and then lets any slope steeper than the talus slope slump.
+ */
+#[derive(Clone, Debug, Reflect, Resource)]
+#[reflect(Resource)]
+pub struct ErosionParams {
+    pub iterations: usize,
+    pub rain: f32,
+    pub evaporation: f32,
+    pub sediment_capacity: f32,
+    pub erosion_rate: f32,
+    pub deposition_rate: f32,
+    pub talus_slope: f32,
+    pub thermal_rate: f32,
+}
+
+impl Default for ErosionParams {
+    fn default() -> Self {
+        ErosionParams {
+            iterations: 50,
+            rain: 0.01,
+            evaporation: 0.5,
+            sediment_capacity: 0.5,
+            erosion_rate: 0.1,
+            deposition_rate: 0.1,
+            talus_slope: 1.0,
+            thermal_rate: 0.25,
+        }
+    }
+}
+
+pub struct ErosionTask {
+    layer: TerrainLayer,
+    range: Range2,
+    /** The region as it was when the task was queued, to find what erosion changed. */
+    original: Array2<f32>,
+    iterations: usize,
+    progress: Arc<AtomicUsize>,
+    cancelled: Arc<AtomicBool>,
+    task: Task<Option<Array2<f32>>>,
+}
+
+impl ErosionTask {
+    pub fn progress(&self) -> f32 {
+        self.progress.load(Ordering::Relaxed) as f32 / self.iterations.max(1) as f32
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+}
+
+#[derive(Default, Resource)]
+pub struct ErosionTaskQueue(pub Vec<ErosionTask>);
+
+#[derive(Component)]
+pub struct ErosionProgressLabel;
+
+/**
+ * Run erosion over a region of a layer in the background.
+ *
+ * The region is copied out of the layer, so the terrain can still be edited while the
+ * task runs.  When it finishes, the change erosion made is added to the layer as it is
+ * then, keeping any edits made in the meantime, and those blocks are dirtied.
+ */
+pub fn queue_erosion_task(
+    terrain_data: &TerrainData,
+    layer: TerrainLayer,
+    range: Range2,
+    params: &ErosionParams,
+    queue: &mut ErosionTaskQueue,
+) {
+    let Some(data) = terrain_data.layers.get(&layer) else { return; };
+    let (rows, cols) = data.dim();
+    let range = Range2(range.0.start.min(rows)..range.0.end.min(rows), range.1.start.min(cols)..range.1.end.min(cols));
+    if range.0.len() < 3 || range.1.len() < 3 { return; }
+
+    let original = data.slice(range.0.clone(), range.1.clone(), 1);
+    let region = original.clone();
+
+    let progress = Arc::new(AtomicUsize::new(0));
+    let cancelled = Arc::new(AtomicBool::new(false));
+
+    let iterations = params.iterations;
+    let params = params.clone();
+    let task_progress = progress.clone();
+    let task_cancelled = cancelled.clone();
+    let thread_pool = AsyncComputeTaskPool::get();
+    let task = thread_pool.spawn(async move {
+        let _span = info_span!("terraform.erode").entered();
+        let mut region = region;
+        let finished = erode(&mut region, &params, &task_progress, &task_cancelled);
+        finished.then_some(region)
+    });
+
+    info!("Eroding {:?} for {} iterations", range, iterations);
+
+    queue.0.push(ErosionTask {
+        layer,
+        range,
+        original,
+        iterations,
+        progress,
+        cancelled,
+        task,
+    });
+}
+
+pub fn handle_erosion_tasks(
+    mut queue: ResMut<ErosionTaskQueue>,
+    mut terrain_data: Single<&mut TerrainData, With<LevelLabel>>,
+) {
+    if queue.0.is_empty() { return; }
+
+    let old_queue = std::mem::take(&mut queue.0);
+
+    for mut et in old_queue {
+        match block_on(future::poll_once(&mut et.task)) {
+            Some(Some(eroded)) => {
+                let Some(data) = terrain_data.layers.get(&et.layer) else { continue; };
+                let current = data.slice(et.range.0.clone(), et.range.1.clone(), 1);
+                let region = current + eroded - &et.original;
+                let offset = (et.range.0.start as isize, et.range.1.start as isize);
+                terrain_data.set_elevation(offset, region.view(), et.layer);
+                info!("Erosion finished for {:?}", et.range);
+            }
+            Some(None) => {
+                info!("Erosion cancelled for {:?}", et.range);
+            }
+            None => queue.0.push(et),
+        }
+    }
+}
+
+/**
+ * Pick the region with the mouse and start eroding it when the button is released.
+ * Right-clicking cancels any erosion in progress.
+ */
+pub fn erode_region(
+    buttons: Res<ButtonInput<MouseButton>>,
+    selected_point: Res<SelectedPoint>,
+    terrain_data: Single<&TerrainData, With<LevelLabel>>,
+    params: Res<ErosionParams>,
+    mut queue: ResMut<ErosionTaskQueue>,
+    mut start_point: Local<SelectedPoint>,
+    mut gizmos: Gizmos,
+) {
+    if buttons.just_pressed(MouseButton::Right) {
+        for et in &queue.0 {
+            et.cancel();
+        }
+    }
+
+    if buttons.just_pressed(MouseButton::Left) {
+        start_point.point = selected_point.point;
+    }
+
+    let min = start_point.point.min(selected_point.point);
+    let max = start_point.point.max(selected_point.point);
+
+    if buttons.pressed(MouseButton::Left) {
+        let centre = (min + max) / 2.0;
+        let size = (max - min).xz();
+        gizmos.rect(Isometry3d::new(centre, Quat::from_axis_angle(Vec3::X, std::f32::consts::FRAC_PI_2)), size, Color::srgb(0.3, 0.3, 1.0));
+    } else if buttons.just_released(MouseButton::Left) {
+        let range = Range2(
+            min.z.max(0.0) as usize..max.z.max(0.0) as usize + 1,
+            min.x.max(0.0) as usize..max.x.max(0.0) as usize + 1,
+        );
+        queue_erosion_task(&terrain_data, TerrainLayer::Elevation, range, &params, &mut queue);
+    }
+
+    for et in &queue.0 {
+        let centre = Vec3::new((et.range.1.start + et.range.1.end) as f32 / 2.0, selected_point.point.y, (et.range.0.start + et.range.0.end) as f32 / 2.0);
+        let size = Vec2::new(et.range.1.len() as f32, et.range.0.len() as f32);
+        gizmos.rect(Isometry3d::new(centre, Quat::from_axis_angle(Vec3::X, std::f32::consts::FRAC_PI_2)), size, Color::srgb(1.0, 1.0, 0.3));
+    }
+}
+
+pub fn create_erosion_progress_text(
+    theme: Res<Theme>,
+    mut commands: Commands,
+) {
+    commands.spawn((
+        Node {
+            position_type: PositionType::Absolute,
+            top: Val::Px(70.0),
+            right: Val::Px(10.0),
+            ..default()
+        },
+        Text("".to_owned()),
+        TextFont {
+            font: theme.font.clone(),
+            font_size: 16.0,
+            ..default()
+        },
+        TextColor(Color::Srgba(GRAY)),
+        ErosionProgressLabel,
+        StateScoped(Screen::Playing),
+    ));
+}
+
+pub fn update_erosion_progress(
+    queue: Res<ErosionTaskQueue>,
+    mut text: Single<&mut Text, With<ErosionProgressLabel>>,
+) {
+    text.0 = match queue.0.first() {
+        Some(et) => format!("Eroding: {:3.0}%", et.progress() * 100.0),
+        None => String::new(),
+    };
+}
+
+const NEIGHBOURS: [(isize, isize, f32); 8] = [
+    (-1, -1, std::f32::consts::SQRT_2), (-1, 0, 1.0), (-1, 1, std::f32::consts::SQRT_2),
+    (0, -1, 1.0), (0, 1, 1.0),
+    (1, -1, std::f32::consts::SQRT_2), (1, 0, 1.0), (1, 1, std::f32::consts::SQRT_2),
+];
+
+/**
+ * Apply hydraulic and thermal erosion to some height data.
+ *
+ * The edges of the data are held fixed, so the region blends into its surroundings.
+ * Returns false if the erosion was cancelled before all iterations were done.
+ */
+pub fn erode(
+    data: &mut Array2<f32>,
+    params: &ErosionParams,
+    progress: &AtomicUsize,
+    cancelled: &AtomicBool,
+) -> bool {
+    let dims = data.dim();
+    let mut water = Array2::<f32>::zeros(dims);
+    let mut sediment = Array2::<f32>::zeros(dims);
+
+    for i in 0..params.iterations {
+        if cancelled.load(Ordering::Relaxed) { return false; }
+
+        water += params.rain;
+        hydraulic_step(data, &mut water, &mut sediment, params);
+        thermal_step(data, params);
+
+        water *= 1.0 - params.evaporation;
+
+        progress.store(i + 1, Ordering::Relaxed);
+    }
+
+    /* Whatever sediment is still being carried settles where it is */
+    for ((r, c), s) in sediment.indexed_iter() {
+        if is_interior(r, c, dims) {
+            data[(r, c)] += s;
+        }
+    }
+
+    true
+}
+
+fn is_interior(row: Ix, col: Ix, dims: (Ix, Ix)) -> bool {
+    row > 0 && col > 0 && row < dims.0 - 1 && col < dims.1 - 1
+}
+
+fn lowest_neighbour(row: Ix, col: Ix, data: &Array2<f32>, water: &Array2<f32>) -> Option<((Ix, Ix), f32)> {
+    let level = data[(row, col)] + water[(row, col)];
+    NEIGHBOURS.iter()
+        .map(|(dr, dc, dist)| ((row.wrapping_add_signed(*dr), col.wrapping_add_signed(*dc)), *dist))
+        .filter(|(n, _)| n.0 < data.dim().0 && n.1 < data.dim().1)
+        .map(|(n, dist)| (n, (level - data[n] - water[n]) / dist))
+        .filter(|(_, slope)| *slope > 0.0)
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+}
+
+fn hydraulic_step(data: &mut Array2<f32>, water: &mut Array2<f32>, sediment: &mut Array2<f32>, params: &ErosionParams) {
+    let dims = data.dim();
+
+    for row in 1..dims.0 - 1 {
+        for col in 1..dims.1 - 1 {
+            let w = water[(row, col)];
+            if w <= 0.0 { continue; }
+
+            let Some((target, slope)) = lowest_neighbour(row, col, data, water) else {
+                /* In a hollow: drop some of the sediment */
+                let deposit = sediment[(row, col)] * params.deposition_rate;
+                sediment[(row, col)] -= deposit;
+                data[(row, col)] += deposit;
+                continue;
+            };
+
+            /* Move as much water as will level out the two points */
+            let moved = w.min(slope / 2.0);
+            let fraction = moved / w;
+
+            let capacity = moved * slope * params.sediment_capacity;
+            let carried = sediment[(row, col)];
+            if carried < capacity {
+                let eroded = ((capacity - carried) * params.erosion_rate).min(slope / 2.0);
+                data[(row, col)] -= eroded;
+                sediment[(row, col)] += eroded;
+            } else {
+                let deposit = (carried - capacity) * params.deposition_rate;
+                data[(row, col)] += deposit;
+                sediment[(row, col)] -= deposit;
+            }
+
+            let moved_sediment = sediment[(row, col)] * fraction;
+            water[(row, col)] -= moved;
+            sediment[(row, col)] -= moved_sediment;
+            water[target] += moved;
+            if is_interior(target.0, target.1, dims) {
+                sediment[target] += moved_sediment;
+            }
+        }
+    }
+}
+
+fn thermal_step(data: &mut Array2<f32>, params: &ErosionParams) {
+    let dims = data.dim();
+
+    for row in 1..dims.0 - 1 {
+        for col in 1..dims.1 - 1 {
+            let h = data[(row, col)];
+            for (dr, dc, dist) in NEIGHBOURS {
+                let n = (row.wrapping_add_signed(dr), col.wrapping_add_signed(dc));
+                if !is_interior(n.0, n.1, dims) { continue; }
+
+                let excess = h - data[n] - params.talus_slope * dist;
+                if excess > 0.0 {
+                    let moved = excess * params.thermal_rate / 2.0;
+                    data[(row, col)] -= moved;
+                    data[n] += moved;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_thermal() {
+        let mut data = Array2::zeros((5, 5));
+        data[(2, 2)] = 10.0;
+        let params = ErosionParams { iterations: 20, rain: 0.0, ..ErosionParams::default() };
+
+        let finished = erode(&mut data, &params, &AtomicUsize::new(0), &AtomicBool::new(false));
+
+        assert!(finished);
+        assert!(data[(2, 2)] < 10.0);
+        assert!((data.sum() - 10.0).abs() < 0.001, "material is conserved");
+        assert_eq!(data[(0, 0)], 0.0);
+    }
+
+    #[test]
+    fn test_cancel() {
+        let mut data = Array2::zeros((5, 5));
+        let progress = AtomicUsize::new(0);
+        let finished = erode(&mut data, &ErosionParams::default(), &progress, &AtomicBool::new(true));
+        assert!(!finished);
+        assert_eq!(progress.load(Ordering::Relaxed), 0);
+    }
+}
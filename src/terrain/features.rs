@@ -0,0 +1,469 @@
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+
+use bevy::asset::RenderAssetUsages;
+use bevy::prelude::*;
+use bevy::render::mesh::{Indices, PrimitiveTopology};
+use bevy::tasks::{block_on, AsyncComputeTaskPool, Task};
+use bevy::tasks::futures_lite::future;
+use ndarray::{Array2, ArrayView2};
+
+use crate::level::LevelLabel;
+use crate::terrain::rendering::LayerLabel;
+use crate::terrain::{TerrainData, TerrainLayer};
+
+/**
+ * Settings for finding buildings and trees in the terrain.
+ *
+ * The Structure layer is a surface model, which includes everything standing on the
+ * ground.  Anything high enough above the Elevation layer is split into connected
+ * regions.  Regions with smooth tops are buildings, and the rest are vegetation, from
+ * which trees are picked out at the highest points of the canopy.
+ */
+#[derive(Clone, Debug, Reflect, Resource)]
+#[reflect(Resource)]
+pub struct FeatureParams {
+    /** Least height above the ground of anything that isn't the ground. */
+    pub min_height: f32,
+    /** Regions with fewer points than this are ignored. */
+    pub min_area: usize,
+    pub min_building_area: usize,
+    /** Mean change in slope between neighbouring points, above which a region isn't a building. */
+    pub max_building_roughness: f32,
+    /** Least distance, in points, between trees. */
+    pub tree_spacing: usize,
+    pub min_tree_height: f32,
+}
+
+impl Default for FeatureParams {
+    fn default() -> Self {
+        FeatureParams {
+            min_height: 2.0,
+            min_area: 4,
+            min_building_area: 20,
+            max_building_roughness: 0.6,
+            tree_spacing: 3,
+            min_tree_height: 3.0,
+        }
+    }
+}
+
+const BUILDING_COLOUR: Color = Color::srgb(0.8, 0.76, 0.7);
+const CROWN_COLOUR: Color = Color::srgb(0.15, 0.4, 0.12);
+const TRUNK_COLOUR: Color = Color::srgb(0.35, 0.22, 0.1);
+
+/**
+ * Buildings are put into one mesh for each square of this many points.
+ */
+const BUILDING_GROUP_SIZE: usize = 256;
+
+/**
+ * A building is its footprint, as runs of points in each row, extruded from the lowest
+ * ground under it up to its roof.
+ */
+#[derive(Debug)]
+pub struct Building {
+    pub runs: Vec<(usize, Range<usize>)>,
+    pub base: f32,
+    pub roof: f32,
+}
+
+#[derive(Debug)]
+pub struct Tree {
+    pub position: Vec3,
+    pub height: f32,
+    pub crown_radius: f32,
+}
+
+#[derive(Debug, Default)]
+pub struct Features {
+    pub buildings: Vec<Building>,
+    pub trees: Vec<Tree>,
+}
+
+#[derive(Default, Resource)]
+pub struct FeatureTask(Option<Task<(Vec<Mesh>, Vec<Tree>)>>);
+
+#[derive(Component)]
+pub struct FeaturesLabel;
+
+/**
+ * Start finding the buildings and trees for the level, in the background.
+ */
+pub fn start_feature_extraction(
+    level: Single<&TerrainData, With<LevelLabel>>,
+    params: Res<FeatureParams>,
+    mut feature_task: ResMut<FeatureTask>,
+) {
+    let (Some(elevation), Some(structure)) = (level.layers.get(&TerrainLayer::Elevation), level.layers.get(&TerrainLayer::Structure))
+    else { return; };
+
+    let elevation = elevation.clone();
+    let structure = structure.clone();
+    let params = params.clone();
+    let thread_pool = AsyncComputeTaskPool::get();
+    feature_task.0 = Some(thread_pool.spawn(async move {
+        let _span = info_span!("terrain.features").entered();
+        let features = extract_features(elevation.to_array().view(), structure.to_array().view(), &params);
+        info!("Found {} buildings and {} trees", features.buildings.len(), features.trees.len());
+        (create_building_meshes(&features.buildings), features.trees)
+    }));
+}
+
+pub fn handle_feature_task(
+    mut feature_task: ResMut<FeatureTask>,
+    level: Option<Single<Entity, With<LevelLabel>>>,
+    mut layers: Query<(&LayerLabel, &mut Visibility)>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut commands: Commands,
+) {
+    let Some(task) = &mut feature_task.0 else { return; };
+    let Some((building_meshes, trees)) = block_on(future::poll_once(task)) else { return; };
+    feature_task.0 = None;
+
+    let Some(level_id) = level.map(|l| *l) else { return; };
+
+    let features_id = commands.spawn((
+        FeaturesLabel,
+        Name::new("Features"),
+        Transform::default(),
+        Visibility::default(),
+        ChildOf(level_id),
+    )).id();
+
+    let building_material = materials.add(StandardMaterial {
+        base_color: BUILDING_COLOUR,
+        perceptual_roughness: 0.8,
+        ..default()
+    });
+    for mesh in building_meshes {
+        commands.spawn((
+            Name::new("Buildings"),
+            Mesh3d(meshes.add(mesh)),
+            MeshMaterial3d(building_material.clone()),
+            ChildOf(features_id),
+        ));
+    }
+
+    /* Every tree shares a mesh and material, so they are drawn as instances */
+    let tree_mesh = meshes.add(create_tree_mesh());
+    let tree_material = materials.add(StandardMaterial {
+        perceptual_roughness: 0.9,
+        ..default()
+    });
+    for tree in trees {
+        let size = Vec3::new(tree.crown_radius * 2.0, tree.height, tree.crown_radius * 2.0);
+        commands.spawn((
+            Mesh3d(tree_mesh.clone()),
+            MeshMaterial3d(tree_material.clone()),
+            Transform::from_translation(tree.position).with_scale(size),
+            ChildOf(features_id),
+        ));
+    }
+
+    /* The buildings and trees replace the Structure layer's surface */
+    for (layer, mut visibility) in layers.iter_mut() {
+        if layer.0 == TerrainLayer::Structure {
+            *visibility = Visibility::Hidden;
+        }
+    }
+}
+
+/**
+ * Find buildings and trees from the height of the surface model above the ground.
+ */
+pub fn extract_features(elevation: ArrayView2<f32>, structure: ArrayView2<f32>, params: &FeatureParams) -> Features {
+    let heights = &structure - &elevation;
+    let (rows, cols) = heights.dim();
+    let above_ground = |(r, c): (usize, usize)| heights[(r, c)] >= params.min_height;
+
+    let mut features = Features::default();
+    let mut visited = Array2::from_elem((rows, cols), false);
+    let mut stack = Vec::new();
+
+    for start in ndarray::indices((rows, cols)) {
+        let start = (start.0, start.1);
+        if visited[start] || !above_ground(start) { continue; }
+
+        /* Collect the region connected to this point */
+        let mut region = Vec::new();
+        visited[start] = true;
+        stack.push(start);
+        while let Some((r, c)) = stack.pop() {
+            region.push((r, c));
+            let neighbours = [
+                (r.wrapping_sub(1), c), (r + 1, c), (r, c.wrapping_sub(1)), (r, c + 1),
+            ];
+            for n in neighbours {
+                if n.0 < rows && n.1 < cols && !visited[n] && above_ground(n) {
+                    visited[n] = true;
+                    stack.push(n);
+                }
+            }
+        }
+
+        if region.len() < params.min_area { continue; }
+
+        if region.len() >= params.min_building_area && roughness(&region, heights.view()) <= params.max_building_roughness {
+            features.buildings.push(create_building(region, elevation, structure));
+        } else {
+            features.trees.extend(find_trees(&region, heights.view(), elevation, params));
+        }
+    }
+
+    features
+}
+
+/**
+ * Mean change in slope between neighbouring points in a region.  Roofs are made of flat
+ * planes, even if they are pitched, so they are smoother than the tops of trees.
+ */
+fn roughness(region: &[(usize, usize)], heights: ArrayView2<f32>) -> f32 {
+    let points: HashSet<_> = region.iter().copied().collect();
+    let mut total = 0.0;
+    let mut count = 0;
+    for &(r, c) in region {
+        for (before, after) in [((r.wrapping_sub(1), c), (r + 1, c)), ((r, c.wrapping_sub(1)), (r, c + 1))] {
+            if points.contains(&before) && points.contains(&after) {
+                total += (heights[before] - 2.0 * heights[(r, c)] + heights[after]).abs();
+                count += 1;
+            }
+        }
+    }
+    if count == 0 { 0.0 } else { total / count as f32 }
+}
+
+fn create_building(mut region: Vec<(usize, usize)>, elevation: ArrayView2<f32>, structure: ArrayView2<f32>) -> Building {
+    region.sort();
+
+    let base = region.iter().map(|p| elevation[*p]).fold(f32::INFINITY, f32::min);
+    let mut roofs: Vec<_> = region.iter().map(|p| structure[*p]).collect();
+    roofs.sort_by(f32::total_cmp);
+    let roof = roofs[roofs.len() / 2];
+
+    let mut runs: Vec<(usize, Range<usize>)> = Vec::new();
+    for (r, c) in region {
+        match runs.last_mut() {
+            Some((row, cols)) if *row == r && cols.end == c => cols.end += 1,
+            _ => runs.push((r, c..c + 1)),
+        }
+    }
+
+    Building { runs, base, roof }
+}
+
+/**
+ * Put a tree at each point of a region that is the highest point within the tree spacing.
+ */
+fn find_trees(region: &[(usize, usize)], heights: ArrayView2<f32>, elevation: ArrayView2<f32>, params: &FeatureParams) -> Vec<Tree> {
+    let (rows, cols) = heights.dim();
+    let spacing = params.tree_spacing;
+
+    region.iter().filter_map(|&(r, c)| {
+        let height = heights[(r, c)];
+        if height < params.min_tree_height { return None; }
+
+        /* Of points at the same height, the first one is the top */
+        for i in r.saturating_sub(spacing)..(r + spacing + 1).min(rows) {
+            for j in c.saturating_sub(spacing)..(c + spacing + 1).min(cols) {
+                let other = heights[(i, j)];
+                if other > height || (other == height && (i, j) < (r, c)) {
+                    return None;
+                }
+            }
+        }
+
+        Some(Tree {
+            position: Vec3::new(c as f32, elevation[(r, c)], r as f32),
+            height,
+            crown_radius: (height * 0.3).clamp(1.0, spacing as f32 * 2.0),
+        })
+    }).collect()
+}
+
+/**
+ * Build meshes for the buildings, with those in the same area of the map sharing one.
+ * Each point of a footprint is a one metre square centred on the point.
+ */
+pub fn create_building_meshes(buildings: &[Building]) -> Vec<Mesh> {
+    let mut groups: HashMap<(usize, usize), Vec<&Building>> = HashMap::new();
+    for building in buildings {
+        let (row, cols) = &building.runs[0];
+        groups.entry((row / BUILDING_GROUP_SIZE, cols.start / BUILDING_GROUP_SIZE)).or_default().push(building);
+    }
+
+    groups.into_values().map(|group| {
+        let mut builder = MeshBuilder::default();
+        for building in group {
+            add_building(&mut builder, building);
+        }
+        builder.build()
+    }).collect()
+}
+
+fn add_building(builder: &mut MeshBuilder, building: &Building) {
+    let cells: HashSet<(usize, usize)> = building.runs.iter()
+        .flat_map(|(r, cols)| cols.clone().map(|c| (*r, c)))
+        .collect();
+    let (base, roof) = (building.base, building.roof);
+
+    for (r, cols) in &building.runs {
+        let (x0, x1) = (cols.start as f32 - 0.5, cols.end as f32 - 0.5);
+        let (z0, z1) = (*r as f32 - 0.5, *r as f32 + 0.5);
+
+        builder.add_quad([
+            Vec3::new(x0, roof, z0), Vec3::new(x0, roof, z1), Vec3::new(x1, roof, z1), Vec3::new(x1, roof, z0),
+        ]);
+
+        /* Walls run so that they face out from the footprint */
+        builder.add_wall(Vec2::new(x0, z0), Vec2::new(x0, z1), base, roof);
+        builder.add_wall(Vec2::new(x1, z1), Vec2::new(x1, z0), base, roof);
+
+        for (neighbour_row, z, forwards) in [(r.wrapping_sub(1), z0, false), (r + 1, z1, true)] {
+            let mut span_start = None;
+            for c in cols.start..=cols.end {
+                let open = c < cols.end && !cells.contains(&(neighbour_row, c));
+                match (open, span_start) {
+                    (true, None) => span_start = Some(c),
+                    (false, Some(start)) => {
+                        let (xa, xb) = (start as f32 - 0.5, c as f32 - 0.5);
+                        if forwards {
+                            builder.add_wall(Vec2::new(xa, z), Vec2::new(xb, z), base, roof);
+                        } else {
+                            builder.add_wall(Vec2::new(xb, z), Vec2::new(xa, z), base, roof);
+                        }
+                        span_start = None;
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct MeshBuilder {
+    positions: Vec<Vec3>,
+    normals: Vec<Vec3>,
+    indices: Vec<u32>,
+}
+
+impl MeshBuilder {
+    /**
+     * Add a flat quad, with its corners anticlockwise as seen from the front.
+     */
+    fn add_quad(&mut self, corners: [Vec3; 4]) {
+        let normal = (corners[1] - corners[0]).cross(corners[2] - corners[0]).normalize();
+        let first = self.positions.len() as u32;
+        self.positions.extend(corners);
+        self.normals.extend([normal; 4]);
+        self.indices.extend([0, 1, 2, 0, 2, 3].map(|i| first + i));
+    }
+
+    /**
+     * Add a vertical wall along a line, facing to the left of it as seen from above.
+     */
+    fn add_wall(&mut self, from: Vec2, to: Vec2, base: f32, top: f32) {
+        self.add_quad([
+            Vec3::new(from.x, base, from.y), Vec3::new(to.x, base, to.y),
+            Vec3::new(to.x, top, to.y), Vec3::new(from.x, top, from.y),
+        ]);
+    }
+
+    fn build(self) -> Mesh {
+        Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::default())
+            .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, self.positions)
+            .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, self.normals)
+            .with_inserted_indices(Indices::U32(self.indices))
+    }
+}
+
+/**
+ * A tree one metre high and across, standing on the origin, coloured by its vertices.
+ */
+fn create_tree_mesh() -> Mesh {
+    fn coloured(mesh: Mesh, colour: Color) -> Mesh {
+        let colours = vec![colour.to_linear().to_f32_array(); mesh.count_vertices()];
+        mesh.with_inserted_attribute(Mesh::ATTRIBUTE_COLOR, colours)
+    }
+
+    let trunk: Mesh = Cylinder::new(0.08, 0.3).into();
+    let crown: Mesh = Cone::new(0.5, 0.75).into();
+    let mut mesh = coloured(trunk.translated_by(Vec3::Y * 0.15), TRUNK_COLOUR);
+    let crown = coloured(crown.translated_by(Vec3::Y * 0.625), CROWN_COLOUR);
+    if let Err(err) = mesh.merge(&crown) {
+        warn!("Could not create tree mesh: {err}");
+    }
+    mesh
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_extract_features() {
+        let elevation = Array2::from_elem((20, 20), 10.0);
+        let mut structure = elevation.clone();
+
+        /* A flat roofed building, 5 by 6 points and 8m high */
+        for r in 2..7 {
+            for c in 2..8 {
+                structure[(r, c)] = 18.0;
+            }
+        }
+
+        /* A clump of trees, with two distinct tops */
+        for r in 12..18 {
+            for c in 10..19 {
+                let top = if c < 14 { (13, 12) } else { (15, 16) };
+                let distance = (r as f32 - top.0 as f32).abs() + (c as f32 - top.1 as f32).abs();
+                structure[(r, c)] = 10.0 + 12.0 - distance * 1.5;
+            }
+        }
+
+        let features = extract_features(elevation.view(), structure.view(), &FeatureParams::default());
+
+        assert_eq!(features.buildings.len(), 1);
+        let building = &features.buildings[0];
+        assert_eq!(building.runs.len(), 5);
+        assert!(building.runs.iter().all(|(_, cols)| *cols == (2..8)));
+        assert_eq!(building.base, 10.0);
+        assert_eq!(building.roof, 18.0);
+
+        assert_eq!(features.trees.len(), 2);
+        assert!(features.trees.iter().all(|t| t.position.y == 10.0 && t.height >= 3.0));
+    }
+
+    #[test]
+    fn test_building_mesh() {
+        /* An L shape: two points in the first row, one in the second */
+        let building = Building {
+            runs: vec![(0, 0..2), (1, 0..1)],
+            base: 0.0,
+            roof: 5.0,
+        };
+        let meshes = create_building_meshes(&[building]);
+        assert_eq!(meshes.len(), 1);
+
+        /* Two roof quads, and walls: 2 for the left and right of each run, 1 for the
+           north side, and 2 for the south side */
+        let Some(Indices::U32(indices)) = meshes[0].indices() else { panic!() };
+        assert_eq!(indices.len() / 6, 2 + 4 + 1 + 2);
+
+        /* Every wall faces away from the footprint */
+        let positions = meshes[0].attribute(Mesh::ATTRIBUTE_POSITION).unwrap().as_float3().unwrap();
+        let normals = meshes[0].attribute(Mesh::ATTRIBUTE_NORMAL).unwrap().as_float3().unwrap();
+        let cells = [Vec2::new(0.0, 0.0), Vec2::new(1.0, 0.0), Vec2::new(0.0, 1.0)];
+        for (quad, n) in positions.chunks(4).zip(normals.iter().step_by(4)) {
+            let n = Vec3::from(*n);
+            if n.y != 0.0 { continue; }
+            let p = Vec3::from(quad[0]).lerp(Vec3::from(quad[1]), 0.25);
+            let outside = p.xz() + n.xz() * 0.25;
+            let inside = p.xz() - n.xz() * 0.25;
+            let in_footprint = |q: Vec2| cells.iter().any(|c| (q - *c).abs().max_element() < 0.5);
+            assert!(!in_footprint(outside) && in_footprint(inside), "wall at {p} facing {n}");
+        }
+    }
+}
@@ -1,18 +1,24 @@
-use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
+use std::collections::{HashMap, HashSet};
 
 use bevy::prelude::*;
 use ndarray::s;
 use serde::{Deserialize, Serialize};
 
+use crate::terrain::chunks::ChunkedArray;
+use crate::terrain::soil::SoilMap;
 use crate::terrain::utils::{get_copyable_range, Range2};
 use crate::level::datafile::DataFile;
+use crate::screens::Screen;
 
+pub mod chunks;
 pub mod creation;
 pub mod edit;
+pub mod erosion;
+pub mod features;
 pub mod heightmap;
 pub mod rendering;
 pub mod rtin;
+pub mod soil;
 pub mod tiles;
 pub mod utils;
 
@@ -31,6 +37,8 @@
  * is at the "top-left" corner of the map.
  *
  * In 3D world space, W-E is the x-axis and N-S is the z-axis.  (The y-axis is the height.)
+ *
+ * Each layer is stored in chunks the size of a block, which are locked separately.
  */
 pub struct TerrainPlugin;
 
@@ -41,6 +49,18 @@ fn build(&self, app: &mut App) {
             .init_asset_loader::<tiles::TileSetsLoader>()
             .init_asset::<tiles::ElevationFile>()
             .init_asset_loader::<tiles::ElevationFileLoader>()
+            .register_type::<erosion::ErosionParams>()
+            .init_resource::<erosion::ErosionParams>()
+            .init_resource::<erosion::ErosionTaskQueue>()
+            .register_type::<features::FeatureParams>()
+            .init_resource::<features::FeatureParams>()
+            .init_resource::<features::FeatureTask>()
+            .add_systems(OnEnter(Screen::Playing), features::start_feature_extraction)
+            .add_systems(Update, (
+                erosion::handle_erosion_tasks,
+                erosion::update_erosion_progress,
+                features::handle_feature_task,
+            ))
             .add_plugins(rendering::TerrainRenderingPlugin);
     }
 }
@@ -56,6 +76,9 @@ pub struct BlockInfo {
     pub block_num: (usize, usize),
     pub range: Range2,
     pub dirty: bool,
+    pub minimap_dirty: bool,
+    /** Whether track over the block needs checking for bridges and tunnels again. */
+    pub track_dirty: bool,
 }
 
 #[derive(Component, Default, Debug)]
@@ -70,12 +93,15 @@ pub struct Terrain {
 
 #[derive(Component, Default, Debug)]
 pub struct TerrainData {
-    pub layers: HashMap<TerrainLayer, Arc<RwLock<ndarray::Array2<f32>>>>,
+    pub layers: HashMap<TerrainLayer, ChunkedArray>,
     pub block_info: ndarray::Array2<BlockInfo>,
+    pub soil: SoilMap,
+    /** Points, as (row, col), where the surface is cut away to show what is beneath. */
+    pub holes: HashSet<(usize, usize)>,
 }
 
 impl Terrain {
-    pub(crate) fn reset(&mut self, datafile: &DataFile) {
+    pub fn reset(&mut self, datafile: &DataFile) {
         self.bounds = datafile.bounds;
         self.size = datafile.size;
         self.block_size = 64;
@@ -97,20 +123,28 @@ pub fn coord_to_offset(&self, coord: Vec2) -> (isize, isize) {
 impl TerrainData {
     pub fn reset(&mut self, terrain: &Terrain, datafile: &DataFile) {
         for layer in &datafile.layers {
-            self.layers.insert(*layer, Arc::new(RwLock::new(ndarray::Array2::default(terrain.point_dims))));
+            self.layers.insert(*layer, ChunkedArray::new(terrain.point_dims, terrain.block_size));
         }
         self.block_info = ndarray::Array2::from_shape_fn(terrain.num_blocks, |(r, c)| BlockInfo {
             block_num: (r, c),
             range: Range2(r * terrain.block_size..(r+1) * terrain.block_size + 1, c * terrain.block_size..(c+1) * terrain.block_size + 1),
             dirty: false,
+            minimap_dirty: false,
+            track_dirty: false,
         });
+
+        self.soil = SoilMap::new(terrain.point_dims, &datafile.soils);
+        for area in &datafile.soil_areas {
+            let (row, col) = terrain.coord_to_offset(Vec2::new(area.bounds.min.x, area.bounds.max.y));
+            let size = area.bounds.size();
+            let rows = row.max(0) as usize..(row + size.y as isize).max(0) as usize;
+            let cols = col.max(0) as usize..(col + size.x as isize).max(0) as usize;
+            self.soil.set_soil(Range2(rows, cols), area.soil);
+        }
     }
 
     pub fn set_elevation(&mut self, offset: (isize, isize), data: ndarray::ArrayView2<f32>, layer: TerrainLayer) {
-        /* Clone the layer reference so we aren't still borrowing it when we
-           want to call self.dirty_range */
-        let target_layer = self.layers[&layer].clone();
-        let mut target_layer = target_layer.write().unwrap();
+        let target_layer = &self.layers[&layer];
 
         let data_dims = data.dim();
         let layer_dims = target_layer.dim();
@@ -122,13 +156,26 @@ pub fn set_elevation(&mut self, offset: (isize, isize), data: ndarray::ArrayView
         let data_range = Range2(to_rows.clone(), to_cols.clone());
 
         let src = data.slice(s!(from_rows, from_cols));
-        let mut dest = target_layer.slice_mut(s!(to_rows, to_cols));
-        dest.assign(&src);
+        target_layer.assign((to_rows.start, to_cols.start), src);
 
         self.dirty_range(data_range);
     }
 
     pub fn dirty_range(&mut self, range: Range2) {
+        for bi in self.block_info.iter_mut() {
+            if bi.range.overlaps(&range) {
+                bi.dirty = true;
+                bi.minimap_dirty = true;
+                bi.track_dirty = true;
+            }
+        }
+    }
+
+    /**
+     * Mark the meshes over a range for rebuilding, when only the holes in the surface
+     * have changed, and not its height.
+     */
+    pub fn dirty_mesh_range(&mut self, range: Range2) {
         for bi in self.block_info.iter_mut() {
             if bi.range.overlaps(&range) {
                 bi.dirty = true;
@@ -147,13 +194,6 @@ pub fn elevation_at(&self, point: Vec2) -> f32 {
             return -1.0;
         }
 
-        let elevation = &self.layers[&TerrainLayer::Elevation];
-        let elevation = elevation.read().unwrap();
-
-        if r < elevation.dim().0 && c < elevation.dim().1 {
-            elevation[(r, c)]
-        } else {
-            -1.0
-        }
+        self.layers[&TerrainLayer::Elevation].get((r, c)).unwrap_or(-1.0)
     }
 }
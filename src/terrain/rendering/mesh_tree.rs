@@ -17,6 +17,11 @@ pub enum BlockKind {
 
 pub struct Entry {
     pub kind: BlockKind,
+    pub generation: u32,
+    /** Geometric error of this block's own mesh, compared with its children's */
+    pub mesh_error: f32,
+    /** Geometric error of this block's mesh compared with the full terrain */
+    pub error: f32,
 }
 
 pub struct Level {
@@ -48,7 +53,7 @@ impl MeshTree {
                 entries: Array2::from_shape_fn((level_height, level_width), |(i, j)| {
                     let valid = i < valid_height && j < valid_width;
                     let kind = if valid { BlockKind::Pending } else { BlockKind::Invalid };
-                    Entry { kind }
+                    Entry { kind, generation: 0, mesh_error: 0.0, error: 0.0 }
                 })
             });
 
@@ -132,6 +137,42 @@ pub fn set_mesh(&mut self, block_id: BlockId, kind: BlockKind) -> Option<Entity>
         } else { None }
     }
 
+    /**
+     * Start a new generation of mesh for a block.  Any mesh from an earlier generation
+     * that is still being built is stale, and should not be used.
+     */
+    pub fn next_generation(&mut self, block_id: BlockId) -> u32 {
+        let entry = self.get_entry_mut(block_id);
+        entry.generation = entry.generation.wrapping_add(1);
+        entry.generation
+    }
+
+    pub fn current(&self, block_id: BlockId, generation: u32) -> bool {
+        self.get_entry(block_id).generation == generation
+    }
+
+    /**
+     * Record the geometric error of a block's mesh.  A block's total error is bounded by
+     * its own error plus the largest total error of its children, so that is updated for
+     * the block and each of its ancestors.
+     */
+    pub fn set_error(&mut self, block_id: BlockId, mesh_error: f32) {
+        self.get_entry_mut(block_id).mesh_error = mesh_error;
+
+        let mut block_id = block_id;
+        loop {
+            let child_error = self.children(block_id).iter()
+                .filter(|c| self.valid(**c))
+                .map(|c| self.get_entry(*c).error)
+                .fold(0.0, f32::max);
+            let entry = self.get_entry_mut(block_id);
+            entry.error = entry.mesh_error + child_error;
+
+            if block_id.level + 1 >= self.levels.len() { break; }
+            block_id = self.parent(block_id);
+        }
+    }
+
     pub fn valid(&self, block_id: BlockId) -> bool {
         !matches!(self.get_entry(block_id).kind, BlockKind::Invalid)
     }
@@ -207,4 +248,28 @@ fn test_structure() {
         tree.walk(&mut |_, _| { *nv += 1; true });
         assert_eq!(num_visited, 5, "num_visited");
     }
+
+    #[test]
+    fn test_generation() {
+        let mut tree = MeshTree::new([2, 2], 1);
+        let block_id = BlockId { row: 1, col: 0, level: 0 };
+        let first = tree.next_generation(block_id);
+        assert!(tree.current(block_id, first));
+        let second = tree.next_generation(block_id);
+        assert!(!tree.current(block_id, first));
+        assert!(tree.current(block_id, second));
+        assert!(tree.current(BlockId { row: 0, col: 0, level: 0 }, 0));
+    }
+
+    #[test]
+    fn test_error() {
+        let mut tree = MeshTree::new([2, 2], 1);
+        let root = BlockId { row: 0, col: 0, level: 1 };
+        tree.set_error(root, 1.0);
+        assert_eq!(tree.get_entry(root).error, 1.0);
+
+        tree.set_error(BlockId { row: 1, col: 1, level: 0 }, 0.5);
+        tree.set_error(BlockId { row: 0, col: 1, level: 0 }, 0.25);
+        assert_eq!(tree.get_entry(root).error, 1.5);
+    }
 }
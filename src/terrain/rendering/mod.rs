@@ -1,26 +1,32 @@
-use std::sync::{Arc, RwLock};
+use std::collections::HashSet;
+
 
 use bevy::{
     prelude::*,
     render::{
-        mesh::PrimitiveTopology,
+        mesh::{Indices, PrimitiveTopology},
         render_asset::RenderAssetUsages
     }};
+use bevy::math::Affine3A;
 use bevy::render::mesh::MeshAabb;
-use bevy::render::primitives::Aabb;
+use bevy::render::mesh::morph::{MeshMorphWeights, MorphAttributes, MorphTargetImage};
+use bevy::render::primitives::{Aabb, Frustum};
 use bevy::tasks::{block_on, AsyncComputeTaskPool, Task};
 use bevy::tasks::futures_lite::future;
 use ndarray::{s, Array2};
 
 use crate::events::GraphicsEvent;
 use crate::level::LevelLabel;
+use crate::terrain::chunks::ChunkedArray;
 use crate::terrain::heightmap::heightmap_to_mesh;
 use crate::terrain::rtin::{triangulate_rtin, Triangle, Triangulation};
 use crate::terrain::{Terrain, TerrainData, TerrainLayer};
 use crate::terrain::rendering::mesh_tree::{BlockId, BlockKind, MeshTree};
+use crate::terrain::rendering::overlays::TerrainOverlays;
 use crate::terrain::utils::Range2;
 
 pub mod mesh_tree;
+pub mod overlays;
 pub mod water;
 
 pub(crate) struct TerrainRenderingPlugin;
@@ -31,12 +37,23 @@ fn build(&self, app: &mut App) {
             .register_type::<TerrainMesh>()
             .add_systems(Startup, init_render_params)
             .init_resource::<MeshTaskQueue>()
+            .register_type::<MeshScheduler>()
+            .init_resource::<MeshScheduler>()
+            .register_type::<LodSettings>()
+            .init_resource::<LodSettings>()
+            .register_type::<overlays::TerrainOverlays>()
+            .init_resource::<overlays::TerrainOverlays>()
             .add_systems(Update, water::update_water)
+            .add_systems(Update, (
+                overlays::update_slope_tint,
+                overlays::update_overlay_lines,
+            ).after(handle_mesh_tasks))
             .add_systems(Update, (
                 update_layer_parents,
                 update_meshes,
                 handle_mesh_tasks,
                 select_meshes,
+                morph_meshes,
             ).chain());
     }
 }
@@ -48,46 +65,147 @@ pub struct TerrainMesh {
 }
 
 const RENDERS_PER_FRAME: usize = 16;
+const MAX_RENDERS_PER_FRAME: usize = 64;
+
+/**
+ * How long a mesh task should take to finish, once queued.  If tasks take longer than
+ * this, fewer blocks are rebuilt each frame.
+ */
+const TARGET_TASK_SECONDS: f32 = 0.25;
 const MAX_MESH_TREE_LEVEL: usize = 4;
 
+/**
+ * Skirts hang down from the edges of each block, to hide the gaps where it meets a
+ * neighbour with a different level of detail.  They need to reach down further than
+ * the error allowed in the coarser of the two blocks.
+ */
+const SKIRT_DEPTH_FACTOR: f32 = 2.0;
+
+/**
+ * Time taken for a block to morph from the shape of its parent to its own shape,
+ * when it replaces the parent.
+ */
+const MORPH_SECONDS: f32 = 0.5;
+
+/**
+ * Size in metres covered by one repeat of a texture on the terrain.  Blocks are always
+ * a multiple of this, so textures line up across their edges.
+ */
+const UV_TILE_SIZE: f32 = 8.0;
+
 #[derive(Resource)]
 pub struct TerrainRenderParams {
     dirt_material: Handle<StandardMaterial>,
     grass_material: Handle<StandardMaterial>,
     water_material: Handle<StandardMaterial>,
+    slope_material: Handle<StandardMaterial>,
+    line_material: Handle<StandardMaterial>,
 }
 
 pub struct MeshTask {
     terrain_mesh: TerrainMesh,
+    generation: u32,
     transform: Transform,
     material: Handle<StandardMaterial>,
-    task: Task<Mesh>,
+    queued_at: f32,
+    task: Task<(Mesh, Option<Image>, f32)>,
 }
 
 #[derive(Default, Resource)]
 pub struct MeshTaskQueue(Vec<MeshTask>);
 
+/**
+ * Decides how many dirty blocks are rebuilt each frame, based on how long recent mesh
+ * tasks have taken to complete.
+ */
+#[derive(Reflect, Resource)]
+#[reflect(Resource)]
+pub struct MeshScheduler {
+    pub budget: usize,
+    pub average_task_seconds: f32,
+}
+
+impl Default for MeshScheduler {
+    fn default() -> Self {
+        MeshScheduler {
+            budget: RENDERS_PER_FRAME,
+            average_task_seconds: 0.0,
+        }
+    }
+}
+
+impl MeshScheduler {
+    fn record_task(&mut self, seconds: f32) {
+        const SMOOTHING: f32 = 0.1;
+        self.average_task_seconds += (seconds - self.average_task_seconds) * SMOOTHING;
+    }
+
+    /**
+     * Raise or lower the budget by a step, depending on the recent task times.  This is
+     * done once a frame, however many tasks finish in it.
+     */
+    fn adjust_budget(&mut self) {
+        if self.average_task_seconds < TARGET_TASK_SECONDS {
+            self.budget = (self.budget + 1).min(MAX_RENDERS_PER_FRAME);
+        } else {
+            self.budget = (self.budget * 3 / 4).max(1);
+        }
+    }
+}
+
+/**
+ * How much detail the terrain is shown in.  A block is replaced by its children when its
+ * geometric error, projected onto the screen, would be more than this many pixels.
+ */
+#[derive(Reflect, Resource)]
+#[reflect(Resource)]
+pub struct LodSettings {
+    pub pixel_tolerance: f32,
+}
+
+impl Default for LodSettings {
+    fn default() -> Self {
+        LodSettings {
+            pixel_tolerance: 10.0,
+        }
+    }
+}
+
 pub fn init_render_params(
     mut materials: ResMut<Assets<StandardMaterial>>,
+    mut images: ResMut<Assets<Image>>,
     mut commands: Commands,
 ) {
-    let mut dirt_material = StandardMaterial::from(Color::srgb(0.51, 0.25, 0.03));
-    dirt_material.perceptual_roughness = 0.5;
-    dirt_material.reflectance = 0.1;
-    let mut grass_material = StandardMaterial::from(Color::srgba(0.3, 0.6, 0.2, 0.75));
-    grass_material.perceptual_roughness = 0.75;
-    grass_material.reflectance = 0.25;
     let mut water_material = StandardMaterial::from(Color::srgb(0.25, 0.41, 0.88));
     water_material.perceptual_roughness = 0.75;
     water_material.reflectance = 0.25;
     let params = TerrainRenderParams {
-        dirt_material: materials.add(dirt_material),
-        grass_material: materials.add(grass_material),
+        dirt_material: materials.add(layer_material(TerrainLayer::Elevation)),
+        grass_material: materials.add(layer_material(TerrainLayer::Structure)),
         water_material: materials.add(water_material),
+        slope_material: materials.add(overlays::slope_material(&mut images)),
+        line_material: materials.add(overlays::line_material()),
     };
     commands.insert_resource(params);
 }
 
+pub fn layer_material(layer: TerrainLayer) -> StandardMaterial {
+    match layer {
+        TerrainLayer::Elevation => {
+            let mut dirt_material = StandardMaterial::from(Color::srgb(0.51, 0.25, 0.03));
+            dirt_material.perceptual_roughness = 0.5;
+            dirt_material.reflectance = 0.1;
+            dirt_material
+        }
+        TerrainLayer::Structure => {
+            let mut grass_material = StandardMaterial::from(Color::srgba(0.3, 0.6, 0.2, 0.75));
+            grass_material.perceptual_roughness = 0.75;
+            grass_material.reflectance = 0.25;
+            grass_material
+        }
+    }
+}
+
 #[derive(Component)]
 pub struct LayerLabel(pub TerrainLayer);
 
@@ -117,33 +235,47 @@ pub fn update_layer_parents(
 }
 
 pub fn update_meshes(
+    time: Res<Time<Real>>,
     mut level: Single<(&Terrain, &mut TerrainData), With<LevelLabel>>,
+    camera: Single<(&GlobalTransform, &Frustum), With<Camera>>,
     params: Res<TerrainRenderParams>,
-    mesh_trees: Query<(&LayerLabel, &MeshTree)>,
+    overlays: Res<TerrainOverlays>,
+    scheduler: Res<MeshScheduler>,
+    mut mesh_trees: Query<(&LayerLabel, &mut MeshTree)>,
+    visibilities: Query<&Visibility, With<TerrainMesh>>,
     mut mesh_task_queue: ResMut<MeshTaskQueue>,
 ) {
     let (terrain, terrain_data) = &mut *level;
 
-    /* Collect affected blocks */
+    /* Collect affected blocks, most important first */
     let blocks: Vec<_> = terrain_data.block_info.indexed_iter()
         .filter_map(|((i, j), bi)| if bi.dirty { Some((i, j))} else { None })
-        .take(RENDERS_PER_FRAME)
         .collect();
 
     if blocks.is_empty() { return; }
 
+    let elevation_tree = mesh_trees.iter()
+        .find_map(|(l, t)| if l.0 == TerrainLayer::Elevation { Some(t) } else { None });
+    let priorities: Vec<_> = blocks.iter()
+        .map(|(i, j)| {
+            let block_id = BlockId { row: *i, col: *j, level: 0 };
+            let shown_level = elevation_tree.map_or(0, |tree| shown_level(tree, block_id, &visibilities));
+            block_priority(terrain.block_size, block_id, shown_level, &camera)
+        })
+        .collect();
+    let mut order: Vec<_> = (0..blocks.len()).collect();
+    order.sort_by(|a, b| priorities[*b].total_cmp(&priorities[*a]));
+    let blocks: Vec<_> = order.iter().take(scheduler.budget).map(|ix| blocks[*ix]).collect();
+
     info!("Updating {} blocks", blocks.len());
 
     /* Process each layer */
     for (layer, elevation) in &terrain_data.layers {
         /* Get the parent, mesh tree, and various render settings for this layer */
-        let Some(tree) = mesh_trees.iter().find_map(|(l, t)| if l.0 == *layer { Some(t) } else { None })
+        let Some(mut tree) = mesh_trees.iter_mut().find_map(|(l, t)| if l.0 == *layer { Some(t) } else { None })
         else { continue; };
 
-        let (layer_height_adjust, layer_material) = match layer {
-            TerrainLayer::Elevation => (0.0, params.dirt_material.clone()),
-            TerrainLayer::Structure => (-1.0, params.grass_material.clone()),
-        };
+        let layer_material = overlays::terrain_material(*layer, &overlays, &params);
 
         /* Figure out which blocks are needed */
         let mut blocks_needed = Vec::new();
@@ -161,25 +293,38 @@ pub fn update_meshes(
 
             let range = block_range(terrain.block_size, *block);
             let (threshold, spacing) = block_quality(*block);
+            let (parent_threshold, _) = block_quality(tree.parent(*block));
+            let skirt_depth = parent_threshold * SKIRT_DEPTH_FACTOR;
 
             let terrain_mesh = TerrainMesh {
                 layer: *layer,
                 block_id: *block,
             };
-            let level_size = terrain.block_size * (1 << block.level);
-            let xp = block.col as f32 * level_size as f32;
-            let yp = block.row as f32 * level_size as f32;
-            let transform = Transform::from_xyz(xp, layer_height_adjust, yp);
+            let transform = block_transform(terrain.block_size, *block, *layer);
             let material = layer_material.clone();
 
+            /* Any task still building this block's mesh has been superseded */
+            let generation = tree.next_generation(*block);
+            mesh_task_queue.0.retain(|mt|
+                mt.terrain_mesh.layer != *layer || mt.terrain_mesh.block_id != *block);
+
+            let holes: Vec<_> = terrain_data.holes.iter()
+                .filter(|(r, c)| range.0.contains(r) && range.1.contains(c))
+                .copied()
+                .collect();
+
             queue_mesh_task(
                 terrain_mesh,
+                generation,
                 transform,
                 material,
                 threshold,
                 spacing,
-                elevation.clone(),
+                skirt_depth,
+                elevation,
                 range.clone(),
+                holes,
+                time.elapsed_secs(),
                 &mut mesh_task_queue.0
             );
         }
@@ -191,59 +336,268 @@ pub fn update_meshes(
     }
 }
 
-fn create_mesh(data: ndarray::ArrayView2<f32>, scale: &Vec3, threshold: f32) -> Mesh {
+/**
+ * Build meshes for all of a layer's blocks at one level of detail, in this thread.
+ * This is for exporting the terrain, rather than showing it.
+ */
+pub fn create_block_meshes(terrain: &Terrain, data: &ChunkedArray, layer: TerrainLayer, level: usize) -> Vec<(BlockId, Transform, Mesh)> {
+    let tree = MeshTree::new(terrain.num_blocks, MAX_MESH_TREE_LEVEL);
+    let level = level.min(tree.levels.len() - 1);
+
+    let mut blocks = Vec::new();
+    tree.walk(&mut |tree, block_id| {
+        if block_id.level == level && tree.valid(block_id) {
+            blocks.push(block_id);
+        }
+        block_id.level > level
+    });
+
+    blocks.into_iter().map(|block| {
+        let range = block_range(terrain.block_size, block);
+        let (threshold, spacing) = block_quality(block);
+        let (parent_threshold, _) = block_quality(tree.parent(block));
+        let data = data.slice(range.0, range.1, spacing as usize);
+        let scale = Vec3::new(spacing as f32, 1.0, spacing as f32);
+        let (mesh, _) = create_mesh(data.view(), &scale, threshold, parent_threshold * SKIRT_DEPTH_FACTOR, None);
+        (block, block_transform(terrain.block_size, block, layer), mesh)
+    }).collect()
+}
+
+/**
+ * Build a block's mesh, returning it along with its largest vertical error.  The mesh is
+ * refined as far as it goes around each point of the hole mask, and the triangles
+ * touching those points are left out.
+ */
+fn create_mesh(data: ndarray::ArrayView2<f32>, scale: &Vec3, threshold: f32, skirt_depth: f32, holes: Option<ndarray::ArrayView2<bool>>) -> (Mesh, f32) {
     let _span = info_span!("create.mesh").entered();
 
     if threshold == 0.0 {
-        heightmap_to_mesh(&data, scale)
+        (heightmap_to_mesh(&data, scale), 0.0)
     } else {
-        let Triangulation { triangles } = triangulate_rtin(&data, threshold);
+        let Triangulation { mut triangles, error } = triangulate_rtin(&data, threshold, holes.as_ref());
+        if let Some(holes) = holes {
+            triangles.retain(|triangle| !covers_hole(triangle, &holes));
+        }
+
+        /* Each point of the grid used by the triangulation gets one vertex */
+        let mut vertex_ids = Array2::from_elem(data.dim(), u32::MAX);
+        let mut pos = Vec::new();
+        let mut norms = Vec::new();
+        let mut uvs = Vec::new();
+        let mut slope_uvs = Vec::new();
+        let mut add_vertex = |[r, c]: [usize; 2], depth: f32| {
+            let p = Vec3::new(c as f32 * scale.x, data[(r, c)] - depth, r as f32 * scale.z);
+            let normal = grid_normal(&data, r, c, scale);
+            pos.push(p);
+            norms.push(normal);
+            uvs.push(Vec2::new(p.x, p.z) / UV_TILE_SIZE);
+            slope_uvs.push(overlays::slope_uv(normal));
+            (pos.len() - 1) as u32
+        };
+
+        let mut inds = Vec::new();
+        for Triangle { points } in &triangles {
+            for point in points {
+                let id = &mut vertex_ids[*point];
+                if *id == u32::MAX {
+                    *id = add_vertex(*point, 0.0);
+                }
+                inds.push(*id);
+            }
+        }
 
-        let mut pos  = Vec::new();
+        /* Hang a skirt below each edge of the triangulation that lies along the block's border */
+        let (last_row, last_col) = (data.dim().0 - 1, data.dim().1 - 1);
+        let on_border = |[r1, c1]: [usize; 2], [r2, c2]: [usize; 2]| {
+            (r1 == r2 && (r1 == 0 || r1 == last_row)) || (c1 == c2 && (c1 == 0 || c1 == last_col))
+        };
+        let mut skirt_ids = Array2::from_elem(data.dim(), u32::MAX);
         for Triangle { points } in &triangles {
-            let p = points.map(|[r,c]| {
-                let h = data[(r, c)];
-                Vec3::new(c as f32 * scale.x, h, r as f32 * scale.z)
-            });
-            pos.extend(p);
+            for i in 0..3 {
+                let (p, q, o) = (points[i], points[(i + 1) % 3], points[(i + 2) % 3]);
+                if !on_border(p, q) { continue; }
+
+                /* Orient the skirt to face away from the rest of the triangle */
+                let side = (q[0] as isize - p[0] as isize) * (o[1] as isize - p[1] as isize)
+                    - (q[1] as isize - p[1] as isize) * (o[0] as isize - p[0] as isize);
+                let (a, b) = if side > 0 { (p, q) } else { (q, p) };
+                for point in [a, b] {
+                    if skirt_ids[point] == u32::MAX {
+                        skirt_ids[point] = add_vertex(point, skirt_depth);
+                    }
+                }
+                let (a_low, b_low) = (skirt_ids[a], skirt_ids[b]);
+                let (a, b) = (vertex_ids[a], vertex_ids[b]);
+                inds.extend([b, a, a_low, b, a_low, b_low]);
+            }
+        }
+
+        let mesh = Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::default())
+            .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, pos)
+            .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, norms)
+            .with_inserted_attribute(Mesh::ATTRIBUTE_UV_0, uvs)
+            .with_inserted_attribute(Mesh::ATTRIBUTE_UV_1, slope_uvs)
+            .with_inserted_indices(Indices::U32(inds));
+
+        (mesh, error)
+    }
+}
+
+/**
+ * Whether any point of the hole mask lies in or on a triangle of the grid.
+ */
+fn covers_hole(Triangle { points }: &Triangle, holes: &ndarray::ArrayView2<bool>) -> bool {
+    let rows = points.iter().map(|p| p[0]).min().unwrap()..=points.iter().map(|p| p[0]).max().unwrap();
+    let cols = points.iter().map(|p| p[1]).min().unwrap()..=points.iter().map(|p| p[1]).max().unwrap();
+    let [p0, p1, p2] = points.map(|[r, c]| IVec2::new(r as i32, c as i32));
+    let side = |p: IVec2, q: IVec2, o: IVec2| (q - p).perp_dot(o - p).signum();
+
+    rows.flat_map(|r| cols.clone().map(move |c| (r, c)))
+        .filter(|point| holes.get(*point).copied().unwrap_or(false))
+        .any(|(r, c)| {
+            let o = IVec2::new(r as i32, c as i32);
+            let sides = [side(p0, p1, o), side(p1, p2, o), side(p2, p0, o)];
+            !(sides.contains(&1) && sides.contains(&-1))
+        })
+}
+
+/**
+ * Mask of the holes in a block, in the grid of points it is built from: each hole goes
+ * to the nearest point of the grid.
+ */
+fn hole_mask(holes: &[(usize, usize)], origin: (usize, usize), spacing: usize, dim: (usize, usize)) -> Option<Array2<bool>> {
+    if holes.is_empty() { return None; }
+
+    let mut mask = Array2::from_elem(dim, false);
+    for (row, col) in holes {
+        let index = ((row - origin.0 + spacing / 2) / spacing, (col - origin.1 + spacing / 2) / spacing);
+        if let Some(hole) = mask.get_mut(index) {
+            *hole = true;
+        }
+    }
+    Some(mask)
+}
+
+/**
+ * Normal of the terrain at a point of the grid, estimated from the slope between its
+ * neighbours on either side (or the point itself, at the edge of the data).
+ */
+fn grid_normal(data: &ndarray::ArrayView2<f32>, r: usize, c: usize, scale: &Vec3) -> Vec3 {
+    let (rows, cols) = data.dim();
+    let (r0, r1) = (r.saturating_sub(1), (r + 1).min(rows - 1));
+    let (c0, c1) = (c.saturating_sub(1), (c + 1).min(cols - 1));
+
+    let dx = (data[(r, c1)] - data[(r, c0)]) * scale.y / ((c1 - c0).max(1) as f32 * scale.x);
+    let dz = (data[(r1, c)] - data[(r0, c)]) * scale.y / ((r1 - r0).max(1) as f32 * scale.z);
+
+    Vec3::new(-dx, 1.0, -dz).normalize()
+}
+
+/**
+ * Largest vertical distance between a grid of points and the surface through every other
+ * point of it, which is what a block's mesh is built from when its children use the grid.
+ */
+fn sampling_error(fine: ndarray::ArrayView2<f32>) -> f32 {
+    fine.indexed_iter()
+        .map(|((r, c), h)| (coarse_height(&fine, r, c) - h).abs())
+        .fold(0.0, f32::max)
+}
+
+/**
+ * Height at a point of the grid, taken from only the points that are in the grid with
+ * half as many points in each direction: points not in that grid take the average of
+ * their neighbours that are.
+ */
+fn coarse_height(data: &ndarray::ArrayView2<f32>, r: usize, c: usize) -> f32 {
+    let (rows, cols) = data.dim();
+    let rs = if r % 2 == 0 { [r, r] } else { [r - 1, (r + 1).min(rows - 1)] };
+    let cs = if c % 2 == 0 { [c, c] } else { [c - 1, (c + 1).min(cols - 1)] };
+    (data[(rs[0], cs[0])] + data[(rs[0], cs[1])] + data[(rs[1], cs[0])] + data[(rs[1], cs[1])]) / 4.0
+}
+
+/**
+ * Create morph targets that move each vertex of a mesh to where it would be in the
+ * next coarser level of detail, which has half as many points in each direction.
+ */
+fn create_morph_targets(mesh: &Mesh, data: ndarray::ArrayView2<f32>, scale: &Vec3) -> Option<Image> {
+    let positions = mesh.attribute(Mesh::ATTRIBUTE_POSITION)?.as_float3()?;
+    let (rows, cols) = data.dim();
+
+    let attributes = positions.iter().map(|[x, _, z]| {
+        let r = ((z / scale.z).round() as usize).min(rows - 1);
+        let c = ((x / scale.x).round() as usize).min(cols - 1);
+        let delta = coarse_height(&data, r, c) - data[(r, c)];
+        MorphAttributes::new(Vec3::Y * delta, Vec3::ZERO, Vec3::ZERO)
+    });
+
+    match MorphTargetImage::new([attributes].into_iter(), positions.len(), RenderAssetUsages::RENDER_WORLD) {
+        Ok(image) => Some(image.0),
+        Err(err) => {
+            warn!("Could not create morph targets for terrain mesh: {err}");
+            None
         }
-        let mut mesh = Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::default());
-        mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, pos);
-        mesh.compute_flat_normals();
-        mesh
     }
 }
 
+/**
+ * Start building a block's mesh in the background.  The task copies out the part of the
+ * data it needs first, so the layer's chunks aren't kept locked while the mesh is built.
+ */
 fn queue_mesh_task(
     terrain_mesh: TerrainMesh,
+    generation: u32,
     transform: Transform,
     material: Handle<StandardMaterial>,
     threshold: f32,
     spacing: i32,
-    data: Arc<RwLock<Array2<f32>>>,
+    skirt_depth: f32,
+    data: &ChunkedArray,
     range: Range2,
+    holes: Vec<(usize, usize)>,
+    queued_at: f32,
     queue: &mut Vec<MeshTask>
 ) {
     let thread_pool = AsyncComputeTaskPool::get();
 
+    let data = data.clone();
     let task = thread_pool.spawn(async move {
-        let data = data.read().unwrap();
-        let elevation_view = data.slice(s!(range.0.clone();spacing, range.1.clone();spacing));
-        create_mesh(elevation_view, &Vec3::new(spacing as f32, 1.0, spacing as f32), threshold)
+        let origin = (range.0.start, range.1.start);
+
+        /* Blocks above the lowest level are also measured against their children's grid */
+        let (data, sampling_error) = if spacing > 1 {
+            let fine = data.slice(range.0, range.1, spacing as usize / 2);
+            let error = sampling_error(fine.view());
+            (fine.slice(s!(..;2, ..;2)).to_owned(), error)
+        } else {
+            (data.slice(range.0, range.1, 1), 0.0)
+        };
+
+        let elevation_view = data.view();
+        let scale = Vec3::new(spacing as f32, 1.0, spacing as f32);
+        let holes = hole_mask(&holes, origin, spacing as usize, data.dim());
+        let (mesh, mesh_error) = create_mesh(elevation_view, &scale, threshold, skirt_depth, holes.as_ref().map(|h| h.view()));
+        let morph_targets = create_morph_targets(&mesh, elevation_view, &scale);
+        (mesh, morph_targets, mesh_error + sampling_error)
     });
 
     queue.push(MeshTask {
         terrain_mesh,
+        generation,
         transform,
         material,
+        queued_at,
         task,
     });
 }
 
 pub fn handle_mesh_tasks(
+    time: Res<Time<Real>>,
+    mut scheduler: ResMut<MeshScheduler>,
     mut meshes: ResMut<Assets<Mesh>>,
+    mut images: ResMut<Assets<Image>>,
     mut mesh_task_queue: ResMut<MeshTaskQueue>,
     mut mesh_trees: Query<(Entity, &LayerLabel, &mut MeshTree)>,
+    visibilities: Query<&Visibility, With<TerrainMesh>>,
     mut commands: Commands,
     mut events: EventWriter<GraphicsEvent>,
 ) {
@@ -252,13 +606,12 @@ pub fn handle_mesh_tasks(
     let old_queue = std::mem::take(&mut mesh_task_queue.0);
 
     let mut any_change = false;
+    let mut any_finished = false;
 
     for mut mt in old_queue {
-        if let Some(mesh) = block_on(future::poll_once(&mut mt.task)) {
-            let Some(aabb) = mesh.compute_aabb()
-                else { warn!("Could not compute Aabb for terrain mesh"); continue; };
-
-            let handle = meshes.add(mesh);
+        if let Some((mut mesh, morph_targets, error)) = block_on(future::poll_once(&mut mt.task)) {
+            scheduler.record_task(time.elapsed_secs() - mt.queued_at);
+            any_finished = true;
 
             let block_id = mt.terrain_mesh.block_id;
             let layer = mt.terrain_mesh.layer;
@@ -266,16 +619,42 @@ pub fn handle_mesh_tasks(
             let Some((parent_id, _, mut tree)) = mesh_trees.iter_mut().find(|(_, l, _)| l.0 == layer)
             else { continue; };
 
-            let id = commands.spawn((
+            if !tree.current(block_id, mt.generation) {
+                debug!("Dropping stale mesh for {:?}", block_id);
+                continue;
+            }
+
+            tree.set_error(block_id, error);
+
+            let Some(aabb) = mesh.compute_aabb()
+                else { warn!("Could not compute Aabb for terrain mesh"); continue; };
+
+            let has_morph_targets = morph_targets.is_some();
+            if let Some(morph_targets) = morph_targets {
+                mesh.set_morph_targets(images.add(morph_targets));
+            }
+
+            let handle = meshes.add(mesh);
+
+            /* A rebuilt block keeps the visibility of the mesh it replaces, so it doesn't morph */
+            let visibility = match tree.get_entry(block_id).kind {
+                BlockKind::Populated(old_id) => visibilities.get(old_id).copied().unwrap_or(Visibility::Hidden),
+                _ => Visibility::Hidden,
+            };
+
+            let mut entity = commands.spawn((
                 mt.terrain_mesh,
                 Mesh3d(handle),
                 MeshMaterial3d(mt.material),
                 mt.transform,
-                Visibility::Hidden,
+                visibility,
                 aabb,
                 ChildOf(parent_id),
-            ))
-                .id();
+            ));
+            if has_morph_targets {
+                entity.insert(MeshMorphWeights::new(vec![0.0]).unwrap());
+            }
+            let id = entity.id();
 
             if let Some(old_id) = tree.set_mesh(block_id, BlockKind::Populated(id)) {
                 commands.entity(old_id).despawn();
@@ -287,26 +666,40 @@ pub fn handle_mesh_tasks(
         }
     }
 
+    if any_finished {
+        scheduler.adjust_budget();
+    }
+
     if any_change {
         events.write(GraphicsEvent::RenderTerrain);
     }
 }
 
+/**
+ * Choose which level of detail to show each part of the terrain at.
+ *
+ * Each block's geometric error is projected onto the screen at its distance from the
+ * camera, and the block is replaced by its children if that is more than the tolerance.
+ * Blocks out of view are never replaced, so the tree is only walked near the frustum.
+ */
 pub fn select_meshes(
-    terrain: Single<&Terrain, With<LevelLabel>>,
-    camera: Single<&GlobalTransform, With<Camera>>,
-    mesh_trees: Query<&mut MeshTree>,
-    mut meshes: Query<(&GlobalTransform, &Aabb, &mut Visibility), Without<Camera>>,
+    camera: Single<(Ref<GlobalTransform>, Ref<Camera>, Ref<Projection>, &Frustum)>,
+    settings: Res<LodSettings>,
+    mesh_trees: Query<&MeshTree>,
+    mut meshes: Query<(&GlobalTransform, &Aabb, &mut Visibility, Option<&mut MeshMorphWeights>), Without<Camera>>,
     mut events: EventReader<GraphicsEvent>,
 ) {
-    const TOLERANCE: f32 = 50.0;
+    let (camera_transform, camera, projection, frustum) = camera.into_inner();
 
-    if !events.read().any(|e|
-        matches!(e, GraphicsEvent::MoveCamera | GraphicsEvent::RenderTerrain)
-    ) {
+    let new_meshes = events.read().any(|e| matches!(e, GraphicsEvent::RenderTerrain));
+    let camera_changed = camera_transform.is_changed() || camera.is_changed() || projection.is_changed();
+    if !new_meshes && !camera_changed && !settings.is_changed() {
         return;
     }
 
+    let Some(viewport_size) = camera.logical_viewport_size() else { return; };
+    let camera_position = camera_transform.translation();
+
     fn set_vis(vis: &mut Mut<Visibility>, new_value: Visibility) {
         if **vis != new_value {
             **vis = new_value;
@@ -314,57 +707,135 @@ fn set_vis(vis: &mut Mut<Visibility>, new_value: Visibility) {
     }
 
     for tree in &mesh_trees {
+        /* Blocks that were shown until they were refined on this walk */
+        let mut refined = HashSet::new();
         tree.walk(&mut |tree, block_id| {
             let entry = tree.get_entry(block_id);
-            let descend = match entry.kind {
-                BlockKind::Populated(entity) => {
-                    if let Ok((mesh_transform, aabb, mut vis)) = meshes.get_mut(entity) {
-                        let mut too_close = false;
-                        if block_id.level > 0 {
-                            let centre = mesh_transform.translation() + Vec3::from(aabb.center);
-                            let distance = centre.distance(camera.translation());
-                            let cutoff = terrain.block_size as f32 * 4.0 * (1 << block_id.level) as f32;
-                            too_close = distance < cutoff;
-
-                            /* Check all children are populated; if not, then just use this block */
-                            if too_close && !tree.children(block_id).iter()
-                                .all(|child| tree.populated(*child)) {
-                                too_close = false;
-                            }
-                        }
+            let BlockKind::Populated(entity) = entry.kind else { return true; };
+            let Ok((mesh_transform, aabb, mut vis, morph_weights)) = meshes.get_mut(entity) else {
+                warn!("no mesh?");
+                return true;
+            };
 
-                        if too_close {
-                            set_vis(&mut vis, Visibility::Hidden);
-                        } else {
-                            set_vis(&mut vis, Visibility::Inherited);
-                        }
+            let mut refine = false;
+            if block_id.level > 0 && frustum.intersects_obb(aabb, &mesh_transform.affine(), true, false) {
+                let centre = mesh_transform.transform_point(Vec3::from(aabb.center));
+                let distance = ((camera_position - centre).abs() - Vec3::from(aabb.half_extents)).max(Vec3::ZERO).length();
+                refine = projected_error(entry.error, distance, &projection, viewport_size.y) > settings.pixel_tolerance;
 
-                        too_close
-                    } else {
-                        warn!("no mesh?");
-                        true
-                    }
-                },
-                _ => true
-            };
+                /* Check all children are populated; if not, then just use this block */
+                if refine && !tree.children(block_id).iter()
+                    .all(|child| tree.populated(*child)) {
+                    refine = false;
+                }
+            }
+
+            if refine {
+                if *vis != Visibility::Hidden {
+                    refined.insert(block_id);
+                }
+                set_vis(&mut vis, Visibility::Hidden);
+                return true;
+            }
+
+            if *vis == Visibility::Hidden {
+                /*
+                 * When a block replaces its parent, start it in its parent's shape.  When it
+                 * replaces its children, it starts in its own.
+                 */
+                let has_parent = block_id.level + 1 < tree.levels.len();
+                let replaces_parent = has_parent && refined.contains(&tree.parent(block_id));
+                if let Some(mut morph_weights) = morph_weights {
+                    morph_weights.weights_mut()[0] = if replaces_parent { 1.0 } else { 0.0 };
+                }
+                set_vis(&mut vis, Visibility::Inherited);
 
-            /* Hide all the descendents */
-            if !descend {
+                /* Hide all the descendants; while a block is shown, they stay hidden */
                 for child in tree.descendants(block_id) {
                     if child == block_id { continue; }
                     if let BlockKind::Populated(entity) = tree.get_entry(child).kind {
-                        if let Ok((_, _, mut vis)) = meshes.get_mut(entity) {
+                        if let Ok((_, _, mut vis, _)) = meshes.get_mut(entity) {
                             set_vis(&mut vis, Visibility::Hidden);
                         }
                     }
                 }
             }
 
-            descend
+            false
         });
     }
 }
 
+/**
+ * Size in pixels that a vertical error would appear on screen, at some distance.
+ */
+fn projected_error(error: f32, distance: f32, projection: &Projection, viewport_height: f32) -> f32 {
+    match projection {
+        Projection::Orthographic(ortho) => error * viewport_height / ortho.area.height(),
+        Projection::Perspective(persp) => {
+            error * viewport_height / (2.0 * (persp.fov / 2.0).tan() * distance.max(persp.near))
+        },
+        _ => {
+            let persp = PerspectiveProjection::default();
+            error * viewport_height / (2.0 * (persp.fov / 2.0).tan() * distance.max(persp.near))
+        },
+    }
+}
+
+/**
+ * Gradually morph blocks from the shape of their parent to their own shape.
+ */
+pub fn morph_meshes(
+    time: Res<Time<Real>>,
+    mut meshes: Query<&mut MeshMorphWeights, With<TerrainMesh>>,
+) {
+    let step = time.delta_secs() / MORPH_SECONDS;
+
+    for mut morph_weights in meshes.iter_mut() {
+        let weight = morph_weights.weights()[0];
+        if weight > 0.0 {
+            morph_weights.weights_mut()[0] = (weight - step).max(0.0);
+        }
+    }
+}
+
+/**
+ * Level of detail currently shown for the part of the terrain covered by a block.
+ */
+fn shown_level(tree: &MeshTree, block_id: BlockId, visibilities: &Query<&Visibility, With<TerrainMesh>>) -> usize {
+    std::iter::once(block_id)
+        .chain(tree.ancestors(block_id))
+        .find(|b| match tree.get_entry(*b).kind {
+            BlockKind::Populated(entity) => visibilities.get(entity).is_ok_and(|v| *v != Visibility::Hidden),
+            _ => false,
+        })
+        .map_or(0, |b| b.level)
+}
+
+/**
+ * How important it is to rebuild a block: blocks near the camera are more important than
+ * those far away, and blocks that are in view or shown in detail are more important still.
+ */
+fn block_priority(block_size: usize, block_id: BlockId, shown_level: usize, camera: &(&GlobalTransform, &Frustum)) -> f32 {
+    const OUT_OF_VIEW_FACTOR: f32 = 0.1;
+    const BLOCK_HEIGHT: f32 = 1000.0;
+
+    let (camera_transform, frustum) = camera;
+
+    let size = block_size as f32;
+    let centre = Vec3::new((block_id.col as f32 + 0.5) * size, 0.0, (block_id.row as f32 + 0.5) * size);
+    let aabb = Aabb::from_min_max(
+        centre - Vec3::new(size / 2.0, BLOCK_HEIGHT, size / 2.0),
+        centre + Vec3::new(size / 2.0, BLOCK_HEIGHT, size / 2.0));
+
+    let distance = centre.distance(camera_transform.translation());
+    let visible = frustum.intersects_obb(&aabb, &Affine3A::IDENTITY, true, true);
+
+    let mut priority = 1.0 / (distance + size);
+    if !visible { priority *= OUT_OF_VIEW_FACTOR; }
+    priority / (1 << shown_level) as f32
+}
+
 fn block_range(block_size: usize, block_id: BlockId) -> Range2 {
     let (row, col) = (block_id.row, block_id.col);
     let level_block_size = (1 << block_id.level) * block_size;
@@ -372,6 +843,17 @@ fn block_range(block_size: usize, block_id: BlockId) -> Range2 {
            col * level_block_size..(col+1) * level_block_size + 1)
 }
 
+fn block_transform(block_size: usize, block_id: BlockId, layer: TerrainLayer) -> Transform {
+    let layer_height_adjust = match layer {
+        TerrainLayer::Elevation => 0.0,
+        TerrainLayer::Structure => -1.0,
+    };
+    let level_size = block_size * (1 << block_id.level);
+    let xp = block_id.col as f32 * level_size as f32;
+    let yp = block_id.row as f32 * level_size as f32;
+    Transform::from_xyz(xp, layer_height_adjust, yp)
+}
+
 fn block_quality(block_id: BlockId) -> (f32, i32) {
     let quality = 0.125f32 + 1.5f32.powi(block_id.level as i32);
     let spacing = 1 << block_id.level;
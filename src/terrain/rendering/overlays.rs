@@ -0,0 +1,375 @@
+use bevy::asset::RenderAssetUsages;
+use bevy::image::ImageSampler;
+use bevy::pbr::UvChannel;
+use bevy::prelude::*;
+use bevy::render::mesh::PrimitiveTopology;
+use bevy::render::render_resource::{Extent3d, TextureDimension, TextureFormat};
+use ndarray::ArrayView2;
+
+use crate::level::LevelLabel;
+use crate::terrain::rendering::{block_quality, block_range, TerrainMesh, TerrainRenderParams};
+use crate::terrain::{Terrain, TerrainData, TerrainLayer};
+
+/**
+ * Extra information drawn over the terrain, to help with reading heights when planning
+ * routes.
+ *
+ * Contour lines and the easting/northing grid are built per block, from the same points
+ * as the block's mesh, and are children of it; so they are shown at the same level of
+ * detail as the terrain.  The slope tint is a separate material for the elevation layer,
+ * which colours each vertex by the gradient of the ground there.
+ */
+#[derive(Reflect, Resource)]
+#[reflect(Resource)]
+pub struct TerrainOverlays {
+    pub contours: bool,
+    /** Height between contour lines, in metres. */
+    pub contour_interval: f32,
+    /** Every this many contours is a major one, drawn darker. */
+    pub major_contour_every: u32,
+    pub slope_tint: bool,
+    pub grid: bool,
+    /** Distance between grid lines, in coordinate system units. */
+    pub grid_spacing: f32,
+}
+
+impl Default for TerrainOverlays {
+    fn default() -> Self {
+        TerrainOverlays {
+            contours: false,
+            contour_interval: 5.0,
+            major_contour_every: 5,
+            slope_tint: false,
+            grid: false,
+            grid_spacing: 100.0,
+        }
+    }
+}
+
+/**
+ * Upper gradients of each slope class, and the colour it is tinted.  The last class
+ * covers everything steeper.
+ */
+const SLOPE_CLASSES: [(f32, [u8; 4]); 4] = [
+    (0.02, [64, 160, 64, 255]),
+    (0.04, [224, 208, 64, 255]),
+    (0.10, [224, 128, 32, 255]),
+    (f32::INFINITY, [192, 32, 32, 255]),
+];
+
+const CONTOUR_COLOUR: Color = Color::srgb(0.35, 0.2, 0.05);
+const MAJOR_CONTOUR_COLOUR: Color = Color::srgb(0.15, 0.08, 0.0);
+const GRID_COLOUR: Color = Color::srgb(0.9, 0.9, 0.9);
+
+/**
+ * Height the lines are drawn above the terrain, so they aren't hidden by it.
+ */
+const LINE_LIFT: f32 = 0.3;
+
+/**
+ * Most blocks to rebuild the lines for each frame.
+ */
+const LINE_BLOCKS_PER_FRAME: usize = 64;
+
+/**
+ * The settings a terrain mesh's overlay lines were last built with.
+ */
+#[derive(Component, Clone, Debug, PartialEq)]
+pub struct OverlayLines {
+    contour_interval: Option<f32>,
+    grid_spacing: Option<f32>,
+}
+
+impl OverlayLines {
+    /** What a mesh without any lines has, whether or not they were ever built. */
+    const NONE: OverlayLines = OverlayLines { contour_interval: None, grid_spacing: None };
+}
+
+#[derive(Component)]
+pub struct OverlayLinesMesh;
+
+/**
+ * Material for the elevation layer that shows its slope class, using the second set of
+ * UVs on each mesh to look up a palette.
+ */
+pub(super) fn slope_material(images: &mut Assets<Image>) -> StandardMaterial {
+    let extent = Extent3d {
+        width: SLOPE_CLASSES.len() as u32,
+        height: 1,
+        depth_or_array_layers: 1,
+    };
+    let data = SLOPE_CLASSES.iter().flat_map(|(_, colour)| *colour).collect();
+    let mut palette = Image::new(extent, TextureDimension::D2, data, TextureFormat::Rgba8UnormSrgb, RenderAssetUsages::RENDER_WORLD);
+    palette.sampler = ImageSampler::nearest();
+
+    StandardMaterial {
+        base_color_texture: Some(images.add(palette)),
+        base_color_channel: UvChannel::Uv1,
+        perceptual_roughness: 0.75,
+        reflectance: 0.1,
+        ..default()
+    }
+}
+
+pub(super) fn line_material() -> StandardMaterial {
+    StandardMaterial {
+        unlit: true,
+        depth_bias: 100.0,
+        ..default()
+    }
+}
+
+/**
+ * Coordinates into the slope palette for a vertex with the given normal.
+ */
+pub(super) fn slope_uv(normal: Vec3) -> Vec2 {
+    let gradient = normal.xz().length() / normal.y.max(f32::EPSILON);
+    let class = SLOPE_CLASSES.iter()
+        .position(|(max, _)| gradient < *max)
+        .unwrap_or(SLOPE_CLASSES.len() - 1);
+    Vec2::new((class as f32 + 0.5) / SLOPE_CLASSES.len() as f32, 0.5)
+}
+
+pub(super) fn terrain_material(layer: TerrainLayer, overlays: &TerrainOverlays, params: &TerrainRenderParams) -> Handle<StandardMaterial> {
+    match layer {
+        TerrainLayer::Elevation if overlays.slope_tint => params.slope_material.clone(),
+        TerrainLayer::Elevation => params.dirt_material.clone(),
+        TerrainLayer::Structure => params.grass_material.clone(),
+    }
+}
+
+pub fn update_slope_tint(
+    overlays: Res<TerrainOverlays>,
+    params: Res<TerrainRenderParams>,
+    mut terrain_meshes: Query<(&TerrainMesh, &mut MeshMaterial3d<StandardMaterial>)>,
+) {
+    if !overlays.is_changed() { return; }
+
+    for (terrain_mesh, mut material) in terrain_meshes.iter_mut() {
+        let new_material = terrain_material(terrain_mesh.layer, &overlays, &params);
+        if material.0 != new_material {
+            material.0 = new_material;
+        }
+    }
+}
+
+/**
+ * Rebuild the contour and grid lines for any elevation meshes that were built with
+ * different settings, or not built at all.  Meshes being shown are done first.
+ */
+pub fn update_overlay_lines(
+    overlays: Res<TerrainOverlays>,
+    params: Res<TerrainRenderParams>,
+    level: Single<(&Terrain, &TerrainData), With<LevelLabel>>,
+    terrain_meshes: Query<(Entity, &TerrainMesh, &Visibility, Option<&OverlayLines>, Option<&Children>)>,
+    line_meshes: Query<(), With<OverlayLinesMesh>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut commands: Commands,
+) {
+    let (terrain, terrain_data) = *level;
+    let Some(elevation) = terrain_data.layers.get(&TerrainLayer::Elevation)
+    else { return; };
+
+    let wanted = OverlayLines {
+        contour_interval: overlays.contours.then_some(overlays.contour_interval),
+        grid_spacing: overlays.grid.then_some(overlays.grid_spacing),
+    };
+
+    let mut outdated: Vec<_> = terrain_meshes.iter()
+        .filter(|(_, tm, _, lines, _)| tm.layer == TerrainLayer::Elevation && *lines.unwrap_or(&OverlayLines::NONE) != wanted)
+        .collect();
+    if outdated.is_empty() { return; }
+
+    outdated.sort_by_key(|(_, _, vis, _, _)| **vis == Visibility::Hidden);
+
+    for (entity, terrain_mesh, _, _, children) in outdated.into_iter().take(LINE_BLOCKS_PER_FRAME) {
+        for child in children.into_iter().flatten() {
+            if line_meshes.contains(*child) {
+                commands.entity(*child).despawn();
+            }
+        }
+        commands.entity(entity).insert(wanted.clone());
+
+        let block_id = terrain_mesh.block_id;
+        let range = block_range(terrain.block_size, block_id);
+        let (_, spacing) = block_quality(block_id);
+        let spacing = spacing as usize;
+        let origin = Vec2::new(range.1.start as f32, range.0.start as f32);
+        let data = elevation.slice(range.0, range.1, spacing);
+
+        let mut lines = Vec::new();
+        if let Some(interval) = wanted.contour_interval {
+            for (from, to, level) in contour_segments(data.view(), spacing as f32, interval) {
+                let major = (level / interval).round() as i64 % overlays.major_contour_every.max(1) as i64 == 0;
+                lines.push((from, to, if major { MAJOR_CONTOUR_COLOUR } else { CONTOUR_COLOUR }));
+            }
+        }
+        if let Some(grid_spacing) = wanted.grid_spacing {
+            for (from, to) in grid_segments(data.view(), spacing as f32, origin, terrain, grid_spacing) {
+                lines.push((from, to, GRID_COLOUR));
+            }
+        }
+
+        if lines.is_empty() { continue; }
+
+        commands.spawn((
+            OverlayLinesMesh,
+            Mesh3d(meshes.add(create_line_mesh(&lines))),
+            MeshMaterial3d(params.line_material.clone()),
+            Transform::default(),
+            ChildOf(entity),
+        ));
+    }
+}
+
+fn create_line_mesh(lines: &[(Vec3, Vec3, Color)]) -> Mesh {
+    let lift = Vec3::Y * LINE_LIFT;
+    let positions: Vec<_> = lines.iter().flat_map(|(from, to, _)| [*from + lift, *to + lift]).collect();
+    let colours: Vec<_> = lines.iter().flat_map(|(_, _, colour)| [colour.to_linear().to_f32_array(); 2]).collect();
+
+    Mesh::new(PrimitiveTopology::LineList, RenderAssetUsages::default())
+        .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
+        .with_inserted_attribute(Mesh::ATTRIBUTE_COLOR, colours)
+}
+
+/**
+ * Contour lines across a grid of heights, found by marching squares.  Returns each piece
+ * of line with the height it is at, in the grid's space with points `spacing` apart.
+ *
+ * A point exactly at a contour's height counts as below it, so a contour along the
+ * shared edge of two blocks is only found in one of them.
+ */
+pub fn contour_segments(data: ArrayView2<f32>, spacing: f32, interval: f32) -> Vec<(Vec3, Vec3, f32)> {
+    let (rows, cols) = data.dim();
+    let mut segments = Vec::new();
+    if rows < 2 || cols < 2 { return segments; }
+
+    for r in 0..rows - 1 {
+        for c in 0..cols - 1 {
+            /* Corners and edges go clockwise from the top left */
+            let corners = [(r, c), (r, c + 1), (r + 1, c + 1), (r + 1, c)];
+            let heights = corners.map(|p| data[p]);
+            let low = heights.iter().copied().fold(f32::INFINITY, f32::min);
+            let high = heights.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+
+            for k in (low / interval).floor() as i64..=(high / interval).floor() as i64 {
+                let level = k as f32 * interval;
+                let above = heights.map(|h| h > level);
+
+                let crossings: Vec<_> = (0..4)
+                    .filter(|i| above[*i] != above[(i + 1) % 4])
+                    .map(|i| {
+                        let (a, b) = (i, (i + 1) % 4);
+                        let t = (level - heights[a]) / (heights[b] - heights[a]);
+                        let (ra, ca) = corners[a];
+                        let (rb, cb) = corners[b];
+                        let row = ra as f32 + (rb as f32 - ra as f32) * t;
+                        let col = ca as f32 + (cb as f32 - ca as f32) * t;
+                        (i, Vec3::new(col * spacing, level, row * spacing))
+                    })
+                    .collect();
+
+                match crossings.as_slice() {
+                    [(_, p), (_, q)] => segments.push((*p, *q, level)),
+                    [e0, e1, e2, e3] => {
+                        /* A saddle: the centre decides which pairs of corners are joined */
+                        let centre_above = heights.iter().sum::<f32>() / 4.0 > level;
+                        let pairs = if centre_above == above[0] { [(e0, e1), (e2, e3)] } else { [(e3, e0), (e1, e2)] };
+                        for ((_, p), (_, q)) in pairs {
+                            segments.push((*p, *q, level));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    segments
+}
+
+/**
+ * Lines of constant easting and northing across a grid of heights, following the
+ * surface.  The grid's top left point is at `origin` in world space.
+ */
+fn grid_segments(data: ArrayView2<f32>, spacing: f32, origin: Vec2, terrain: &Terrain, grid_spacing: f32) -> Vec<(Vec3, Vec3)> {
+    let (rows, cols) = data.dim();
+    let mut segments = Vec::new();
+    if rows < 2 || cols < 2 { return segments; }
+
+    let width = (cols - 1) as f32 * spacing;
+    let height = (rows - 1) as f32 * spacing;
+
+    /* Height anywhere in the grid, between its points */
+    let height_at = |x: f32, z: f32| {
+        let (c, r) = (x / spacing, z / spacing);
+        let (c0, r0) = (c.floor().min((cols - 1) as f32), r.floor().min((rows - 1) as f32));
+        let (c1, r1) = ((c0 as usize + 1).min(cols - 1), (r0 as usize + 1).min(rows - 1));
+        let (c0, r0, tc, tr) = (c0 as usize, r0 as usize, c - c0, r - r0);
+        let top = data[(r0, c0)] * (1.0 - tc) + data[(r0, c1)] * tc;
+        let bottom = data[(r1, c0)] * (1.0 - tc) + data[(r1, c1)] * tc;
+        top * (1.0 - tr) + bottom * tr
+    };
+
+    /* Eastings increase along x; northings decrease along z */
+    let first_easting = ((terrain.bounds.min.x + origin.x) / grid_spacing).ceil() * grid_spacing;
+    let mut x = first_easting - terrain.bounds.min.x - origin.x;
+    while x <= width {
+        for r in 0..rows - 1 {
+            let (z0, z1) = (r as f32 * spacing, (r + 1) as f32 * spacing);
+            segments.push((Vec3::new(x, height_at(x, z0), z0), Vec3::new(x, height_at(x, z1), z1)));
+        }
+        x += grid_spacing;
+    }
+
+    let top_northing = terrain.bounds.min.y + terrain.size[0] as f32 - origin.y;
+    let mut z = top_northing - (top_northing / grid_spacing).floor() * grid_spacing;
+    while z <= height {
+        for c in 0..cols - 1 {
+            let (x0, x1) = (c as f32 * spacing, (c + 1) as f32 * spacing);
+            segments.push((Vec3::new(x0, height_at(x0, z), z), Vec3::new(x1, height_at(x1, z), z)));
+        }
+        z += grid_spacing;
+    }
+
+    segments
+}
+
+#[cfg(test)]
+mod test {
+    use ndarray::Array2;
+
+    use super::*;
+
+    #[test]
+    fn test_contour_segments() {
+        /* A slope rising 1m per column has a straight contour down each column */
+        let data = Array2::from_shape_fn((5, 5), |(_, c)| c as f32);
+        let segments = contour_segments(data.view(), 2.0, 2.0);
+        assert_eq!(segments.len(), 2 * 4);
+        for (from, to, level) in &segments {
+            assert!(level == &0.0 || level == &2.0);
+            assert_eq!(from.x, level * 2.0);
+            assert_eq!(to.x, level * 2.0);
+            assert_eq!((from.z - to.z).abs(), 2.0);
+        }
+
+        /* A peak in the middle is surrounded by closed loops, closer in as they go up */
+        let mut data = Array2::from_elem((3, 3), 0.5);
+        data[(1, 1)] = 2.5;
+        let segments = contour_segments(data.view(), 1.0, 1.0);
+        assert_eq!(segments.len(), 8);
+        for (from, to, level) in &segments {
+            let distance = if *level == 1.0 { 0.75 } else { 0.25 };
+            assert_eq!(from.xz().distance(Vec2::ONE), distance);
+            assert_eq!(to.xz().distance(Vec2::ONE), distance);
+        }
+    }
+
+    #[test]
+    fn test_slope_uv() {
+        assert_eq!(slope_uv(Vec3::Y), Vec2::new(0.125, 0.5));
+        assert_eq!(slope_uv(Vec3::new(0.03, 1.0, 0.0)), Vec2::new(0.375, 0.5));
+        assert_eq!(slope_uv(Vec3::X), Vec2::new(0.875, 0.5));
+    }
+}
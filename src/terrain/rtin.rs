@@ -4,6 +4,8 @@ pub struct Triangle {
 
 pub struct Triangulation {
     pub triangles: Vec<Triangle>,
+    /** Largest vertical distance between the triangles and the points they cover */
+    pub error: f32,
 }
 
 /**
@@ -29,33 +31,78 @@ pub fn triangulate_basic(points: &ndarray::ArrayView2<f32>) -> Triangulation {
         }
     }
 
-    Triangulation { triangles }
+    Triangulation { triangles, error: 0.0 }
 }
 
 /**
- * Use the "MARTINI" algorithm to triangulate a square array of height data.
+ * Use the "MARTINI" algorithm to triangulate an array of height data.
+ *
+ * Unlike the original, which only measures each triangle's error at the middle of its long
+ * edge, the error is taken over every point a triangle covers, so the reported error is a
+ * true bound on the distance between the mesh and the data.
+ *
+ * The algorithm works on a square grid of (2^n + 1) points.  Other sizes of data are
+ * padded out to the next such grid, and any triangle that crosses the edge of the real
+ * data is always split, so that only triangles within it are kept.  Triangles touching a
+ * point of the optional hole mask are always split too, so that the triangles left around
+ * a hole are as small as possible.
  *
  * Transcribed to Rust from https://observablehq.com/@mourner/martin-real-time-rtin-terrain-mesh
  * internal comments are from the article's code samples
  */
 pub fn triangulate_rtin(
     points: &ndarray::ArrayView2<f32>,
-    threshold: f32
+    threshold: f32,
+    holes: Option<&ndarray::ArrayView2<bool>>,
 ) -> Triangulation {
-    let errors = build_error_map(points);
-    let triangles = build_rtin_mesh(points, threshold, &errors.view());
-    Triangulation { triangles }
+    let (rows, cols) = points.dim();
+    if rows < 2 || cols < 2 {
+        return Triangulation { triangles: Vec::new(), error: 0.0 };
+    }
+
+    let grid_size = (rows.max(cols) - 1).next_power_of_two() + 1;
+    let padded;
+    let grid = if rows == grid_size && cols == grid_size {
+        points.view()
+    } else {
+        padded = ndarray::Array2::from_shape_fn((grid_size, grid_size), |(r, c)| {
+            points[[r.min(rows - 1), c.min(cols - 1)]]
+        });
+        padded.view()
+    };
+
+    let errors = build_error_map(&grid, (rows, cols), holes);
+    let (triangles, error) = build_rtin_mesh(&grid, (rows, cols), threshold, &errors.view());
+    Triangulation { triangles, error }
 }
 
-fn build_error_map(points: &ndarray::ArrayView2<f32>) -> ndarray::Array2<f32> {
+/**
+ * Whether a triangle covers some of the data but not all of its points are within it.
+ * Points are (x, y), i.e. (column, row).
+ */
+fn crosses_edge(points: [(usize, usize); 3], (rows, cols): (usize, usize)) -> bool {
+    let outside = points.iter().any(|(x, y)| *x >= cols || *y >= rows);
+    let min_x = points.iter().map(|(x, _)| *x).min().unwrap_or(0);
+    let min_y = points.iter().map(|(_, y)| *y).min().unwrap_or(0);
+    outside && min_x + 1 < cols && min_y + 1 < rows
+}
+
+fn build_error_map(
+    points: &ndarray::ArrayView2<f32>,
+    dims: (usize, usize),
+    holes: Option<&ndarray::ArrayView2<bool>>,
+) -> ndarray::Array2<f32> {
     let grid_size = points.shape()[0];
     let tile_size = grid_size - 1;
+    if tile_size < 2 {
+        return ndarray::Array2::zeros(points.dim());
+    }
 
     let num_smallest = tile_size * tile_size;
     let num_triangles = num_smallest * 2 - 2;
     let last_level_index = num_triangles - num_smallest;
 
-    let mut errors = ndarray::Array2::zeros(points.dim());
+    let mut errors = ndarray::Array2::<f32>::zeros(points.dim());
 
     // iterate over all possible triangles, starting from the smallest level
     for i in (0..num_triangles).rev() {
@@ -86,13 +133,18 @@ fn build_error_map(points: &ndarray::ArrayView2<f32>) -> ndarray::Array2<f32> {
             cx = mx; cy = my;
         }
 
-        let interpolated_height = (points[[ay, ax]] + points[[by, bx]]) / 2.0;
         let mx = (ax + bx) >> 1;
         let my = (ay + by) >> 1;
-        let middle_error = (interpolated_height - points[[my, mx]]).abs();
+        let middle_error = triangle_error(points, [(ax, ay), (bx, by), (cx, cy)]);
 
         if i >= last_level_index { // smallest triangles
-            errors[[my, mx]] = middle_error;
+            errors[[my, mx]] = errors[[my, mx]].max(middle_error);
+
+            // triangles touching a hole must be split, and so must all their ancestors
+            let is_hole = |x: usize, y: usize| holes.and_then(|h| h.get((y, x)).copied()).unwrap_or(false);
+            if is_hole(ax, ay) || is_hole(bx, by) || is_hole(cx, cy) || is_hole(mx, my) {
+                errors[[my, mx]] = f32::INFINITY;
+            }
         } else { // bigger triangles; accumulate error with children
             let left_child_error = errors[[(ay + cy) >> 1, (ax + cx) >> 1]];
             let right_child_error = errors[[(by + cy) >> 1, (bx + cx) >> 1]];
@@ -103,36 +155,84 @@ fn build_error_map(points: &ndarray::ArrayView2<f32>) -> ndarray::Array2<f32> {
                 }
             }
         }
+
+        // triangles crossing the edge of the real data must be split
+        if crosses_edge([(ax, ay), (bx, by), (cx, cy)], dims) {
+            errors[[my, mx]] = f32::INFINITY;
+        }
     }
 
     errors
 }
 
+/**
+ * Largest vertical distance between the plane through a triangle's corners and the points
+ * it covers.  Points are (x, y), i.e. (column, row).
+ */
+fn triangle_error(points: &ndarray::ArrayView2<f32>, corners: [(usize, usize); 3]) -> f32 {
+    let [a, b, c] = corners.map(|(x, y)| (x as isize, y as isize));
+    let cross = |p: (isize, isize), q: (isize, isize), o: (isize, isize)| (q.0 - p.0) * (o.1 - p.1) - (q.1 - p.1) * (o.0 - p.0);
+    let area = cross(a, b, c) as f32;
+    let height = |(x, y): (isize, isize)| points[[y as usize, x as usize]];
+
+    let (min_x, max_x) = (a.0.min(b.0).min(c.0), a.0.max(b.0).max(c.0));
+    let (min_y, max_y) = (a.1.min(b.1).min(c.1), a.1.max(b.1).max(c.1));
+
+    let mut error = 0.0f32;
+    for y in min_y..=max_y {
+        for x in min_x..=max_x {
+            let o = (x, y);
+            let weights = [cross(b, c, o), cross(c, a, o), cross(a, b, o)];
+            if weights.iter().any(|w| (*w as f32) * area < 0.0) { continue; }
+
+            let plane = (weights[0] as f32 * height(a) + weights[1] as f32 * height(b) + weights[2] as f32 * height(c)) / area;
+            error = error.max((plane - height(o)).abs());
+        }
+    }
+
+    error
+}
+
 fn build_rtin_mesh(
     points: &ndarray::ArrayView2<f32>,
+    dims: (usize, usize),
     threshold: f32,
     errors: &ndarray::ArrayView2<f32>
-) -> Vec<Triangle> {
+) -> (Vec<Triangle>, f32) {
     let grid_size = points.shape()[0];
     let tile_size = grid_size - 1;
 
     let mut triangles = Vec::new();
+    let mut max_error = 0.0f32;
 
     fn process_triangle(
         ax: usize, ay: usize, bx: usize, by: usize, cx: usize, cy: usize,
+        dims: (usize, usize),
         threshold: f32,
         errors: &ndarray::ArrayView2<f32>,
         triangles: &mut Vec<Triangle>,
+        max_error: &mut f32,
     ) {
         // middle of the long edge
         let mx = (ax + bx) >> 1;
         let my = (ay + by) >> 1;
 
-        if ax.abs_diff(cx) + ay.abs_diff(cy) > 1 && errors[[my, mx]] > threshold {
+        let splittable = ax.abs_diff(cx) + ay.abs_diff(cy) > 1;
+        if splittable && errors[[my, mx]] > threshold {
             // triangle doesn't approximate the surface well enough; split it into two
-            process_triangle(cx, cy, ax, ay, mx, my, threshold, errors, triangles);
-            process_triangle(bx, by, cx, cy, mx, my, threshold, errors, triangles);
+            process_triangle(cx, cy, ax, ay, mx, my, dims, threshold, errors, triangles, max_error);
+            process_triangle(bx, by, cx, cy, mx, my, dims, threshold, errors, triangles, max_error);
         } else {
+            // triangles outside the real data are only padding
+            let (rows, cols) = dims;
+            if [ax, bx, cx].iter().any(|x| *x >= cols) || [ay, by, cy].iter().any(|y| *y >= rows) {
+                return;
+            }
+
+            if splittable {
+                *max_error = max_error.max(errors[[my, mx]]);
+            }
+
             // add a triangle to the final mesh
             // let v1 = Vec3::new(ay as f32, points[[ay, ax]], ax as f32);
             // let v2 = Vec3::new(by as f32, points[[by, bx]], bx as f32);
@@ -149,16 +249,20 @@ fn process_triangle(
 
     process_triangle(
         0, 0, tile_size, tile_size, tile_size, 0,
+        dims,
         threshold,
         errors,
-        &mut triangles);
+        &mut triangles,
+        &mut max_error);
     process_triangle(
         tile_size, tile_size, 0, 0, 0, tile_size,
+        dims,
         threshold,
         errors,
-        &mut triangles);
+        &mut triangles,
+        &mut max_error);
 
-    triangles
+    (triangles, max_error)
 }
 
 #[cfg(test)]
@@ -169,13 +273,187 @@ mod test {
     fn trivial() {
         let points = ndarray::arr2(&[[1.0, 2.0], [1.0, 3.0]]);
 
-        let Triangulation { triangles } = triangulate_basic(&points.view());
+        let Triangulation { triangles, .. } = triangulate_basic(&points.view());
         assert_eq!(2, triangles.len());
     }
 
     #[test]
     fn build_error_map1() {
         let points = ndarray::Array2::zeros([3, 3]);
-        let errors = build_error_map(&points.view());
+        let errors = build_error_map(&points.view(), (3, 3), None);
+        assert!(errors.iter().all(|e| *e == 0.0));
+
+        let mut points = ndarray::Array2::zeros([3, 3]);
+        points[[1, 1]] = 1.0;
+        let errors = build_error_map(&points.view(), (3, 3), None);
+        assert_eq!(errors[[1, 1]], 1.0);
+    }
+
+    /** Bumpy but repeatable test data */
+    fn test_points(rows: usize, cols: usize) -> ndarray::Array2<f32> {
+        ndarray::Array2::from_shape_fn((rows, cols), |(r, c)| {
+            ((r * 7919 + c * 104729) % 97) as f32 / 10.0 + (r as f32 * 0.3).sin() * 5.0
+        })
+    }
+
+    /**
+     * Collect every triangle in the hierarchy, by depth, using plain recursion rather than
+     * the implicit binary tree indices.  Points are (x, y).
+     */
+    fn collect_triangles(
+        [a, b, c]: [(usize, usize); 3],
+        depth: usize,
+        by_depth: &mut Vec<Vec<[(usize, usize); 3]>>,
+    ) {
+        if a.0.abs_diff(c.0) + a.1.abs_diff(c.1) <= 1 { return; }
+
+        if by_depth.len() <= depth { by_depth.push(Vec::new()); }
+        by_depth[depth].push([a, b, c]);
+
+        let m = ((a.0 + b.0) / 2, (a.1 + b.1) / 2);
+        collect_triangles([c, a, m], depth + 1, by_depth);
+        collect_triangles([b, c, m], depth + 1, by_depth);
+    }
+
+    /**
+     * The largest vertical distance between the plane through a triangle's corners and the
+     * heights at all the grid points it covers, straight from the definition.
+     */
+    fn brute_force_error(points: &ndarray::Array2<f32>, [a, b, c]: [(usize, usize); 3]) -> f32 {
+        let [a, b, c] = [a, b, c].map(|(x, y)| (x as f64, y as f64));
+        let height = |(x, y): (f64, f64)| points[[y as usize, x as usize]] as f64;
+        let cross = |p: (f64, f64), q: (f64, f64), o: (f64, f64)| (q.0 - p.0) * (o.1 - p.1) - (q.1 - p.1) * (o.0 - p.0);
+        let area = cross(a, b, c);
+
+        let mut error = 0.0f64;
+        for ((y, x), h) in points.indexed_iter() {
+            let o = (x as f64, y as f64);
+            let (wa, wb, wc) = (cross(b, c, o) / area, cross(c, a, o) / area, cross(a, b, o) / area);
+            if wa < 0.0 || wb < 0.0 || wc < 0.0 { continue; }
+
+            let plane = wa * height(a) + wb * height(b) + wc * height(c);
+            error = error.max((plane - *h as f64).abs());
+        }
+
+        error as f32
+    }
+
+    /** Pad data out to a (2^n + 1) square grid, as `triangulate_rtin` does */
+    fn padded(points: &ndarray::Array2<f32>) -> ndarray::Array2<f32> {
+        let (rows, cols) = points.dim();
+        let grid_size = (rows.max(cols) - 1).next_power_of_two() + 1;
+        ndarray::Array2::from_shape_fn((grid_size, grid_size), |(r, c)| points[[r.min(rows - 1), c.min(cols - 1)]])
+    }
+
+    #[test]
+    fn error_map_bounds_brute_force() {
+        for (rows, cols) in [(3, 3), (5, 5), (9, 9), (17, 17), (12, 17), (17, 6), (10, 3)] {
+            let grid = padded(&test_points(rows, cols));
+            let errors = build_error_map(&grid.view(), (rows, cols), None);
+
+            let t = grid.dim().0 - 1;
+            let mut by_depth = Vec::new();
+            collect_triangles([(0, 0), (t, t), (t, 0)], 0, &mut by_depth);
+            collect_triangles([(t, t), (0, 0), (0, t)], 0, &mut by_depth);
+
+            for (depth, triangles) in by_depth.iter().enumerate() {
+                for [a, b, c] in triangles {
+                    let (mx, my) = ((a.0 + b.0) / 2, (a.1 + b.1) / 2);
+                    let expected = brute_force_error(&grid, [*a, *b, *c]);
+                    assert!(
+                        errors[[my, mx]] >= expected - 1e-4,
+                        "{}x{} depth {} triangle {:?}: {} < {}", rows, cols, depth, [a, b, c], errors[[my, mx]], expected
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn triangle_count_monotonic() {
+        for (rows, cols) in [(17, 17), (12, 17), (17, 6), (10, 10)] {
+            let points = test_points(rows, cols);
+            let mut last_count = usize::MAX;
+            for threshold in [0.0, 0.1, 0.5, 1.0, 2.0, 5.0, 10.0, 100.0] {
+                let Triangulation { triangles, error } = triangulate_rtin(&points.view(), threshold, None);
+                assert!(triangles.len() <= last_count, "{}x{} at {}", rows, cols, threshold);
+                assert!(error <= threshold);
+                last_count = triangles.len();
+            }
+        }
+    }
+
+    /**
+     * The triangles should exactly cover the data, with each edge either on its border
+     * or shared by exactly two triangles, so there are no cracks.
+     */
+    fn assert_watertight(rows: usize, cols: usize, triangles: &[Triangle]) {
+        let mut edges = std::collections::HashMap::new();
+        let mut area = 0;
+        for Triangle { points } in triangles {
+            for p in points {
+                assert!(p[0] < rows && p[1] < cols, "{:?} is outside the data", p);
+            }
+            for i in 0..3 {
+                let (p, q) = (points[i], points[(i + 1) % 3]);
+                *edges.entry(if p < q { (p, q) } else { (q, p) }).or_insert(0) += 1;
+            }
+            let [a, b, c] = points.map(|p| [p[0] as isize, p[1] as isize]);
+            area += ((b[0] - a[0]) * (c[1] - a[1]) - (b[1] - a[1]) * (c[0] - a[0])).abs();
+        }
+
+        assert_eq!(area as usize, 2 * (rows - 1) * (cols - 1), "triangles cover the data");
+
+        let on_border = |p: [usize; 2], q: [usize; 2]| {
+            (p[0] == q[0] && (p[0] == 0 || p[0] == rows - 1)) || (p[1] == q[1] && (p[1] == 0 || p[1] == cols - 1))
+        };
+        for ((p, q), count) in edges {
+            if on_border(p, q) {
+                assert_eq!(count, 1, "border edge {:?}-{:?}", p, q);
+            } else {
+                assert_eq!(count, 2, "inner edge {:?}-{:?}", p, q);
+            }
+        }
+    }
+
+    #[test]
+    fn watertight() {
+        for (rows, cols) in [(2, 2), (5, 5), (17, 17), (12, 17), (17, 6), (10, 3), (33, 20)] {
+            let points = test_points(rows, cols);
+            for threshold in [0.0, 0.5, 2.0, 100.0] {
+                let Triangulation { triangles, .. } = triangulate_rtin(&points.view(), threshold, None);
+                assert_watertight(rows, cols, &triangles);
+            }
+        }
+    }
+
+    #[test]
+    fn refined_around_holes() {
+        for (rows, cols) in [(17, 17), (12, 17)] {
+            let points = ndarray::Array2::<f32>::zeros((rows, cols));
+            let mut holes = ndarray::Array2::from_elem((rows, cols), false);
+            holes[[5, 7]] = true;
+            holes[[11, 16]] = true;
+
+            let Triangulation { triangles, .. } = triangulate_rtin(&points.view(), 100.0, Some(&holes.view()));
+            assert_watertight(rows, cols, &triangles);
+            for Triangle { points } in &triangles {
+                let [a, b, c] = points.map(|p| [p[0] as isize, p[1] as isize]);
+                let side = |p: [isize; 2], q: [isize; 2], o: [isize; 2]| {
+                    ((q[0] - p[0]) * (o[1] - p[1]) - (q[1] - p[1]) * (o[0] - p[0])).signum()
+                };
+                let covers = |o: [isize; 2]| {
+                    let sides = [side(a, b, o), side(b, c, o), side(c, a, o)];
+                    !(sides.contains(&1) && sides.contains(&-1))
+                };
+                if covers([5, 7]) || covers([11, 16]) {
+                    assert!(points.iter().all(|p| p[0].abs_diff(points[0][0]) <= 1 && p[1].abs_diff(points[0][1]) <= 1), "{:?} is too big", points);
+                }
+            }
+
+            /* Without the holes, flat data needs only a few triangles */
+            let Triangulation { triangles: plain, .. } = triangulate_rtin(&points.view(), 100.0, None);
+            assert!(plain.len() < triangles.len());
+        }
     }
 }
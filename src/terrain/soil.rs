@@ -0,0 +1,103 @@
+use bevy::prelude::Reflect;
+use ndarray::{s, Array2};
+use serde::{Deserialize, Serialize};
+
+use crate::terrain::utils::Range2;
+
+/**
+ * Properties of the material the terrain is made of.
+ *
+ * Slopes are given as the maximum rise over run that the material will hold.  Cut
+ * faces are those exposed by digging material away; fill faces are those formed by
+ * material that has been piled up.  Compacted earth usually holds a steeper cut face
+ * than the loose spoil that is dumped beside it.
+ */
+#[derive(Clone, Debug, Reflect, Serialize, Deserialize)]
+pub struct Soil {
+    pub name: String,
+    pub cut_slope: f32,
+    pub fill_slope: f32,
+}
+
+impl Default for Soil {
+    fn default() -> Self {
+        Soil {
+            name: "Default".to_owned(),
+            cut_slope: 1.0,
+            fill_slope: 1.0,
+        }
+    }
+}
+
+/**
+ * Soil type for each point of the terrain, as an index into a list of soils.
+ *
+ * The first soil in the list is the default for the level.
+ */
+#[derive(Debug, Default)]
+pub struct SoilMap {
+    pub soils: Vec<Soil>,
+    pub cells: Array2<u8>,
+}
+
+impl SoilMap {
+    pub fn new(dims: [usize; 2], soils: &[Soil]) -> Self {
+        let mut soils = soils.to_vec();
+        if soils.is_empty() {
+            soils.push(Soil::default());
+        }
+
+        SoilMap {
+            soils,
+            cells: Array2::zeros(dims),
+        }
+    }
+
+    pub fn soil_at(&self, row: usize, col: usize) -> &Soil {
+        let index = self.cells.get((row, col)).copied().unwrap_or_default();
+        self.soils.get(index as usize).unwrap_or(&self.soils[0])
+    }
+
+    pub fn cut_slope(&self, row: usize, col: usize) -> f32 {
+        self.soil_at(row, col).cut_slope
+    }
+
+    pub fn fill_slope(&self, row: usize, col: usize) -> f32 {
+        self.soil_at(row, col).fill_slope
+    }
+
+    pub fn set_soil(&mut self, range: Range2, soil: u8) {
+        let (rows, cols) = self.cells.dim();
+        let rows = range.0.start.min(rows)..range.0.end.min(rows);
+        let cols = range.1.start.min(cols)..range.1.end.min(cols);
+        self.cells.slice_mut(s!(rows, cols)).fill(soil);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_soil_at() {
+        let soils = [
+            Soil::default(),
+            Soil { name: "Rock".to_owned(), cut_slope: 4.0, fill_slope: 0.75 },
+        ];
+        let mut map = SoilMap::new([10, 10], &soils);
+        map.set_soil(Range2(2..4, 5..20), 1);
+
+        assert_eq!(map.cut_slope(0, 0), 1.0);
+        assert_eq!(map.cut_slope(3, 9), 4.0);
+        assert_eq!(map.fill_slope(2, 5), 0.75);
+        assert_eq!(map.fill_slope(4, 5), 1.0);
+        assert_eq!(map.cut_slope(100, 100), 1.0);
+    }
+
+    #[test]
+    fn test_empty_soils() {
+        let map = SoilMap::new([2, 2], &[]);
+        assert_eq!(map.soils.len(), 1);
+        assert_eq!(map.cut_slope(1, 1), 1.0);
+    }
+}
@@ -73,7 +73,7 @@ pub enum ElevationFileLoaderError {
 
 #[derive(Asset, Debug, TypePath)]
 pub struct ElevationFile {
-    pub(crate) heights: ndarray::Array2<f32>,
+    pub heights: ndarray::Array2<f32>,
 }
 
 #[derive(Default)]
@@ -1,7 +1,11 @@
 use bevy::asset::AssetServer;
 use bevy::prelude::*;
+use crate::screens;
 use crate::screens::Screen;
-use crate::terrain;
+use crate::track::editing::TrackEditing;
+use crate::track::laying::TrackLaying;
+use crate::{terrain, track};
+use crate::terrain::rendering::overlays::TerrainOverlays;
 use crate::ui::toolbar;
 use crate::ui::toolbar::{Toolbar, ToolbarButton, ToolbarLine, ToolbarPlugin};
 
@@ -18,10 +22,26 @@ fn build(&self, app: &mut App) {
             .add_systems(Update, update_tool_buttons)
             .add_systems(Update, update_terraform_tool_buttons)
             .add_systems(Update, update_track_tool_buttons)
+            .add_systems(Update, update_overlay_buttons)
             .add_systems(Update, (
                 terrain::edit::click_point.run_if(in_state(TerraformTool::Height)),
-                terrain::edit::drag_point.run_if(in_state(TerraformTool::Level))
-            ).run_if(in_state(Tool::Terraform)));
+                terrain::edit::drag_point.run_if(in_state(TerraformTool::Level)),
+                terrain::erosion::erode_region.run_if(in_state(TerraformTool::Erode)),
+            ).run_if(in_state(Tool::Terraform)))
+            .add_systems(Update, track::switch::click_switch.run_if(in_state(Tool::Select)))
+            .init_resource::<TrackLaying>()
+            .add_systems(Update, track::laying::lay_track
+                .run_if(in_state(Tool::Track).and(in_state(TrackTool::Create)))
+                .before(screens::exit_level))
+            .add_systems(OnExit(Tool::Track), track::laying::cancel_laying)
+            .add_systems(OnExit(TrackTool::Create), track::laying::cancel_laying)
+            .init_resource::<TrackEditing>()
+            .add_systems(Update, track::editing::edit_track
+                .run_if(in_state(Tool::Track).and(in_state(TrackTool::Edit)))
+                .before(track::point::move_points))
+            .add_systems(OnExit(Tool::Track), track::editing::stop_editing)
+            .add_systems(Update, track::validation::show_violations.run_if(in_state(Tool::Track)))
+            .add_systems(OnExit(TrackTool::Edit), track::editing::stop_editing);
     }
 }
 
@@ -57,6 +77,7 @@ enum TerraformTool {
     Level,
     Flatten,
     Smooth,
+    Erode,
 }
 
 #[derive(Clone, Component, Copy, Debug, Default, Eq, Hash, PartialEq, States)]
@@ -66,6 +87,17 @@ enum TrackTool {
     Edit,
 }
 
+/**
+ * Buttons for overlays on the terrain, which are toggled on and off independently of
+ * the current tool.
+ */
+#[derive(Clone, Component, Copy, Debug, Eq, PartialEq)]
+enum OverlayToggle {
+    Contours,
+    Slope,
+    Grid,
+}
+
 pub(crate) fn create_tools(
     asset_server: Res<AssetServer>,
     mut commands: Commands,
@@ -111,6 +143,7 @@ pub fn create_terraform_tools(
         (TerraformTool::Level, "Level", true),
         (TerraformTool::Flatten, "Flatten", false),
         (TerraformTool::Smooth, "Smooth", false),
+        (TerraformTool::Erode, "Erode", true),
     ] {
         toolbar::create_button(&mut commands, toolbar_line_id, enabled)
             .insert(tool)
@@ -148,6 +181,29 @@ pub fn create_track_tools(
     tools.track_line_id = toolbar_line_id;
 }
 
+pub fn create_overlay_tools(
+    asset_server: Res<AssetServer>,
+    mut commands: Commands,
+    toolbar_id: Single<Entity, With<Toolbar>>,
+) {
+    let button_font = asset_server.load("fonts/FiraMono-Medium.ttf");
+
+    let toolbar_line_id = toolbar::create_line(&mut commands, *toolbar_id)
+        .id();
+
+    for (toggle, label) in [
+        (OverlayToggle::Contours, "Con-\ntours"),
+        (OverlayToggle::Slope, "Slope"),
+        (OverlayToggle::Grid, "Grid"),
+    ] {
+        toolbar::create_button(&mut commands, toolbar_line_id, true)
+            .insert(toggle)
+            .with_children(|p| {
+                p.spawn(toolbar::create_label(button_font.clone(), label));
+            });
+    }
+}
+
 fn update_tool_buttons(
     tools: ResMut<Tools>,
     query: Query<(&Tool, &Interaction), Changed<Interaction>>,
@@ -221,3 +277,21 @@ fn update_track_tool_buttons(
     state.set(tool);
     info!("TrackTool: {tool:?}");
 }
+
+fn update_overlay_buttons(
+    mut query: Query<(&OverlayToggle, &mut ToolbarButton, Ref<Interaction>), With<Button>>,
+    mut overlays: ResMut<TerrainOverlays>,
+) {
+    for (toggle, mut button, interaction) in query.iter_mut() {
+        if !interaction.is_changed() || *interaction != Interaction::Pressed { continue; }
+
+        let enabled = match toggle {
+            OverlayToggle::Contours => &mut overlays.contours,
+            OverlayToggle::Slope => &mut overlays.slope_tint,
+            OverlayToggle::Grid => &mut overlays.grid,
+        };
+        *enabled = !*enabled;
+        button.selected = *enabled;
+        info!("Overlay {toggle:?}: {enabled}");
+    }
+}
@@ -1,15 +1,19 @@
-use bevy::prelude::{ChildOf, Cuboid, Entity, Mesh3d, ReflectComponent, Res, ResMut};
-use bevy::prelude::ReflectResource;
+use bevy::prelude::{ChildOf, Children, Cuboid, Entity, Mesh3d, Name, ReflectComponent, Res, ResMut, Single, Vec2, Visibility, With};
+use bevy::prelude::{IntoScheduleConfigs, ReflectResource};
 use bevy::app::{App, Plugin, Startup, Update};
 use bevy::asset::{Assets, Handle};
 use bevy::color::Color;
-use bevy::log::info;
-use bevy::math::Vec3;
+use bevy::math::{Vec3, Vec3Swizzles};
 use bevy::pbr::{MeshMaterial3d, StandardMaterial};
-use bevy::prelude::{Changed, Commands, Component, Mesh, Or, Query, Resource, Transform};
+use bevy::prelude::{Changed, Commands, Component, DetectChanges, Mesh, Or, Query, Ref, Resource, Transform};
 use bevy::reflect::Reflect;
 
-use crate::track::segment::Segment;
+use crate::level::LevelLabel;
+use crate::terrain::TerrainData;
+use crate::track::curve::SegmentCurve;
+use crate::track::earthworks;
+use crate::track::rendering::{to_segment_space, CURVE_STEP};
+use crate::track::TerrainChanges;
 
 pub struct BridgePlugin;
 
@@ -17,10 +21,48 @@ impl Plugin for BridgePlugin {
     fn build(&self, app: &mut App) {
         app
             .register_type::<BridgeRenderParams>()
+            .register_type::<BridgeParams>()
             .register_type::<Bridge>()
             .init_resource::<BridgeRenderParams>()
+            .init_resource::<BridgeParams>()
             .add_systems(Startup, init_render_params)
-            .add_systems(Update, render_bridges);
+            .add_systems(Update, (
+                detect_bridges,
+                render_bridges,
+            ).chain().after(earthworks::update_earthworks));
+    }
+}
+
+/**
+ * Spacing of the checks for clearance along each segment.
+ */
+const SAMPLE_STEP: f32 = 1.0;
+
+/**
+ * Depth of the bridge deck under the track.
+ */
+const DECK_DEPTH: f32 = 1.0;
+
+/**
+ * Where a segment is bridged.  Track higher above the ground than the clearance is
+ * carried on a bridge, with pillars no further apart than the spacing.
+ */
+#[derive(Clone, Debug, Reflect, Resource)]
+#[reflect(Resource)]
+pub struct BridgeParams {
+    pub min_clearance: f32,
+    /** Shorter spans are left on the ground. */
+    pub min_span: f32,
+    pub pillar_spacing: f32,
+}
+
+impl Default for BridgeParams {
+    fn default() -> Self {
+        BridgeParams {
+            min_clearance: 3.0,
+            min_span: 4.0,
+            pillar_spacing: 20.0,
+        }
     }
 }
 
@@ -32,55 +74,238 @@ pub struct BridgeRenderParams {
     pillar_mesh: Handle<Mesh>,
 }
 
-#[derive(Component, Reflect)]
+/**
+ * A pillar standing under the deck, with its distance along the segment, and its height
+ * from the ground up to the deck.
+ */
+#[derive(Clone, Debug, PartialEq, Reflect)]
+pub struct Pillar {
+    pub distance: f32,
+    pub height: f32,
+}
+
+/**
+ * A stretch of a segment carried on a bridge, as distances along the segment.
+ */
+#[derive(Clone, Debug, PartialEq, Reflect)]
+pub struct BridgeSpan {
+    pub start: f32,
+    pub end: f32,
+    pub pillars: Vec<Pillar>,
+}
+
+/**
+ * Bridges along a segment, found from its height above the terrain.
+ */
+#[derive(Component, Default, Reflect)]
 #[reflect(Component)]
 pub struct Bridge {
-    pub(crate) pillars: usize,
+    pub spans: Vec<BridgeSpan>,
+    pub rendered_id: Option<Entity>,
 }
 
+const BRIDGE_COLOUR: Color = Color::srgb(0.2, 0.2, 0.2);
+
 fn init_render_params(
     mut params: ResMut<BridgeRenderParams>,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
 ) {
-    let bridge_material = StandardMaterial::from(Color::srgb(0.2, 0.2, 0.2));
-    params.bridge_material = materials.add(bridge_material);
+    params.bridge_material = materials.add(bridge_material());
+    params.bridge_mesh = meshes.add(create_deck_mesh());
+    params.pillar_mesh = meshes.add(create_pillar_mesh());
+}
 
-    let bridge_mesh: Mesh = Cuboid::from_size(Vec3::new(5.0, 1.0, 1.0)).into();
-    let bridge_mesh = bridge_mesh.translated_by(Vec3::new(0.0, -0.5, 0.5));
-    params.bridge_mesh = meshes.add(bridge_mesh);
+pub fn bridge_material() -> StandardMaterial {
+    StandardMaterial::from(BRIDGE_COLOUR)
+}
+
+/**
+ * A piece of deck one metre long, hanging below the track.
+ */
+pub fn create_deck_mesh() -> Mesh {
+    let deck_mesh: Mesh = Cuboid::from_size(Vec3::new(5.0, DECK_DEPTH, 1.0)).into();
+    deck_mesh.translated_by(Vec3::new(0.0, -DECK_DEPTH / 2.0, 0.5))
+}
 
+/**
+ * A pillar one metre high, hanging below its top.
+ */
+pub fn create_pillar_mesh() -> Mesh {
     let pillar_mesh: Mesh = Cuboid::from_size(Vec3::new(2.0, 1.0, 1.0)).into();
-    let pillar_mesh = pillar_mesh.translated_by(Vec3::new(0.0, -0.5, 0.0));
-    params.pillar_mesh = meshes.add(pillar_mesh);
+    pillar_mesh.translated_by(Vec3::new(0.0, -0.5, 0.0))
+}
+
+/**
+ * Transforms of the deck pieces and pillars of a span, for the deck and pillar meshes.
+ * Decks are laid in short straight pieces along the span, and pillars stand upright
+ * beneath them whatever the gradient.
+ */
+pub fn span_transforms(curve: &SegmentCurve, span: &BridgeSpan) -> (Vec<Transform>, Vec<Transform>) {
+    let path = curve.path_between(span.start, span.end, CURVE_STEP);
+    let decks = path.windows(2).map(|pair| {
+        let (from, to) = (pair[0].translation, pair[1].translation);
+        let mut deck = Transform::from_translation(from).looking_to(from - to, Vec3::Y);
+        deck.scale.z = from.distance(to);
+        deck
+    }).collect();
+
+    let pillars = span.pillars.iter().map(|pillar| {
+        let top = curve.position_at(pillar.distance) - Vec3::Y * DECK_DEPTH;
+        let direction = curve.direction_at(pillar.distance) * Vec3::new(1.0, 0.0, 1.0);
+        let mut pillar_pos = Transform::from_translation(top).looking_to(-direction, Vec3::Y);
+        pillar_pos.scale.y = pillar.height;
+        pillar_pos
+    }).collect();
+
+    (decks, pillars)
+}
+
+/**
+ * Find the stretches of a curve that are high enough above the ground to need a bridge,
+ * and place pillars evenly along each.  A pillar where a span reaches the end of the
+ * segment stands at the point there, and belongs to this segment rather than the next.
+ */
+pub fn find_bridge_spans(curve: &SegmentCurve, ground: impl Fn(Vec2) -> f32, params: &BridgeParams) -> Vec<BridgeSpan> {
+    let length = curve.length();
+    let clearance = |distance: f32| {
+        let pos = curve.position_at(distance);
+        pos.y - ground(pos.xz())
+    };
+
+    let steps = ((length / SAMPLE_STEP).ceil() as usize).max(1);
+    let mut ranges: Vec<(f32, f32)> = Vec::new();
+    let mut start = None;
+    for i in 0..=steps {
+        let distance = length * i as f32 / steps as f32;
+        let elevated = clearance(distance) > params.min_clearance;
+        match (elevated, start) {
+            (true, None) => start = Some(distance),
+            (false, Some(s)) => {
+                ranges.push((s, distance));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(s) = start {
+        ranges.push((s, length));
+    }
+
+    ranges.into_iter()
+        .map(|(start, end)| ((start - SAMPLE_STEP).max(0.0), end))
+        .filter(|(start, end)| end - start >= params.min_span)
+        .map(|(start, end)| {
+            let count = ((end - start) / params.pillar_spacing).ceil().max(1.0) as usize;
+            let last = if end >= length { count } else { count - 1 };
+            let pillars = (1..=last)
+                .map(|i| start + (end - start) * i as f32 / count as f32)
+                .map(|distance| Pillar { distance, height: clearance(distance) - DECK_DEPTH })
+                .filter(|pillar| pillar.height > 0.0)
+                .collect();
+            BridgeSpan { start, end, pillars }
+        })
+        .collect()
+}
+
+/**
+ * Bridge segments where they run high above the terrain, after any earthworks have been
+ * dug.  Segments are checked again when they move, or the terrain under them changes.
+ * Without a level, the ground is taken to be flat at zero.
+ */
+pub fn detect_bridges(
+    mut segments: Query<(Entity, Ref<SegmentCurve>, Option<&mut Bridge>)>,
+    terrain_data: Option<Single<&TerrainData, With<LevelLabel>>>,
+    terrain_changes: Res<TerrainChanges>,
+    params: Res<BridgeParams>,
+    mut commands: Commands,
+) {
+    let ground = |pos: Vec2| terrain_data.as_ref().map_or(0.0, |data| data.elevation_at(pos));
+
+    for (segment_id, curve, bridge) in segments.iter_mut() {
+        if !curve.is_changed() && !terrain_changes.under(&curve) { continue; }
+
+        let spans = find_bridge_spans(&curve, ground, &params);
+        match bridge {
+            Some(bridge) if spans.is_empty() => {
+                if let Some(rendered_id) = bridge.rendered_id {
+                    commands.entity(rendered_id).try_despawn();
+                }
+                commands.entity(segment_id).try_remove::<Bridge>();
+            }
+            Some(mut bridge) if bridge.spans != spans => {
+                bridge.spans = spans;
+            }
+            None if !spans.is_empty() => {
+                commands.entity(segment_id).insert(Bridge { spans, rendered_id: None });
+            }
+            _ => {}
+        }
+    }
 }
 
 fn render_bridges(
-    bridges: Query<(Entity, &Bridge, &Segment, &Transform), Or<(Changed<Bridge>, Changed<Segment>)>>,
+    mut bridges: Query<(Entity, &mut Bridge, &SegmentCurve, &Transform), Or<(Changed<Bridge>, Changed<SegmentCurve>)>>,
     params: Res<BridgeRenderParams>,
     mut commands: Commands,
 ) {
-    for (seg_id, bridge, seg, seg_transform) in bridges {
-        let mut bridge_pos = Transform::default();
-        bridge_pos.scale.z = seg.length;
-        commands.spawn((
-            Mesh3d(params.bridge_mesh.clone()),
-            MeshMaterial3d(params.bridge_material.clone()),
-            ChildOf(seg_id),
-            bridge_pos
-        ));
-
-        for i in 0..bridge.pillars {
-            let mut pillar_pos = Transform::default();
-            pillar_pos.translation = pillar_pos.rotation * Vec3::Z * (i as f32 * seg.length/(bridge.pillars - 1) as f32);
-            pillar_pos.scale.y = 10.0;
-
-            commands.spawn((
-                Mesh3d(params.pillar_mesh.clone()),
-                MeshMaterial3d(params.bridge_material.clone()),
+    for (seg_id, mut bridge, curve, seg_transform) in bridges.iter_mut() {
+        if let Some(rendered_id) = bridge.rendered_id {
+            commands.entity(rendered_id).despawn_related::<Children>();
+        } else {
+            bridge.rendered_id = Some(commands.spawn((
+                Name::new("Bridge"),
+                Transform::default(),
+                Visibility::default(),
                 ChildOf(seg_id),
-                pillar_pos
-            ));
+            )).id());
+        }
+        let parent_id = bridge.rendered_id.unwrap();
+
+        for span in &bridge.spans {
+            let (decks, pillars) = span_transforms(curve, span);
+            let pieces = decks.into_iter().map(|deck| (deck, &params.bridge_mesh))
+                .chain(pillars.into_iter().map(|pillar| (pillar, &params.pillar_mesh)));
+            for (transform, mesh) in pieces {
+                commands.spawn((
+                    Mesh3d(mesh.clone()),
+                    MeshMaterial3d(params.bridge_material.clone()),
+                    ChildOf(parent_id),
+                    to_segment_space(seg_transform, transform),
+                ));
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::track::curve::SegmentShape;
+
+    use super::*;
+
+    #[test]
+    fn test_find_bridge_spans() {
+        let params = BridgeParams::default();
+        let curve = SegmentCurve::new(Vec3::new(0.0, 10.0, 0.0), Vec3::new(100.0, 10.0, 0.0), SegmentShape::Straight);
+
+        /* A valley from 30 m to 70 m along, 10 m below the track at the bottom */
+        let valley = |pos: Vec2| if (30.0..=70.0).contains(&pos.x) { 0.0 } else { 10.0 };
+        let spans = find_bridge_spans(&curve, valley, &params);
+        assert_eq!(spans.len(), 1);
+        assert!((spans[0].start - 29.0).abs() < 0.1);
+        assert!((spans[0].end - 71.0).abs() < 0.1);
+        assert_eq!(spans[0].pillars.len(), 2);
+        assert!(spans[0].pillars.iter().all(|p| p.height == 10.0 - DECK_DEPTH));
+
+        /* Running off the end onto the next segment, with a pillar at the point */
+        let falling = |pos: Vec2| if pos.x > 50.0 { 0.0 } else { 10.0 };
+        let spans = find_bridge_spans(&curve, falling, &params);
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0].end, 100.0);
+        assert_eq!(spans[0].pillars.last().unwrap().distance, 100.0);
+
+        /* Level ground needs no bridge */
+        assert!(find_bridge_spans(&curve, |_| 9.0, &params).is_empty());
+    }
+}
@@ -0,0 +1,509 @@
+use std::f32::consts::PI;
+
+use bevy::math::{Vec2, Vec3, Vec3Swizzles};
+use bevy::prelude::{Component, Reflect, Transform};
+use serde::Deserialize;
+
+/**
+ * Number of pieces a curve is measured in, to find positions by distance along it.
+ */
+const CURVE_SAMPLES: usize = 64;
+
+/**
+ * Horizontal shape of a segment between its two points.
+ *
+ * Directions are horizontal, pointing along the track from the segment's first point
+ * towards its second.  Height always changes at an even grade along the curve.
+ */
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Reflect)]
+pub enum SegmentShape {
+    #[default]
+    Straight,
+    /** A circular arc, turning left for a positive radius and right for a negative one. */
+    Arc { radius: f32 },
+    /** A cubic curve leaving the first point and arriving at the second in the given directions. */
+    Cubic { start_direction: Vec3, end_direction: Vec3 },
+    /**
+     * A transition curve, which is straight in the given direction at one end and curves
+     * increasingly towards the other, its curvature growing evenly with distance as in a
+     * clothoid.  The straight end is the first point, or the second if reversed.
+     */
+    Transition { direction: Vec3, reversed: bool },
+}
+
+impl SegmentShape {
+    /**
+     * The same shape, followed from its second point to its first.
+     */
+    pub fn reversed(self) -> Self {
+        match self {
+            SegmentShape::Straight => SegmentShape::Straight,
+            SegmentShape::Arc { radius } => SegmentShape::Arc { radius: -radius },
+            SegmentShape::Cubic { start_direction, end_direction } =>
+                SegmentShape::Cubic { start_direction: -end_direction, end_direction: -start_direction },
+            SegmentShape::Transition { direction, reversed } =>
+                SegmentShape::Transition { direction: -direction, reversed: !reversed },
+        }
+    }
+}
+
+/**
+ * A segment's shape placed between its points, measured so it can be followed by distance.
+ */
+#[derive(Clone, Component, Debug, Default, PartialEq)]
+pub struct SegmentCurve {
+    shape: SegmentShape,
+    from: Vec3,
+    to: Vec3,
+    /** Horizontal distance along the curve at evenly spaced parameters. */
+    distances: Vec<f32>,
+    length: f32,
+    /** For transitions, the clothoid's turn, its length, and which way it turns. */
+    clothoid: (f32, f32, f32),
+}
+
+impl SegmentCurve {
+    pub fn new(from: Vec3, to: Vec3, shape: SegmentShape) -> Self {
+        let mut curve = SegmentCurve { shape, from, to, distances: Vec::new(), length: 0.0, clothoid: (0.0, 0.0, 1.0) };
+        if let SegmentShape::Transition { direction, reversed } = shape {
+            let (origin, target, axis) = if reversed { (to, from, -direction) } else { (from, to, direction) };
+            let (u, v) = transition_axes(axis);
+            let local = target.xz() - origin.xz();
+            let (x, y) = (local.dot(u), local.dot(v));
+            let (turn, length) = fit_clothoid(Vec2::new(x, y.abs()));
+            curve.clothoid = (turn, length, if y < 0.0 { -1.0 } else { 1.0 });
+        }
+
+        let mut distance = 0.0;
+        let mut prev = from.xz();
+        curve.distances.push(0.0);
+        for i in 1..=CURVE_SAMPLES {
+            let pt = curve.horizontal_point(i as f32 / CURVE_SAMPLES as f32);
+            distance += pt.distance(prev);
+            curve.distances.push(distance);
+            prev = pt;
+        }
+
+        curve.length = distance.hypot(to.y - from.y);
+        curve
+    }
+
+    pub fn shape(&self) -> SegmentShape {
+        self.shape
+    }
+
+    /**
+     * The same curve, followed from its second point to its first.
+     */
+    pub fn reversed(&self) -> Self {
+        SegmentCurve::new(self.to, self.from, self.shape.reversed())
+    }
+
+    /** Length along the curve, including its rise or fall. */
+    pub fn length(&self) -> f32 {
+        self.length
+    }
+
+    fn horizontal_length(&self) -> f32 {
+        self.distances[CURVE_SAMPLES]
+    }
+
+    /**
+     * Point on the horizontal shape of the curve, as (x, z), for parameter t in 0..1.
+     */
+    fn horizontal_point(&self, t: f32) -> Vec2 {
+        let (from, to) = (self.from.xz(), self.to.xz());
+
+        match self.shape {
+            SegmentShape::Straight => from.lerp(to, t),
+            SegmentShape::Arc { radius } => {
+                let chord = to - from;
+                let d = chord.length();
+                if d == 0.0 || radius == 0.0 { return from.lerp(to, t); }
+
+                let r = radius.abs().max(d / 2.0);
+                let side = radius.signum();
+                let left = Vec2::new(chord.y, -chord.x) / d;
+                let centre = (from + to) / 2.0 + left * side * (r * r - d * d / 4.0).max(0.0).sqrt();
+                let angle = side * 2.0 * (d / (2.0 * r)).min(1.0).asin() * t;
+                let (sin, cos) = angle.sin_cos();
+                let v = from - centre;
+                centre + Vec2::new(v.x * cos + v.y * sin, -v.x * sin + v.y * cos)
+            },
+            SegmentShape::Cubic { start_direction, end_direction } => {
+                let handle = from.distance(to) / 3.0;
+                let p1 = from + start_direction.xz().normalize_or_zero() * handle;
+                let p2 = to - end_direction.xz().normalize_or_zero() * handle;
+                let u = 1.0 - t;
+                from * (u * u * u) + p1 * (3.0 * u * u * t) + p2 * (3.0 * u * t * t) + to * (t * t * t)
+            },
+            SegmentShape::Transition { direction, reversed } => {
+                let (origin, axis, t) = if reversed {
+                    (to, -direction, 1.0 - t)
+                } else {
+                    (from, direction, t)
+                };
+                let (u, v) = transition_axes(axis);
+                let (turn, length, side) = self.clothoid;
+                let p = clothoid_point(turn, t) * length;
+                origin + u * p.x + v * (side * p.y)
+            },
+        }
+    }
+
+    /**
+     * Parameter of the point a horizontal distance along the curve, clamped to its ends.
+     */
+    fn param_at(&self, distance: f32) -> f32 {
+        let i = self.distances.partition_point(|d| *d <= distance).clamp(1, CURVE_SAMPLES);
+        let (d0, d1) = (self.distances[i - 1], self.distances[i]);
+        let frac = if d1 > d0 { ((distance - d0) / (d1 - d0)).clamp(0.0, 1.0) } else { 0.0 };
+        (i as f32 - 1.0 + frac) / CURVE_SAMPLES as f32
+    }
+
+    /** Horizontal distance for a distance along the curve. */
+    fn horizontal_distance(&self, distance: f32) -> f32 {
+        if self.length == 0.0 { 0.0 } else { distance * self.horizontal_length() / self.length }
+    }
+
+    /**
+     * Direction of travel a distance along the curve, including its grade.
+     */
+    pub fn direction_at(&self, distance: f32) -> Vec3 {
+        const EPSILON: f32 = 1e-3;
+
+        let t = self.param_at(self.horizontal_distance(distance));
+        let (t0, t1) = ((t - EPSILON).max(0.0), (t + EPSILON).min(1.0));
+        let tangent = (self.horizontal_point(t1) - self.horizontal_point(t0)).normalize_or_zero();
+
+        let horizontal_length = self.horizontal_length();
+        if horizontal_length == 0.0 { return (self.to - self.from).normalize_or_zero(); }
+        let grade = (self.to.y - self.from.y) / horizontal_length;
+        Vec3::new(tangent.x, grade, tangent.y).normalize()
+    }
+
+    /**
+     * Horizontal curvature a distance along the curve, which is the reciprocal of its
+     * radius, positive when turning left.
+     */
+    pub fn curvature_at(&self, distance: f32) -> f32 {
+        const STEP: f32 = 0.5;
+
+        /* Measuring would only find rounding errors */
+        if self.shape == SegmentShape::Straight { return 0.0; }
+
+        let (d0, d1) = ((distance - STEP).max(0.0), (distance + STEP).min(self.length));
+        let horizontal = self.horizontal_distance(d1 - d0);
+        if horizontal <= 0.0 { return 0.0; }
+
+        let (a, b) = (self.direction_at(d0).xz(), self.direction_at(d1).xz());
+        let turn = (-a.perp_dot(b)).atan2(a.dot(b));
+        turn / horizontal
+    }
+
+    /**
+     * Position a distance along the curve.  Beyond either end, the curve carries straight on.
+     */
+    pub fn position_at(&self, distance: f32) -> Vec3 {
+        if distance < 0.0 {
+            return self.from + self.direction_at(0.0) * distance;
+        } else if distance > self.length {
+            return self.to + self.direction_at(self.length) * (distance - self.length);
+        }
+
+        let horizontal_distance = self.horizontal_distance(distance);
+        let pt = self.horizontal_point(self.param_at(horizontal_distance));
+        let frac = if self.length == 0.0 { 0.0 } else { distance / self.length };
+        Vec3::new(pt.x, self.from.y + (self.to.y - self.from.y) * frac, pt.y)
+    }
+
+    /**
+     * Frame a distance along the curve, with its local z-axis pointing along the track.
+     */
+    pub fn transform_at(&self, distance: f32) -> Transform {
+        let direction = self.direction_at(distance.clamp(0.0, self.length));
+        Transform::from_translation(self.position_at(distance)).looking_to(-direction, Vec3::Y)
+    }
+
+    /**
+     * Shapes for the two pieces of the curve either side of a distance along it.  Arcs stay
+     * arcs; other curves become cubics keeping their directions at the ends and the split.
+     */
+    pub fn split(&self, distance: f32) -> (SegmentShape, SegmentShape) {
+        match self.shape {
+            SegmentShape::Straight | SegmentShape::Arc { .. } => (self.shape, self.shape),
+            _ => {
+                let [start, middle, end] = [0.0, distance, self.length].map(|d| self.direction_at(d).with_y(0.0));
+                (SegmentShape::Cubic { start_direction: start, end_direction: middle },
+                 SegmentShape::Cubic { start_direction: middle, end_direction: end })
+            }
+        }
+    }
+
+    /**
+     * Shape for a single segment replacing this curve and the one following it.
+     */
+    pub fn joined(&self, next: &SegmentCurve) -> SegmentShape {
+        match (self.shape, next.shape) {
+            (SegmentShape::Straight, SegmentShape::Straight) => {
+                let (a, b) = (self.direction_at(0.0).xz(), next.direction_at(0.0).xz());
+                if a.dot(b) > 0.9999 { return SegmentShape::Straight; }
+            },
+            (SegmentShape::Arc { radius: r1 }, SegmentShape::Arc { radius: r2 }) if r1 == r2 => {
+                return self.shape;
+            },
+            _ => {},
+        }
+        SegmentShape::Cubic {
+            start_direction: self.direction_at(0.0).with_y(0.0),
+            end_direction: next.direction_at(next.length()).with_y(0.0),
+        }
+    }
+
+    /**
+     * Distance along the curve of the point nearest a position, and how far away it is,
+     * both measured horizontally.
+     */
+    pub fn nearest(&self, pos: Vec3, step: f32) -> (f32, f32) {
+        let steps = ((self.length / step).ceil() as usize).max(1);
+        (0..=steps)
+            .map(|i| self.length * i as f32 / steps as f32)
+            .map(|d| (d, self.position_at(d).xz().distance(pos.xz())))
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap()
+    }
+
+    /**
+     * Frames along the whole curve, no further apart than the given step.  Straight
+     * segments only need their ends.
+     */
+    pub fn path(&self, max_step: f32) -> Vec<Transform> {
+        self.path_between(0.0, self.length, max_step)
+    }
+
+    /**
+     * Frames along part of the curve, between two distances along it.
+     */
+    pub fn path_between(&self, start: f32, end: f32, max_step: f32) -> Vec<Transform> {
+        let steps = match self.shape {
+            SegmentShape::Straight => 1,
+            _ => (((end - start) / max_step).ceil() as usize).max(1),
+        };
+        (0..=steps)
+            .map(|i| self.transform_at(start + (end - start) * i as f32 / steps as f32))
+            .collect()
+    }
+}
+
+/**
+ * Horizontal axes for a transition straight along a direction: forward, and to the left.
+ */
+fn transition_axes(direction: Vec3) -> (Vec2, Vec2) {
+    let u = direction.xz().normalize_or_zero();
+    (u, Vec2::new(u.y, -u.x))
+}
+
+/**
+ * Point a fraction of the way along a clothoid of unit length, which leaves the origin
+ * along the x-axis and curves towards +y, turning through the given angle by its end.
+ */
+fn clothoid_point(turn: f32, t: f32) -> Vec2 {
+    /* Simpson's rule, over a heading growing with the square of the distance */
+    const STEPS: usize = 16;
+    let h = t / STEPS as f32;
+    let heading = |s: f32| Vec2::from_angle(turn * s * s);
+    let sum = (1..STEPS).fold(heading(0.0) + heading(t), |sum, i| {
+        sum + heading(i as f32 * h) * if i % 2 == 1 { 4.0 } else { 2.0 }
+    });
+    sum * h / 3.0
+}
+
+/**
+ * The turn and length of the clothoid leaving the origin along the x-axis that passes
+ * through a point with positive y.
+ */
+fn fit_clothoid(target: Vec2) -> (f32, f32) {
+    let bearing = target.y.atan2(target.x);
+    let (mut low, mut high) = (0.0, PI);
+    for _ in 0..24 {
+        let mid = (low + high) / 2.0;
+        let end = clothoid_point(mid, 1.0);
+        if end.y.atan2(end.x) < bearing { low = mid; } else { high = mid; }
+    }
+    let turn = (low + high) / 2.0;
+    (turn, target.length() / clothoid_point(turn, 1.0).length())
+}
+
+/**
+ * Shapes for the segments between a run of points, curving smoothly through each point.
+ * The direction at each point is taken from its neighbours, as in a Catmull-Rom spline.
+ * Segments that stay in line with their neighbours are left straight, those whose ends
+ * fit a circle or a transition from straight become arcs or transitions, and the rest
+ * are cubic curves.
+ */
+pub fn smooth_shapes(points: &[Vec3], looped: bool) -> Vec<SegmentShape> {
+    smooth_shapes_between(points, looped, None, None)
+}
+
+/**
+ * As `smooth_shapes`, but leaving the first point and arriving at the last in the given
+ * directions, if any, to carry on smoothly from the track already there.
+ */
+pub fn smooth_shapes_between(
+    points: &[Vec3],
+    looped: bool,
+    start_direction: Option<Vec3>,
+    end_direction: Option<Vec3>,
+) -> Vec<SegmentShape> {
+    let n = points.len();
+    if n < 2 { return Vec::new(); }
+
+    let direction = |i: usize| -> Vec3 {
+        let given = match i {
+            0 if !looped => start_direction,
+            i if i == n - 1 && !looped => end_direction,
+            _ => None,
+        };
+        if let Some(given) = given {
+            return given.with_y(0.0).normalize_or_zero();
+        }
+
+        let (prev, next) = if looped {
+            ((i + n - 1) % n, (i + 1) % n)
+        } else {
+            (i.saturating_sub(1), (i + 1).min(n - 1))
+        };
+        (points[next] - points[prev]).with_y(0.0).normalize_or_zero()
+    };
+
+    let num_segments = if looped { n } else { n - 1 };
+    (0..num_segments).map(|i| {
+        let (from, to) = (points[i], points[(i + 1) % n]);
+        let (start_direction, end_direction) = (direction(i), direction((i + 1) % n));
+        fit_shape(from, to, start_direction, end_direction)
+    }).collect()
+}
+
+/**
+ * Simplest shape leaving one point and arriving at another in the given directions.
+ */
+fn fit_shape(from: Vec3, to: Vec3, start_direction: Vec3, end_direction: Vec3) -> SegmentShape {
+    const STRAIGHT: f32 = 0.9999;
+    const TOLERANCE: f32 = 1e-3;
+
+    let chord = (to - from).xz();
+    let angle_from_chord = |dir: Vec3| chord.normalize_or_zero().angle_to(dir.xz());
+    let (start_angle, end_angle) = (angle_from_chord(start_direction), angle_from_chord(end_direction));
+    let chord_dir = chord.normalize_or_zero();
+    if start_direction.xz().dot(chord_dir) > STRAIGHT && end_direction.xz().dot(chord_dir) > STRAIGHT {
+        return SegmentShape::Straight;
+    }
+
+    /* A circle turns as far from the chord at one end as the other */
+    if (start_angle + end_angle).abs() < TOLERANCE {
+        let angle = (start_angle - end_angle) / 2.0;
+        return SegmentShape::Arc { radius: chord.length() / (2.0 * angle.sin()) };
+    }
+
+    /* A transition from straight turns through its whole angle by the curved end */
+    let turn_at_end = |origin: Vec3, target: Vec3, axis: Vec3, end: Vec3| {
+        let (u, v) = transition_axes(axis);
+        let local = target.xz() - origin.xz();
+        let (x, y) = (local.dot(u), local.dot(v));
+        if x <= 0.0 { return f32::INFINITY; }
+        let (turn, _) = fit_clothoid(Vec2::new(x, y.abs()));
+        let end_turn = end.xz().dot(v).atan2(end.xz().dot(u));
+        (end_turn - turn * y.signum()).abs()
+    };
+    if turn_at_end(from, to, start_direction, end_direction) < TOLERANCE {
+        return SegmentShape::Transition { direction: start_direction, reversed: false };
+    }
+    if turn_at_end(to, from, -end_direction, -start_direction) < TOLERANCE {
+        return SegmentShape::Transition { direction: end_direction, reversed: true };
+    }
+
+    SegmentShape::Cubic { start_direction, end_direction }
+}
+
+#[cfg(test)]
+mod test {
+    use std::f32::consts::PI;
+
+    use super::*;
+
+    #[test]
+    fn test_arc_length() {
+        /* A quarter circle of radius 10, turning left from heading east to heading north */
+        let from = Vec3::new(0.0, 0.0, 0.0);
+        let to = Vec3::new(10.0, 0.0, -10.0);
+        let curve = SegmentCurve::new(from, to, SegmentShape::Arc { radius: 10.0 });
+
+        assert!((curve.length() - 5.0 * PI).abs() < 0.01);
+        assert!(curve.direction_at(0.0).distance(Vec3::X) < 0.01);
+        assert!(curve.direction_at(curve.length()).distance(Vec3::NEG_Z) < 0.01);
+        assert!(curve.position_at(curve.length() / 2.0).distance(Vec3::new(10.0 * (PI / 4.0).sin(), 0.0, 10.0 * (PI / 4.0).cos() - 10.0)) < 0.01);
+        assert!((curve.curvature_at(curve.length() / 2.0) - 0.1).abs() < 0.001);
+        let right = SegmentCurve::new(from, Vec3::new(10.0, 0.0, 10.0), SegmentShape::Arc { radius: -10.0 });
+        assert!((right.curvature_at(1.0) + 0.1).abs() < 0.001);
+
+        /* Equal steps along the arc cover equal distances */
+        let pts: Vec<_> = (0..=10).map(|i| curve.position_at(curve.length() * i as f32 / 10.0)).collect();
+        for w in pts.windows(2) {
+            assert!((w[0].distance(w[1]) - pts[0].distance(pts[1])).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn test_transition() {
+        /* Leaves straight and ends on the arc it leads into */
+        let from = Vec3::ZERO;
+        let to = Vec3::new(30.0, 1.0, -3.0);
+        let curve = SegmentCurve::new(from, to, SegmentShape::Transition { direction: Vec3::X, reversed: false });
+
+        assert!(curve.direction_at(0.0).with_y(0.0).normalize().distance(Vec3::X) < 0.001);
+        assert!(curve.position_at(curve.length()).distance(to) < 0.001);
+        assert!((curve.position_at(curve.length() / 2.0).y - 0.5).abs() < 0.001);
+
+        /* Reversed, it joins the same arc at its first point and is straight at its second */
+        let shape = SegmentShape::Transition { direction: Vec3::X, reversed: false }.reversed();
+        let reversed = SegmentCurve::new(to, from, shape);
+        assert!((reversed.length() - curve.length()).abs() < 0.001);
+        assert!(reversed.direction_at(reversed.length()).with_y(0.0).normalize().distance(-Vec3::X) < 0.001);
+        assert!(reversed.direction_at(0.0).distance(-curve.direction_at(curve.length())) < 0.001);
+        assert!(reversed.position_at(5.0).distance(curve.position_at(curve.length() - 5.0)) < 0.01);
+    }
+
+    #[test]
+    fn test_clothoid() {
+        /* Curvature grows evenly from the straight end */
+        let to = Vec3::new(40.0, 0.0, -6.0);
+        let curve = SegmentCurve::new(Vec3::ZERO, to, SegmentShape::Transition { direction: Vec3::X, reversed: false });
+        let length = curve.length();
+        let (k1, k2) = (curve.curvature_at(length / 4.0), curve.curvature_at(length / 2.0));
+        assert!(k1 > 0.0 && (k2 / k1 - 2.0).abs() < 0.05, "{} {}", k1, k2);
+        assert!(curve.position_at(length).distance(to) < 0.01);
+    }
+
+    #[test]
+    fn test_fit_shapes() {
+        /* Points evenly spaced around a circle are joined by arcs of that circle */
+        let points: Vec<_> = (0..8).map(|i| {
+            let angle = i as f32 * PI / 12.0;
+            Vec3::new(100.0 * angle.sin(), 0.0, 100.0 * angle.cos() - 100.0)
+        }).collect();
+        let shapes = smooth_shapes(&points, false);
+        for shape in &shapes[1..6] {
+            let SegmentShape::Arc { radius } = shape else { panic!("{:?} isn't an arc", shape) };
+            assert!((radius - 100.0).abs() < 0.5, "radius {}", radius);
+        }
+
+        /* Ends matching a clothoid from straight make a transition, either way round */
+        let to = Vec3::new(40.0, 0.0, 6.0);
+        let curve = SegmentCurve::new(Vec3::ZERO, to, SegmentShape::Transition { direction: Vec3::X, reversed: false });
+        let end_direction = curve.direction_at(curve.length()).with_y(0.0).normalize();
+        assert_eq!(fit_shape(Vec3::ZERO, to, Vec3::X, end_direction), curve.shape());
+        assert_eq!(fit_shape(to, Vec3::ZERO, -end_direction, -Vec3::X),
+                   SegmentShape::Transition { direction: -Vec3::X, reversed: true });
+        assert!(matches!(fit_shape(Vec3::ZERO, to, Vec3::X, Vec3::X), SegmentShape::Cubic { .. }));
+    }
+}
@@ -0,0 +1,238 @@
+use bevy::log::{info, info_span};
+use bevy::math::{Vec2, Vec3, Vec3Swizzles};
+use bevy::prelude::{Changed, Component, Or, Query, Reflect, ReflectComponent, Res, Single, Transform, With};
+
+use crate::level::LevelLabel;
+use crate::terrain::chunks::HeightGrid;
+use crate::terrain::soil::SoilMap;
+use crate::terrain::utils::Range2;
+use crate::terrain::{TerrainData, TerrainLayer};
+use crate::track::curve::SegmentCurve;
+use crate::track::editing::TrackEditing;
+use crate::track::segment::Segment;
+
+/**
+ * Longest piece of a curved segment that is shaped as one straight corridor.
+ */
+const CURVE_STEP: f32 = 5.0;
+
+/**
+ * Shape of the ground along a track corridor.
+ *
+ * The formation is the flat surface the track bed sits on, and is at the level of the
+ * track's points.  Beyond the formation, cuttings have a ditch on each side before the
+ * ground rises at the soil's cut slope; embankments fall away at the fill slope.
+ */
+#[derive(Clone, Debug, Reflect)]
+pub struct CorridorProfile {
+    pub formation_width: f32,
+    pub ditch_width: f32,
+    pub ditch_depth: f32,
+    pub max_reach: f32,
+}
+
+impl Default for CorridorProfile {
+    fn default() -> Self {
+        CorridorProfile {
+            formation_width: 6.0,
+            ditch_width: 1.0,
+            ditch_depth: 0.5,
+            max_reach: 40.0,
+        }
+    }
+}
+
+/**
+ * Marks a segment as having its terrain shaped to fit the track.
+ *
+ * Volumes are in cubic metres, and record the most recent run of the earthworks.
+ */
+#[derive(Component, Default, Reflect)]
+#[reflect(Component)]
+pub struct Earthworks {
+    pub profile: CorridorProfile,
+    pub cut_volume: f32,
+    pub fill_volume: f32,
+}
+
+#[derive(Debug, Default)]
+pub struct EarthworksReport {
+    pub cut_volume: f32,
+    pub fill_volume: f32,
+    pub range: Range2,
+}
+
+pub fn update_earthworks(
+    mut segments: Query<(&SegmentCurve, &mut Earthworks), Or<(Changed<Segment>, Changed<Transform>)>>,
+    mut terrain_data: Single<&mut TerrainData, With<LevelLabel>>,
+    editing: Option<Res<TrackEditing>>,
+) {
+    if segments.is_empty() { return; }
+
+    /* Wait until a dragged point is let go, rather than digging along the way */
+    if editing.is_some_and(|e| e.dragging.is_some()) { return; }
+
+    let Some(elevation) = terrain_data.layers.get(&TerrainLayer::Elevation).cloned()
+    else { return; };
+
+    let _span = info_span!("terraform.earthworks").entered();
+
+    let mut ranges_to_dirty = Vec::new();
+    let mut total_cut = 0.0;
+    let mut total_fill = 0.0;
+
+    let mut elevation = elevation.writer();
+    for (curve, mut earthworks) in segments.iter_mut() {
+        earthworks.cut_volume = 0.0;
+        earthworks.fill_volume = 0.0;
+
+        for report in curve_earthworks(curve, &earthworks.profile, &mut elevation, &terrain_data.soil) {
+            earthworks.cut_volume += report.cut_volume;
+            earthworks.fill_volume += report.fill_volume;
+            ranges_to_dirty.push(report.range);
+        }
+        total_cut += earthworks.cut_volume;
+        total_fill += earthworks.fill_volume;
+    }
+    drop(elevation);
+
+    info!("Earthworks: cut {:.1} m3, fill {:.1} m3", total_cut, total_fill);
+
+    for range in ranges_to_dirty {
+        terrain_data.dirty_range(range);
+    }
+}
+
+/**
+ * Cut and fill the terrain along a segment's curve, shaped as a run of short straight
+ * corridors, giving a report for each.
+ */
+pub fn curve_earthworks(
+    curve: &SegmentCurve,
+    profile: &CorridorProfile,
+    data: &mut impl HeightGrid,
+    soil: &SoilMap,
+) -> Vec<EarthworksReport> {
+    curve.path(CURVE_STEP).windows(2)
+        .map(|pair| corridor_earthworks(pair[0].translation, pair[1].translation, profile, data, soil))
+        .collect()
+}
+
+/**
+ * Cut and fill the terrain along a straight corridor between two points.
+ *
+ * Points are in world space, where x is the column and z is the row of the elevation
+ * data.  Each point within reach of the corridor is constrained to lie between the
+ * embankment and cutting surfaces for its distance from the track's centre line.
+ */
+pub fn corridor_earthworks(
+    from: Vec3,
+    to: Vec3,
+    profile: &CorridorProfile,
+    data: &mut impl HeightGrid,
+    soil: &SoilMap,
+) -> EarthworksReport {
+    let mut report = EarthworksReport::default();
+
+    let (rows, cols) = data.dim();
+    let half_width = profile.formation_width / 2.0;
+    let reach = half_width + profile.max_reach;
+
+    let min = from.xz().min(to.xz()) - Vec2::splat(reach);
+    let max = from.xz().max(to.xz()) + Vec2::splat(reach);
+    let row_range = (min.y.floor().max(0.0) as usize)..(max.y.ceil().max(0.0) as usize + 1).min(rows);
+    let col_range = (min.x.floor().max(0.0) as usize)..(max.x.ceil().max(0.0) as usize + 1).min(cols);
+
+    let line = to.xz() - from.xz();
+    let line_length_squared = line.length_squared();
+
+    for row in row_range {
+        for col in col_range.clone() {
+            let pos = Vec2::new(col as f32, row as f32);
+            let along = if line_length_squared > 0.0 {
+                (pos - from.xz()).dot(line) / line_length_squared
+            } else { 0.0 };
+            let t = along.clamp(0.0, 1.0);
+            let dist = pos.distance(from.xz() + line * t);
+            if dist > reach { continue; }
+
+            let formation = from.y + (to.y - from.y) * t;
+            let old_h = data.get((row, col));
+
+            let new_h = if dist <= half_width {
+                formation
+            } else {
+                let side = dist - half_width;
+                let mut ceiling = formation + side * soil.cut_slope(row, col);
+                let floor = formation - side * soil.fill_slope(row, col);
+                /* Ditches run beside the track, not around the ends of the corridor */
+                if old_h > formation && side <= profile.ditch_width && (0.0..=1.0).contains(&along) {
+                    ceiling = formation - profile.ditch_depth;
+                }
+                old_h.clamp(floor, ceiling.max(floor))
+            };
+
+            if new_h == old_h { continue; }
+
+            if new_h < old_h {
+                report.cut_volume += old_h - new_h;
+            } else {
+                report.fill_volume += new_h - old_h;
+            }
+            data.set((row, col), new_h);
+            report.range.expand_to(row, col);
+        }
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod test {
+    use ndarray::Array2;
+    use super::*;
+
+    #[test]
+    fn test_cutting() {
+        let soil = SoilMap::new([21, 21], &[]);
+        let profile = CorridorProfile { formation_width: 2.0, ditch_width: 1.0, ditch_depth: 0.5, max_reach: 10.0 };
+        let mut data = Array2::from_elem((21, 21), 5.0);
+
+        let report = corridor_earthworks(
+            Vec3::new(0.0, 0.0, 10.0), Vec3::new(20.0, 0.0, 10.0),
+            &profile, &mut data, &soil);
+
+        assert_eq!(data[(10, 5)], 0.0);
+        assert_eq!(data[(11, 5)], 0.0);
+        assert_eq!(data[(12, 5)], -0.5);
+        assert_eq!(data[(13, 5)], 2.0);
+        assert_eq!(data[(20, 5)], 5.0);
+        assert!(report.cut_volume > 0.0);
+        assert_eq!(report.fill_volume, 0.0);
+
+        /* Past the end of the corridor there is a slope, but no ditch */
+        let mut data = Array2::from_elem((21, 21), 5.0);
+        corridor_earthworks(
+            Vec3::new(0.0, 0.0, 10.0), Vec3::new(10.0, 0.0, 10.0),
+            &profile, &mut data, &soil);
+        assert_eq!(data[(12, 5)], -0.5);
+        assert_eq!(data[(10, 12)], 1.0);
+    }
+
+    #[test]
+    fn test_embankment() {
+        let soil = SoilMap::new([21, 21], &[]);
+        let profile = CorridorProfile { formation_width: 2.0, ditch_width: 1.0, ditch_depth: 0.5, max_reach: 10.0 };
+        let mut data = Array2::zeros((21, 21));
+
+        let report = corridor_earthworks(
+            Vec3::new(0.0, 5.0, 10.0), Vec3::new(20.0, 5.0, 10.0),
+            &profile, &mut data, &soil);
+
+        assert_eq!(data[(10, 5)], 5.0);
+        assert_eq!(data[(12, 5)], 4.0);
+        assert_eq!(data[(16, 5)], 0.0);
+        assert_eq!(report.cut_volume, 0.0);
+        assert!(report.fill_volume > 0.0);
+    }
+}
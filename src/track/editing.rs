@@ -0,0 +1,540 @@
+use bevy::color::Color;
+use bevy::input::ButtonInput;
+use bevy::log::{info, warn};
+use bevy::math::{Vec3, Vec3Swizzles};
+use bevy::prelude::{ChildOf, Commands, DetectChangesMut, Entity, Gizmos, Isometry3d, KeyCode, MouseButton, Query, Res, ResMut, Resource, Transform, With, Without};
+
+use crate::level::selection::SelectedPoint;
+use crate::track::curve::{SegmentCurve, SegmentShape};
+use crate::track::earthworks::Earthworks;
+use crate::track::point::Point;
+use crate::track::rendering::CURVE_STEP;
+use crate::track::segment::Segment;
+use crate::track::TRACK_HEIGHT;
+use crate::train::TrainCar;
+
+/**
+ * How close the cursor has to be to a point or segment to pick it.
+ */
+const PICK_RADIUS: f32 = 3.0;
+
+/**
+ * How much a dragged point's height above the terrain changes with each press of + or -.
+ */
+const HEIGHT_STEP: f32 = 0.5;
+
+const EDIT_COLOUR: Color = Color::srgb(0.3, 0.8, 1.0);
+
+/**
+ * The point being dragged with the track editing tool, and its height above the terrain.
+ */
+#[derive(Default, Resource)]
+pub struct TrackEditing {
+    pub dragging: Option<(Entity, f32)>,
+}
+
+type PointQuery<'w, 's> = Query<'w, 's, (Entity, &'static mut Transform, Option<&'static ChildOf>), (With<Point>, Without<Segment>)>;
+type SegmentQuery<'w, 's> = Query<'w, 's, (Entity, &'static mut Segment, &'static SegmentCurve, Option<&'static ChildOf>, Option<&'static Earthworks>)>;
+type TrainQuery<'w, 's> = Query<'w, 's, (Entity, &'static mut TrainCar)>;
+
+/**
+ * Edit the track under the cursor:
+ *   - drag a point to move it, keeping its height above the terrain, which + and - change;
+ *   - drop a track end on another point to join them;
+ *   - click on a segment to add a point there;
+ *   - Delete a point to merge the segments either side of it;
+ *   - right click a point to split the track there.
+ */
+pub fn edit_track(
+    buttons: Res<ButtonInput<MouseButton>>,
+    keys: Res<ButtonInput<KeyCode>>,
+    selected_point: Res<SelectedPoint>,
+    mut editing: ResMut<TrackEditing>,
+    mut points: PointQuery,
+    mut segments: SegmentQuery,
+    mut trains: TrainQuery,
+    mut gizmos: Gizmos,
+    mut commands: Commands,
+) {
+    let cursor = selected_point.point;
+
+    if let Some((point_id, mut offset)) = editing.dragging {
+        if keys.just_pressed(KeyCode::Equal) { offset += HEIGHT_STEP; }
+        if keys.just_pressed(KeyCode::Minus) { offset -= HEIGHT_STEP; }
+        editing.dragging = Some((point_id, offset));
+
+        if buttons.pressed(MouseButton::Left) {
+            let Ok((_, mut transform, _)) = points.get_mut(point_id)
+            else {
+                editing.dragging = None;
+                return;
+            };
+            let pos = cursor + Vec3::Y * offset;
+            if transform.translation != pos {
+                transform.translation = pos;
+                refit_shapes(point_id, &mut segments);
+            }
+            gizmos.line(cursor, pos, EDIT_COLOUR);
+        } else {
+            editing.dragging = None;
+            touch(point_id, &mut segments);
+            let target = nearest_point(cursor, &points, |id| id != point_id && !adjacent(point_id, id, &segments));
+            if let Some(target_id) = target {
+                join_points(point_id, target_id, &points, &mut segments, &mut trains, &mut commands);
+            }
+        }
+        return;
+    }
+
+    if let Some(point_id) = nearest_point(cursor, &points, |_| true) {
+        let pos = points.get(point_id).unwrap().1.translation;
+        gizmos.sphere(Isometry3d::from_translation(pos + Vec3::Y * TRACK_HEIGHT), PICK_RADIUS / 2.0, EDIT_COLOUR);
+
+        if buttons.just_pressed(MouseButton::Left) {
+            editing.dragging = Some((point_id, pos.y - cursor.y));
+        } else if keys.just_pressed(KeyCode::Delete) {
+            delete_point(point_id, &mut segments, &mut trains, &mut commands);
+        } else if buttons.just_pressed(MouseButton::Right) {
+            split_at(point_id, &points, &mut segments, &mut commands);
+        }
+        return;
+    }
+
+    let nearest_segment = segments.iter()
+        .filter(|(_, _, curve, ..)| {
+            let reach = curve.length() / 2.0 + PICK_RADIUS;
+            curve.position_at(curve.length() / 2.0).xz().distance(cursor.xz()) < reach
+        })
+        .map(|(segment_id, _, curve, ..)| (segment_id, curve.nearest(cursor, CURVE_STEP / 4.0)))
+        .filter(|(_, (_, separation))| *separation < PICK_RADIUS)
+        .min_by(|a, b| a.1.1.total_cmp(&b.1.1));
+
+    if let Some((segment_id, (distance, _))) = nearest_segment {
+        let (_, _, curve, ..) = segments.get(segment_id).unwrap();
+        let positions = curve.path(CURVE_STEP).into_iter().map(|frame| frame.translation + Vec3::Y * TRACK_HEIGHT);
+        gizmos.linestrip(positions, EDIT_COLOUR);
+
+        if buttons.just_pressed(MouseButton::Left) {
+            insert_point(segment_id, distance, &mut segments, &mut trains, &mut commands);
+        }
+    }
+}
+
+/**
+ * Let go of any point being dragged when the tool is put down.
+ */
+pub fn stop_editing(mut editing: ResMut<TrackEditing>) {
+    editing.dragging = None;
+}
+
+fn nearest_point(pos: Vec3, points: &PointQuery, filter: impl Fn(Entity) -> bool) -> Option<Entity> {
+    points.iter()
+        .filter(|(id, ..)| filter(*id))
+        .map(|(id, transform, _)| (id, transform.translation.xz().distance(pos.xz())))
+        .filter(|(_, distance)| *distance < PICK_RADIUS)
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(id, _)| id)
+}
+
+fn adjacent(a: Entity, b: Entity, segments: &SegmentQuery) -> bool {
+    segments.iter().any(|(_, seg, ..)| {
+        (seg.from_point == a && seg.to_point == b) || (seg.from_point == b && seg.to_point == a)
+    })
+}
+
+/**
+ * Segments arriving at and leaving a point.
+ */
+fn segments_at(point_id: Entity, segments: &SegmentQuery) -> (Vec<Entity>, Vec<Entity>) {
+    let arriving = segments.iter().filter(|(_, seg, ..)| seg.to_point == point_id).map(|(id, ..)| id).collect();
+    let leaving = segments.iter().filter(|(_, seg, ..)| seg.from_point == point_id).map(|(id, ..)| id).collect();
+    (arriving, leaving)
+}
+
+/**
+ * Mark the segments at a point as changed, so the track is linked up again.
+ */
+fn touch(point_id: Entity, segments: &mut SegmentQuery) {
+    for (_, mut seg, ..) in segments.iter_mut() {
+        if seg.from_point == point_id || seg.to_point == point_id {
+            seg.set_changed();
+        }
+    }
+}
+
+/**
+ * Refit the curved segments either side of a moved point to pass smoothly through it,
+ * keeping their directions at their far ends.  Straight segments stay straight.
+ */
+fn refit_shapes(point_id: Entity, segments: &mut SegmentQuery) {
+    let (arriving, leaving) = segments_at(point_id, segments);
+    let ([in_id], [out_id]) = (arriving.as_slice(), leaving.as_slice())
+    else { return; };
+
+    let (_, _, in_curve, ..) = segments.get(*in_id).unwrap();
+    let (_, _, out_curve, ..) = segments.get(*out_id).unwrap();
+    let prev = in_curve.position_at(0.0);
+    let next = out_curve.position_at(out_curve.length());
+    let direction = (next - prev).with_y(0.0).normalize_or_zero();
+
+    let (_, mut seg, curve, ..) = segments.get_mut(*in_id).unwrap();
+    if seg.shape != SegmentShape::Straight {
+        seg.shape = SegmentShape::Cubic { start_direction: curve.direction_at(0.0).with_y(0.0), end_direction: direction };
+    }
+    let (_, mut seg, curve, ..) = segments.get_mut(*out_id).unwrap();
+    if seg.shape != SegmentShape::Straight {
+        seg.shape = SegmentShape::Cubic { start_direction: direction, end_direction: curve.direction_at(curve.length()).with_y(0.0) };
+    }
+}
+
+/**
+ * Split a segment in two with a new point a distance along it.  Trains beyond the point
+ * move onto the new second half.
+ */
+fn insert_point(segment_id: Entity, distance: f32, segments: &mut SegmentQuery, trains: &mut TrainQuery, commands: &mut Commands) {
+    let (_, mut seg, curve, parent, earthworks) = segments.get_mut(segment_id).unwrap();
+    let (first, second) = curve.split(distance);
+
+    let mut point = commands.spawn((Point, Transform::from_translation(curve.position_at(distance))));
+    if let Some(parent) = parent {
+        point.insert(ChildOf(parent.parent()));
+    }
+    let point_id = point.id();
+
+    let mut new_segment = commands.spawn(Segment {
+        from_point: point_id,
+        to_point: seg.to_point,
+        shape: second,
+        length: 0.0,
+        rendered_id: None,
+    });
+    if let Some(parent) = parent {
+        new_segment.insert(ChildOf(parent.parent()));
+    }
+    if let Some(earthworks) = earthworks {
+        new_segment.insert(Earthworks { profile: earthworks.profile.clone(), ..Earthworks::default() });
+    }
+    let new_segment_id = new_segment.id();
+
+    for (_, mut car) in trains.iter_mut() {
+        if car.segment_id == segment_id && car.segment_position > distance {
+            car.segment_id = new_segment_id;
+            car.segment_position -= distance;
+        }
+    }
+
+    seg.to_point = point_id;
+    seg.shape = first;
+    info!("Inserted point {distance:.1} along segment");
+}
+
+/**
+ * Delete a point, merging the segments either side of it into one.  At the end of a
+ * track its segment goes too, along with any trains on it.
+ */
+fn delete_point(point_id: Entity, segments: &mut SegmentQuery, trains: &mut TrainQuery, commands: &mut Commands) {
+    let (arriving, leaving) = segments_at(point_id, segments);
+
+    match (arriving.as_slice(), leaving.as_slice()) {
+        ([in_id], [out_id]) => {
+            let (_, out_seg, out_curve, ..) = segments.get(*out_id).unwrap();
+            let (to_point, out_curve) = (out_seg.to_point, out_curve.clone());
+
+            let (_, mut in_seg, in_curve, ..) = segments.get_mut(*in_id).unwrap();
+            let in_length = in_curve.length();
+            in_seg.shape = in_curve.joined(&out_curve);
+            in_seg.to_point = to_point;
+
+            for (_, mut car) in trains.iter_mut() {
+                if car.segment_id == *out_id {
+                    car.segment_id = *in_id;
+                    car.segment_position += in_length;
+                }
+            }
+            commands.entity(*out_id).despawn();
+        },
+        ([seg_id], []) | ([], [seg_id]) => {
+            let (_, seg, ..) = segments.get(*seg_id).unwrap();
+            let other_id = if seg.from_point == point_id { seg.to_point } else { seg.from_point };
+
+            for (train_id, car) in trains.iter() {
+                if car.segment_id == *seg_id {
+                    commands.entity(train_id).despawn();
+                }
+            }
+            commands.entity(*seg_id).despawn();
+
+            let others = segments.iter().filter(|(id, seg, ..)| {
+                id != seg_id && (seg.from_point == other_id || seg.to_point == other_id)
+            }).count();
+            if others == 0 {
+                commands.entity(other_id).despawn();
+            } else {
+                touch(other_id, segments);
+            }
+        },
+        _ => {
+            warn!("Can't delete a point at a junction");
+            return;
+        },
+    }
+
+    commands.entity(point_id).despawn();
+    info!("Deleted point");
+}
+
+/**
+ * Split the track at a point, giving the segments leaving it a new point of their own.
+ */
+fn split_at(point_id: Entity, points: &PointQuery, segments: &mut SegmentQuery, commands: &mut Commands) {
+    let (arriving, leaving) = segments_at(point_id, segments);
+    if arriving.is_empty() || leaving.is_empty() {
+        info!("Track already ends here");
+        return;
+    }
+
+    let (_, transform, parent) = points.get(point_id).unwrap();
+    let mut point = commands.spawn((Point, Transform::from_translation(transform.translation)));
+    if let Some(parent) = parent {
+        point.insert(ChildOf(parent.parent()));
+    }
+    let new_point_id = point.id();
+
+    for seg_id in leaving {
+        segments.get_mut(seg_id).unwrap().1.from_point = new_point_id;
+    }
+    touch(point_id, segments);
+    info!("Split track");
+}
+
+/**
+ * Join the end of a track to another point, which replaces it.  If the track runs the
+ * other way to the one it joins, it is turned round first.
+ */
+fn join_points(end_id: Entity, target_id: Entity, points: &PointQuery, segments: &mut SegmentQuery, trains: &mut TrainQuery, commands: &mut Commands) {
+    let (arriving, leaving) = segments_at(end_id, segments);
+    let (seg_id, direction) = match (arriving.as_slice(), leaving.as_slice()) {
+        ([seg_id], []) => {
+            let (_, _, curve, ..) = segments.get(*seg_id).unwrap();
+            (*seg_id, curve.direction_at(curve.length()))
+        },
+        ([], [seg_id]) => {
+            let (_, _, curve, ..) = segments.get(*seg_id).unwrap();
+            (*seg_id, curve.direction_at(0.0))
+        },
+        _ => {
+            info!("Only track ends can be joined");
+            return;
+        },
+    };
+
+    let target_forward = points.get(target_id).unwrap().1.forward().as_vec3();
+    if direction.dot(target_forward) < 0.0 {
+        reverse_track(seg_id, segments, trains);
+    }
+
+    for (_, mut seg, ..) in segments.iter_mut() {
+        if seg.from_point == end_id { seg.from_point = target_id; }
+        if seg.to_point == end_id { seg.to_point = target_id; }
+    }
+    touch(target_id, segments);
+    commands.entity(end_id).despawn();
+    info!("Joined track");
+}
+
+/**
+ * The segments running on from a segment without a break, up to the track ends or
+ * junctions either side of it.
+ */
+fn connected_segments(seg_id: Entity, segments: &SegmentQuery) -> Vec<Entity> {
+    let ends: Vec<_> = segments.iter().map(|(id, seg, ..)| (id, seg.from_point, seg.to_point)).collect();
+
+    let mut found = vec![seg_id];
+    let mut stack = vec![seg_id];
+    while let Some(id) = stack.pop() {
+        let Some((_, from, to)) = ends.iter().find(|(other, ..)| *other == id) else { continue; };
+        for point in [*from, *to] {
+            let touching: Vec<_> = ends.iter()
+                .filter(|(_, from, to)| *from == point || *to == point)
+                .map(|(other, ..)| *other)
+                .collect();
+            if touching.len() != 2 { continue; }
+
+            for other in touching {
+                if !found.contains(&other) {
+                    found.push(other);
+                    stack.push(other);
+                }
+            }
+        }
+    }
+
+    found
+}
+
+/**
+ * Turn round a segment and those connected to it, and the trains on them.
+ */
+fn reverse_track(seg_id: Entity, segments: &mut SegmentQuery, trains: &mut TrainQuery) {
+    let connected = connected_segments(seg_id, segments);
+    let mut reversed = Vec::new();
+    for (id, mut seg, curve, ..) in segments.iter_mut() {
+        if !connected.contains(&id) { continue; }
+
+        let seg = &mut *seg;
+        std::mem::swap(&mut seg.from_point, &mut seg.to_point);
+        seg.shape = seg.shape.reversed();
+        reversed.push((id, curve.length()));
+    }
+
+    for (_, mut car) in trains.iter_mut() {
+        if let Some((_, length)) = reversed.iter().find(|(id, _)| *id == car.segment_id) {
+            car.segment_position = length - car.segment_position;
+            car.speed = -car.speed;
+        }
+    }
+    info!("Reversed {} segments", reversed.len());
+}
+
+#[cfg(test)]
+mod test {
+    use bevy::ecs::system::RunSystemOnce;
+    use bevy::prelude::{In, Quat, World};
+
+    use crate::track::earthworks::CorridorProfile;
+    use crate::track::segment::update_segments;
+    use crate::track::{create_track, TrackJoins};
+    use crate::train::create_train;
+
+    use super::*;
+
+    fn split(In(point_id): In<Entity>, points: PointQuery, mut segments: SegmentQuery, mut commands: Commands) {
+        split_at(point_id, &points, &mut segments, &mut commands);
+    }
+
+    fn join(In((end_id, target_id)): In<(Entity, Entity)>, points: PointQuery, mut segments: SegmentQuery, mut trains: TrainQuery, mut commands: Commands) {
+        join_points(end_id, target_id, &points, &mut segments, &mut trains, &mut commands);
+    }
+
+    fn insert(In((segment_id, distance)): In<(Entity, f32)>, mut segments: SegmentQuery, mut trains: TrainQuery, mut commands: Commands) {
+        insert_point(segment_id, distance, &mut segments, &mut trains, &mut commands);
+    }
+
+    fn delete(In(point_id): In<Entity>, mut segments: SegmentQuery, mut trains: TrainQuery, mut commands: Commands) {
+        delete_point(point_id, &mut segments, &mut trains, &mut commands);
+    }
+
+    /** A track bending round to the left, with its points and segments */
+    fn curved_track(world: &mut World) -> (Vec<Entity>, Vec<Entity>) {
+        let positions = [Vec3::ZERO, Vec3::new(100.0, 0.0, 0.0), Vec3::new(180.0, 0.0, -60.0)];
+        let (_, point_ids, segment_ids) = world.run_system_once(move |mut commands: Commands| {
+            create_track("Curved", &positions, false, TrackJoins::default(), &mut commands)
+        }).unwrap();
+        world.run_system_once(update_segments).unwrap();
+        (point_ids, segment_ids)
+    }
+
+    fn spawn_train(world: &mut World, segment_id: Entity, position: f32) -> Entity {
+        world.run_system_once(move |mut commands: Commands| {
+            create_train("Test", segment_id, position, 0.0, &mut commands)
+        }).unwrap()
+    }
+
+    fn train_position(world: &World, train_id: Entity) -> (Entity, f32) {
+        let car = world.get::<TrainCar>(train_id).unwrap();
+        (car.segment_id, car.segment_position)
+    }
+
+    #[test]
+    fn test_delete_point() {
+        let mut world = World::new();
+        let (point_ids, segment_ids) = curved_track(&mut world);
+        let in_curve = world.get::<SegmentCurve>(segment_ids[0]).unwrap().clone();
+        let out_curve = world.get::<SegmentCurve>(segment_ids[1]).unwrap().clone();
+        let first = spawn_train(&mut world, segment_ids[0], 30.0);
+        let second = spawn_train(&mut world, segment_ids[1], 20.0);
+
+        world.run_system_once_with(delete, point_ids[1]).unwrap();
+
+        /* The first segment runs on over the second, which goes along with the point */
+        let merged = world.get::<Segment>(segment_ids[0]).unwrap();
+        assert_eq!((merged.from_point, merged.to_point), (point_ids[0], point_ids[2]));
+        assert_eq!(merged.shape, in_curve.joined(&out_curve));
+        assert!(world.get_entity(segment_ids[1]).is_err());
+        assert!(world.get_entity(point_ids[1]).is_err());
+
+        /* Trains keep their places on the track */
+        assert_eq!(train_position(&world, first), (segment_ids[0], 30.0));
+        assert_eq!(train_position(&world, second), (segment_ids[0], in_curve.length() + 20.0));
+    }
+
+    #[test]
+    fn test_insert_point() {
+        let mut world = World::new();
+        let (point_ids, segment_ids) = curved_track(&mut world);
+        let profile = CorridorProfile { formation_width: 8.0, ..CorridorProfile::default() };
+        world.entity_mut(segment_ids[0]).insert(Earthworks { profile, cut_volume: 10.0, fill_volume: 20.0 });
+        let curve = world.get::<SegmentCurve>(segment_ids[0]).unwrap().clone();
+        let before = spawn_train(&mut world, segment_ids[0], 20.0);
+        let beyond = spawn_train(&mut world, segment_ids[0], 70.0);
+        let next = spawn_train(&mut world, segment_ids[1], 10.0);
+
+        world.run_system_once_with(insert, (segment_ids[0], 50.0)).unwrap();
+
+        /* The segment ends at the new point, and a new one carries on from there */
+        let (first_shape, second_shape) = curve.split(50.0);
+        let first = world.get::<Segment>(segment_ids[0]).unwrap();
+        let point_id = first.to_point;
+        assert_eq!(first.from_point, point_ids[0]);
+        assert_eq!(first.shape, first_shape);
+        assert!(world.get::<Transform>(point_id).unwrap().translation.distance(curve.position_at(50.0)) < 1e-3);
+
+        let (new_id, new_segment) = world.query::<(Entity, &Segment)>().iter(&world)
+            .find(|(_, seg)| seg.from_point == point_id)
+            .unwrap();
+        assert_eq!(new_segment.to_point, point_ids[1]);
+        assert_eq!(new_segment.shape, second_shape);
+
+        /* The new segment has the same earthworks, yet to be dug */
+        let earthworks = world.get::<Earthworks>(new_id).unwrap();
+        assert_eq!(earthworks.profile.formation_width, 8.0);
+        assert_eq!(earthworks.cut_volume, 0.0);
+
+        /* Trains past the new point move onto the new segment */
+        assert_eq!(train_position(&world, before), (segment_ids[0], 20.0));
+        assert_eq!(train_position(&world, beyond), (new_id, 20.0));
+        assert_eq!(train_position(&world, next), (segment_ids[1], 10.0));
+    }
+
+    #[test]
+    fn test_join_after_split() {
+        let mut world = World::new();
+        let positions = [0.0, 100.0, 200.0, 300.0].map(|x| Vec3::new(x, 0.0, 0.0));
+        let (_, point_ids, segment_ids) = world.run_system_once(move |mut commands: Commands| {
+            create_track("Split", &positions, false, TrackJoins::default(), &mut commands)
+        }).unwrap();
+        let other = [Vec3::new(200.0, 0.0, 50.0), Vec3::new(200.0, 0.0, 150.0)];
+        let (_, other_ids, _) = world.run_system_once(move |mut commands: Commands| {
+            create_track("Other", &other, false, TrackJoins::default(), &mut commands)
+        }).unwrap();
+        world.run_system_once(update_segments).unwrap();
+
+        /* The point joined to faces back along -x, against the split track */
+        world.get_mut::<Transform>(other_ids[0]).unwrap().rotation = Quat::from_rotation_y(std::f32::consts::FRAC_PI_2);
+
+        /* Split at the third point, and join the end of the first half to the other track */
+        world.run_system_once_with(split, point_ids[2]).unwrap();
+        let last = segment_ids[2];
+        let last_before = (world.get::<Segment>(last).unwrap().from_point, world.get::<Segment>(last).unwrap().to_point);
+        world.run_system_once_with(join, (point_ids[2], other_ids[0])).unwrap();
+
+        /* The first half is turned round onto the other track, and the second is left alone */
+        let first = world.get::<Segment>(segment_ids[0]).unwrap();
+        assert_eq!((first.from_point, first.to_point), (point_ids[1], point_ids[0]));
+        let second = world.get::<Segment>(segment_ids[1]).unwrap();
+        assert_eq!((second.from_point, second.to_point), (other_ids[0], point_ids[1]));
+        let last = world.get::<Segment>(last).unwrap();
+        assert_eq!((last.from_point, last.to_point), last_before);
+        assert_ne!(last.from_point, point_ids[2]);
+    }
+}
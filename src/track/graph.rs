@@ -0,0 +1,394 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use bevy::math::Vec3;
+use bevy::prelude::{Changed, DetectChanges, Entity, Query, Ref, Reflect, RemovedComponents, ResMut, Resource, Transform, With};
+
+use crate::track::point::Point;
+use crate::track::segment::Segment;
+
+/**
+ * Which way along a segment something travels: forward from its first point to its
+ * second, or backward.
+ */
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum TravelDirection {
+    Forward,
+    Backward,
+}
+
+/**
+ * A place on the track, as a distance along a segment from its first point.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Reflect)]
+pub struct TrackPosition {
+    pub segment: Entity,
+    pub distance: f32,
+}
+
+/**
+ * A point, with the segments arriving at it and leaving it.
+ */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TrackNode {
+    pub position: Vec3,
+    pub arriving: Vec<Entity>,
+    pub leaving: Vec<Entity>,
+}
+
+/**
+ * A segment, running from one point to another.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TrackEdge {
+    pub from: Entity,
+    pub to: Entity,
+    pub length: f32,
+}
+
+/**
+ * A way over the track from one position to another, as the segments passed along in
+ * order, with the direction each is travelled in.  The first and last are the segments
+ * the route starts and ends on.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Route {
+    pub from: TrackPosition,
+    pub to: TrackPosition,
+    pub legs: Vec<(Entity, TravelDirection)>,
+    pub length: f32,
+}
+
+/**
+ * How the track's points and segments connect, kept up to date as they change.
+ *
+ * Trains can only pass through a point from a segment arriving at it onto one leaving it,
+ * or back the other way, so routes never turn back on themselves at a junction.  They
+ * may set off in either direction.
+ */
+#[derive(Default, Resource)]
+pub struct TrackGraph {
+    nodes: HashMap<Entity, TrackNode>,
+    edges: HashMap<Entity, TrackEdge>,
+}
+
+/**
+ * A segment entered at one end on the way to the destination, or the destination itself
+ * once reached, in order of distance travelled.  Steps are kept in an arena so the route
+ * to each can be traced back.
+ */
+struct Visit {
+    distance: f32,
+    step: usize,
+    arrived: bool,
+}
+
+impl PartialEq for Visit {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Visit {}
+
+impl PartialOrd for Visit {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Visit {
+    /* Reversed, so the heap gives the nearest first */
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.distance.total_cmp(&self.distance)
+    }
+}
+
+impl TrackGraph {
+    pub fn nodes(&self) -> &HashMap<Entity, TrackNode> {
+        &self.nodes
+    }
+
+    pub fn edges(&self) -> &HashMap<Entity, TrackEdge> {
+        &self.edges
+    }
+
+    pub fn node(&self, point: Entity) -> Option<&TrackNode> {
+        self.nodes.get(&point)
+    }
+
+    pub fn edge(&self, segment: Entity) -> Option<&TrackEdge> {
+        self.edges.get(&segment)
+    }
+
+    pub fn insert_point(&mut self, point: Entity, position: Vec3) {
+        self.nodes.entry(point).or_default().position = position;
+    }
+
+    /**
+     * Remove a point.  Any segments still joined to it are left without it.
+     */
+    pub fn remove_point(&mut self, point: Entity) {
+        self.nodes.remove(&point);
+    }
+
+    pub fn insert_segment(&mut self, segment: Entity, edge: TrackEdge) {
+        self.remove_segment(segment);
+        self.nodes.entry(edge.from).or_default().leaving.push(segment);
+        self.nodes.entry(edge.to).or_default().arriving.push(segment);
+        self.edges.insert(segment, edge);
+    }
+
+    pub fn remove_segment(&mut self, segment: Entity) {
+        let Some(edge) = self.edges.remove(&segment) else { return; };
+        if let Some(node) = self.nodes.get_mut(&edge.from) {
+            node.leaving.retain(|id| *id != segment);
+        }
+        if let Some(node) = self.nodes.get_mut(&edge.to) {
+            node.arriving.retain(|id| *id != segment);
+        }
+    }
+
+    /**
+     * The segments a train can run onto at the end of a segment it is travelling along,
+     * keeping the same direction.
+     */
+    pub fn successors(&self, segment: Entity, direction: TravelDirection) -> &[Entity] {
+        let Some(edge) = self.edges.get(&segment) else { return &[]; };
+        let node = match direction {
+            TravelDirection::Forward => self.nodes.get(&edge.to),
+            TravelDirection::Backward => self.nodes.get(&edge.from),
+        };
+        match (node, direction) {
+            (Some(node), TravelDirection::Forward) => &node.leaving,
+            (Some(node), TravelDirection::Backward) => &node.arriving,
+            (None, _) => &[],
+        }
+    }
+
+    /**
+     * The shortest route between two positions, if one can be reached from the other.
+     */
+    pub fn route(&self, from: TrackPosition, to: TrackPosition) -> Option<Route> {
+        let start_edge = self.edges.get(&from.segment)?;
+        let end_edge = self.edges.get(&to.segment)?;
+
+        /* Straight there along the same segment, which nothing else can beat */
+        if from.segment == to.segment {
+            let direction = if to.distance >= from.distance { TravelDirection::Forward } else { TravelDirection::Backward };
+            return Some(Route { from, to, legs: vec![(from.segment, direction)], length: (to.distance - from.distance).abs() });
+        }
+
+        let mut steps: Vec<(Entity, TravelDirection, Option<usize>)> = Vec::new();
+        let mut heap = BinaryHeap::new();
+        let mut visited = HashSet::new();
+
+        /* Off either end of the segment it starts on */
+        for (direction, distance) in [
+            (TravelDirection::Forward, start_edge.length - from.distance),
+            (TravelDirection::Backward, from.distance),
+        ] {
+            steps.push((from.segment, direction, None));
+            heap.push(Visit { distance, step: steps.len() - 1, arrived: false });
+        }
+
+        while let Some(Visit { distance, step, arrived }) = heap.pop() {
+            if arrived {
+                let mut legs = Vec::new();
+                let mut next = Some(step);
+                while let Some(i) = next {
+                    let (segment, direction, parent) = steps[i];
+                    legs.push((segment, direction));
+                    next = parent;
+                }
+                legs.reverse();
+                return Some(Route { from, to, legs, length: distance });
+            }
+
+            let (segment, direction, _) = steps[step];
+            if !visited.insert((segment, direction)) { continue; }
+
+            for next in self.successors(segment, direction) {
+                steps.push((*next, direction, Some(step)));
+                let step = steps.len() - 1;
+                if *next == to.segment {
+                    let remaining = match direction {
+                        TravelDirection::Forward => to.distance,
+                        TravelDirection::Backward => end_edge.length - to.distance,
+                    };
+                    heap.push(Visit { distance: distance + remaining, step, arrived: true });
+                } else if let Some(edge) = self.edges.get(next) {
+                    heap.push(Visit { distance: distance + edge.length, step, arrived: false });
+                }
+            }
+        }
+
+        None
+    }
+
+    /**
+     * The segments that can be reached from a segment, setting off either way along it.
+     */
+    pub fn reachable(&self, segment: Entity) -> HashSet<Entity> {
+        let mut reached = HashSet::new();
+        if !self.edges.contains_key(&segment) { return reached; }
+
+        let mut visited = HashSet::new();
+        let mut stack = vec![(segment, TravelDirection::Forward), (segment, TravelDirection::Backward)];
+        reached.insert(segment);
+        while let Some((segment, direction)) = stack.pop() {
+            for next in self.successors(segment, direction) {
+                if visited.insert((*next, direction)) {
+                    reached.insert(*next);
+                    stack.push((*next, direction));
+                }
+            }
+        }
+
+        reached
+    }
+
+    pub fn is_reachable(&self, from: TrackPosition, to: TrackPosition) -> bool {
+        self.route(from, to).is_some()
+    }
+}
+
+impl Route {
+    /**
+     * Distance along the route to a position on it, or `None` if the route doesn't pass
+     * through it.
+     */
+    pub fn distance_along(&self, position: TrackPosition, graph: &TrackGraph) -> Option<f32> {
+        let mut travelled = 0.0;
+        let last = self.legs.len().saturating_sub(1);
+        for (i, (segment, direction)) in self.legs.iter().enumerate() {
+            let length = graph.edge(*segment)?.length;
+            let (start, end) = match direction {
+                TravelDirection::Forward => (0.0, length),
+                TravelDirection::Backward => (length, 0.0),
+            };
+            let start = if i == 0 { self.from.distance } else { start };
+            let end = if i == last { self.to.distance } else { end };
+
+            if position.segment == *segment && position.distance >= start.min(end) && position.distance <= start.max(end) {
+                return Some(travelled + (position.distance - start).abs());
+            }
+            travelled += (end - start).abs();
+        }
+
+        None
+    }
+
+    /**
+     * Distance left to travel from a position on one of the route's segments to its end.
+     * The position may be behind the start of the route, but `None` is given if the route
+     * doesn't use its segment or it is past the end.
+     */
+    pub fn remaining(&self, position: TrackPosition, graph: &TrackGraph) -> Option<f32> {
+        let index = self.legs.iter().position(|(segment, _)| *segment == position.segment)?;
+        let last = self.legs.len() - 1;
+
+        let mut remaining = 0.0;
+        for (i, (segment, direction)) in self.legs.iter().enumerate().skip(index) {
+            let length = graph.edge(*segment)?.length;
+            let (start, end) = match direction {
+                TravelDirection::Forward => (0.0, length),
+                TravelDirection::Backward => (length, 0.0),
+            };
+            let start = if i == index { position.distance } else { start };
+            let end = if i == last { self.to.distance } else { end };
+            remaining += match direction {
+                TravelDirection::Forward => end - start,
+                TravelDirection::Backward => start - end,
+            };
+        }
+
+        (remaining >= 0.0).then_some(remaining)
+    }
+}
+
+/**
+ * Bring the graph up to date with points that have moved and segments that have changed
+ * or been removed.
+ */
+pub fn update_track_graph(
+    points: Query<(Entity, Ref<Transform>), With<Point>>,
+    changed_segments: Query<(Entity, &Segment), Changed<Segment>>,
+    mut removed_points: RemovedComponents<Point>,
+    mut removed_segments: RemovedComponents<Segment>,
+    mut graph: ResMut<TrackGraph>,
+) {
+    for segment_id in removed_segments.read() {
+        graph.remove_segment(segment_id);
+    }
+    for point_id in removed_points.read() {
+        graph.remove_point(point_id);
+    }
+
+    for (point_id, transform) in points.iter() {
+        if transform.is_changed() || !graph.nodes.contains_key(&point_id) {
+            graph.insert_point(point_id, transform.translation);
+        }
+    }
+
+    for (segment_id, segment) in changed_segments.iter() {
+        let edge = TrackEdge { from: segment.from_point, to: segment.to_point, length: segment.length };
+        if graph.edge(segment_id) != Some(&edge) {
+            graph.insert_segment(segment_id, edge);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pos(segment: Entity, distance: f32) -> TrackPosition {
+        TrackPosition { segment, distance }
+    }
+
+    #[test]
+    fn test_route() {
+        /*
+         * A main line a -> b -> c, with a siding leaving b that ends at d, and a spur
+         * arriving at b from e, which can only be reached from the main line by going
+         * backward from b.
+         */
+        let [a, b, c, d, e] = [1, 2, 3, 4, 5].map(Entity::from_raw);
+        let [ab, bc, bd, eb] = [11, 12, 13, 14].map(Entity::from_raw);
+        let mut graph = TrackGraph::default();
+        graph.insert_segment(ab, TrackEdge { from: a, to: b, length: 100.0 });
+        graph.insert_segment(bc, TrackEdge { from: b, to: c, length: 50.0 });
+        graph.insert_segment(bd, TrackEdge { from: b, to: d, length: 30.0 });
+        graph.insert_segment(eb, TrackEdge { from: e, to: b, length: 20.0 });
+        assert_eq!(graph.successors(ab, TravelDirection::Forward), [bc, bd]);
+
+        let route = graph.route(pos(ab, 40.0), pos(bd, 10.0)).unwrap();
+        assert_eq!(route.legs, [(ab, TravelDirection::Forward), (bd, TravelDirection::Forward)]);
+        assert_eq!(route.length, 70.0);
+        assert_eq!(route.distance_along(pos(ab, 90.0), &graph), Some(50.0));
+        assert_eq!(route.distance_along(pos(bc, 10.0), &graph), None);
+        assert_eq!(route.remaining(pos(ab, 30.0), &graph), Some(80.0));
+        assert_eq!(route.remaining(pos(bd, 4.0), &graph), Some(6.0));
+        assert_eq!(route.remaining(pos(bd, 12.0), &graph), None);
+        assert_eq!(route.remaining(pos(bc, 10.0), &graph), None);
+
+        /* From the siding to the spur, back through the junction and out again */
+        let route = graph.route(pos(bd, 10.0), pos(eb, 5.0)).unwrap();
+        assert_eq!(route.legs, [(bd, TravelDirection::Backward), (eb, TravelDirection::Backward)]);
+        assert_eq!(route.length, 25.0);
+
+        /* The siding and the branch can't be run between without reversing at b */
+        assert!(!graph.is_reachable(pos(bd, 10.0), pos(bc, 10.0)));
+        assert_eq!(graph.reachable(bd), HashSet::from([bd, ab, eb]));
+
+        /* Along the same segment */
+        let route = graph.route(pos(ab, 40.0), pos(ab, 10.0)).unwrap();
+        assert_eq!(route.legs, [(ab, TravelDirection::Backward)]);
+        assert_eq!(route.length, 30.0);
+
+        graph.remove_segment(bd);
+        assert!(graph.route(pos(ab, 40.0), pos(bd, 10.0)).is_none());
+        assert_eq!(graph.successors(ab, TravelDirection::Forward), [bc]);
+    }
+}
@@ -0,0 +1,188 @@
+use bevy::color::Color;
+use bevy::input::ButtonInput;
+use bevy::log::{info, warn};
+use bevy::math::{Vec3, Vec3Swizzles};
+use bevy::prelude::{ChildOf, Commands, Entity, Gizmos, Isometry3d, KeyCode, MouseButton, Quat, Query, Res, ResMut, Resource, Single, Transform, With};
+
+use crate::level::selection::SelectedPoint;
+use crate::level::LevelLabel;
+use crate::track::curve::{smooth_shapes_between, SegmentCurve};
+use crate::track::earthworks::Earthworks;
+use crate::track::point::Point;
+use crate::track::rendering::CURVE_STEP;
+use crate::track::validation::{curve_colour, TrackLimits};
+use crate::track::{create_shaped_track, TrackJoins, TRACK_HEIGHT};
+
+/**
+ * How close a click has to be to an existing point to join the new track to it.
+ */
+const SNAP_RADIUS: f32 = 4.0;
+
+/**
+ * Clicks closer than this to the last point placed are ignored.
+ */
+const MIN_SPACING: f32 = 1.0;
+
+const PREVIEW_COLOUR: Color = Color::srgb(1.0, 0.8, 0.2);
+
+/**
+ * Track being laid with the track creation tool, click by click.
+ */
+#[derive(Default, Resource)]
+pub struct TrackLaying {
+    pub points: Vec<Vec3>,
+    /** Existing point the track started from. */
+    pub start: Option<Entity>,
+    pub tracks_laid: usize,
+}
+
+/**
+ * Place points with left clicks, snapping to existing points within reach.  The track is
+ * finished with Esc or a right click, or by clicking on an existing point to join it,
+ * unless the track there runs the opposite way to the track it started from.
+ * Esc is used up here while a track is being laid, so it doesn't also leave the level.
+ */
+pub fn lay_track(
+    buttons: Res<ButtonInput<MouseButton>>,
+    mut keys: ResMut<ButtonInput<KeyCode>>,
+    selected_point: Res<SelectedPoint>,
+    mut laying: ResMut<TrackLaying>,
+    points: Query<(Entity, &Transform), With<Point>>,
+    level_id: Single<Entity, With<LevelLabel>>,
+    limits: Res<TrackLimits>,
+    mut gizmos: Gizmos,
+    mut commands: Commands,
+) {
+    let snap = nearest_point(selected_point.point, &points);
+    let cursor = snap.map_or(selected_point.point, |(_, pos)| pos);
+
+    if buttons.just_pressed(MouseButton::Left) {
+        let far_enough = laying.points.last().is_none_or(|last| last.distance(cursor) >= MIN_SPACING);
+        if laying.points.is_empty() {
+            laying.start = snap.map(|(id, _)| id);
+            laying.points.push(cursor);
+        } else if far_enough {
+            laying.points.push(cursor);
+            if let Some((end_id, _)) = snap {
+                let joins = TrackJoins { start: laying.start, end: Some(end_id) };
+                if needs_reversing(&laying.points, joins, &points).is_some() {
+                    finish_track(&mut laying, Some(end_id), &points, *level_id, &mut commands);
+                    return;
+                }
+                warn!("Can't join tracks running in opposite directions");
+                laying.points.pop();
+            }
+        }
+    }
+
+    if laying.points.is_empty() { return; }
+
+    let finish = buttons.just_pressed(MouseButton::Right) || keys.just_pressed(KeyCode::Escape);
+    if finish {
+        keys.clear_just_pressed(KeyCode::Escape);
+        finish_track(&mut laying, None, &points, *level_id, &mut commands);
+        return;
+    }
+
+    /* Preview the track so far, and the next segment to the cursor, in red where it breaks the limits */
+    let mut preview = laying.points.clone();
+    if preview.last().is_some_and(|last| last.distance(cursor) >= MIN_SPACING) {
+        preview.push(cursor);
+    }
+    let joins = TrackJoins { start: laying.start, end: None };
+    let (start_direction, _) = join_directions(&preview, joins, &points);
+    for (w, shape) in preview.windows(2).zip(smooth_shapes_between(&preview, false, start_direction, None)) {
+        let curve = SegmentCurve::new(w[0], w[1], shape);
+        let positions = curve.path(CURVE_STEP).into_iter().map(|frame| frame.translation + Vec3::Y * TRACK_HEIGHT);
+        gizmos.linestrip(positions, curve_colour(&curve, &limits, PREVIEW_COLOUR));
+    }
+    if let Some((_, pos)) = snap {
+        gizmos.circle(Isometry3d::new(pos + Vec3::Y * TRACK_HEIGHT, Quat::from_rotation_x(std::f32::consts::FRAC_PI_2)), SNAP_RADIUS / 2.0, PREVIEW_COLOUR);
+    }
+}
+
+/**
+ * Forget any track that was part way through being laid.
+ */
+pub fn cancel_laying(mut laying: ResMut<TrackLaying>) {
+    laying.points.clear();
+    laying.start = None;
+}
+
+fn nearest_point(pos: Vec3, points: &Query<(Entity, &Transform), With<Point>>) -> Option<(Entity, Vec3)> {
+    points.iter()
+        .map(|(id, transform)| (id, transform.translation))
+        .filter(|(_, pt)| pt.xz().distance(pos.xz()) < SNAP_RADIUS)
+        .min_by(|a, b| a.1.xz().distance(pos.xz()).total_cmp(&b.1.xz().distance(pos.xz())))
+}
+
+/**
+ * A point faces forward along the track through it.
+ */
+fn forward(id: Option<Entity>, points: &Query<(Entity, &Transform), With<Point>>) -> Option<Vec3> {
+    id.and_then(|id| points.get(id).ok()).map(|(_, t)| t.forward().as_vec3())
+}
+
+/**
+ * Whether track laid through some positions has to be reversed to run the same way as
+ * the tracks it joins, so trains can run from one onto the other.  There is no way round
+ * if it joins tracks running in opposite directions.
+ */
+fn needs_reversing(positions: &[Vec3], joins: TrackJoins, points: &Query<(Entity, &Transform), With<Point>>) -> Option<bool> {
+    let n = positions.len();
+    if n < 2 { return Some(false); }
+
+    let start_backward = forward(joins.start, points).map(|f| f.dot(positions[1] - positions[0]) < 0.0);
+    let end_backward = forward(joins.end, points).map(|f| f.dot(positions[n - 1] - positions[n - 2]) < 0.0);
+    match (start_backward, end_backward) {
+        (Some(start), Some(end)) if start != end => None,
+        (start, end) => Some(start.or(end).unwrap_or(false)),
+    }
+}
+
+/**
+ * Directions the laid track leaves its first point and arrives at its last, carrying on
+ * along the tracks it joins there.
+ */
+fn join_directions(positions: &[Vec3], joins: TrackJoins, points: &Query<(Entity, &Transform), With<Point>>) -> (Option<Vec3>, Option<Vec3>) {
+    let n = positions.len();
+    if n < 2 { return (None, None); }
+
+    let along = |f: Vec3, towards: Vec3| if f.dot(towards) < 0.0 { -f } else { f };
+    (
+        forward(joins.start, points).map(|f| along(f, positions[1] - positions[0])),
+        forward(joins.end, points).map(|f| along(f, positions[n - 1] - positions[n - 2])),
+    )
+}
+
+/**
+ * Create the laid track, if it has at least one segment.  It is made to run the same way
+ * as the track it joins, and to carry on smoothly from it.
+ */
+fn finish_track(
+    laying: &mut TrackLaying,
+    end: Option<Entity>,
+    points: &Query<(Entity, &Transform), With<Point>>,
+    level_id: Entity,
+    commands: &mut Commands,
+) {
+    let mut positions = std::mem::take(&mut laying.points);
+    let mut joins = TrackJoins { start: laying.start.take(), end };
+    if positions.len() < 2 { return; }
+
+    if needs_reversing(&positions, joins, points).unwrap_or(false) {
+        positions.reverse();
+        joins = TrackJoins { start: joins.end, end: joins.start };
+    }
+    let (start_direction, end_direction) = join_directions(&positions, joins, points);
+    let shapes = smooth_shapes_between(&positions, false, start_direction, end_direction);
+
+    laying.tracks_laid += 1;
+    let name = format!("Laid {}", laying.tracks_laid);
+    let (track_id, _, segment_ids) = create_shaped_track(&name, &positions, &shapes, false, joins, commands);
+    for segment_id in segment_ids {
+        commands.entity(segment_id).insert(Earthworks::default());
+    }
+    commands.entity(track_id).insert(ChildOf(level_id));
+    info!("Laid track {name} with {} points", positions.len());
+}
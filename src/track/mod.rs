@@ -1,14 +1,29 @@
 use bevy::log::info;
-use bevy::math::Vec3;
-use bevy::prelude::{App, ChildOf, Commands, Entity, IntoScheduleConfigs, Name, Plugin, PostUpdate, Startup, Transform, Update, Visibility};
+use bevy::math::{Vec2, Vec3, Vec3Swizzles};
+use bevy::asset::AssetApp;
+use bevy::prelude::{App, ChildOf, Commands, Entity, IntoScheduleConfigs, Name, Plugin, PostUpdate, ResMut, Resource, Single, Transform, Update, Visibility, With};
 
+use crate::level::LevelLabel;
+use crate::terrain::utils::Range2;
+use crate::terrain::TerrainData;
+use crate::track::curve::{smooth_shapes, SegmentCurve, SegmentShape};
 use crate::track::point::Point;
 use crate::track::segment::{Segment, SegmentLinkage};
 
 pub mod bridge;
+pub mod curve;
+pub mod earthworks;
+pub mod editing;
+pub mod graph;
+pub mod laying;
 pub mod point;
 pub mod rendering;
 pub mod segment;
+pub mod station;
+pub mod style;
+pub mod switch;
+pub mod tunnel;
+pub mod validation;
 
 /**
  * Height of rail surface above ground level.
@@ -17,6 +32,11 @@
  */
 pub const TRACK_HEIGHT: f32 = 0.5;
 
+/**
+ * How far from a segment the terrain is looked at in finding its bridges and tunnels.
+ */
+const TERRAIN_REACH: f32 = 2.0;
+
 #[derive(Default)]
 pub struct TrackPlugin;
 
@@ -26,23 +46,64 @@ fn build(&self, app: &mut App) {
             .register_type::<Point>()
             .register_type::<Segment>()
             .register_type::<SegmentLinkage>()
-            .add_systems(Startup, rendering::init_render_params)
+            .register_type::<curve::SegmentShape>()
+            .register_type::<switch::Switch>()
+            .register_type::<validation::TrackLimits>()
+            .init_resource::<validation::TrackLimits>()
+            .init_resource::<validation::TrackValidation>()
+            .init_resource::<graph::TrackGraph>()
+            .init_resource::<TerrainChanges>()
+            .register_type::<earthworks::Earthworks>()
+            .init_asset::<style::TrackStyle>()
+            .init_asset_loader::<style::TrackStyleLoader>()
+            .init_resource::<rendering::TrackRenderParams>()
             .add_systems(Update, (
                 point::move_points,
                 segment::update_segments,
                 point::update_point_angles,
                 segment::update_segment_linkage
             ).chain())
-            .add_systems(PostUpdate, rendering::update_track_meshes);
+            .add_systems(Update, earthworks::update_earthworks.after(segment::update_segments))
+            .add_systems(Update, collect_terrain_changes.after(earthworks::update_earthworks).before(bridge::detect_bridges))
+            .add_systems(Update, graph::update_track_graph.after(segment::update_segments))
+            .add_systems(Update, validation::validate_track.after(segment::update_segment_linkage))
+            .add_systems(PostUpdate, (rendering::update_track_meshes, rendering::update_switch_meshes));
 
         app.add_plugins(bridge::BridgePlugin);
+        app.add_plugins(tunnel::TunnelPlugin);
+        app.add_plugins(station::StationPlugin);
     }
 }
 
+/**
+ * Existing points a new track starts or ends at, joining it to the track already there.
+ * The positions given for the new track should include these points.
+ */
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TrackJoins {
+    pub start: Option<Entity>,
+    pub end: Option<Entity>,
+}
+
 pub fn create_track(
     name: &str,
     points: &[Vec3],
     looped: bool,
+    joins: TrackJoins,
+    commands: &mut Commands
+) -> (Entity, Vec<Entity>, Vec<Entity>) {
+    create_shaped_track(name, points, &smooth_shapes(points, looped), looped, joins, commands)
+}
+
+/**
+ * Create a track whose segments have the given shapes, rather than smoothed ones.
+ */
+pub fn create_shaped_track(
+    name: &str,
+    points: &[Vec3],
+    shapes: &[SegmentShape],
+    looped: bool,
+    joins: TrackJoins,
     commands: &mut Commands
 ) -> (Entity, Vec<Entity>, Vec<Entity>) {
     let parent_id = commands
@@ -52,24 +113,30 @@ pub fn create_track(
             Transform::default(),
         )).id();
 
-    let mut point_ids = points.iter()
-        .map(|pt| commands.spawn((
-            Point,
-            Transform::from_translation(*pt),
-            ChildOf(parent_id)
-        )).id())
+    let last = points.len() - 1;
+    let mut point_ids = points.iter().enumerate()
+        .map(|(i, pt)| match (i, joins) {
+            (0, TrackJoins { start: Some(id), .. }) => id,
+            (i, TrackJoins { end: Some(id), .. }) if i == last => id,
+            _ => commands.spawn((
+                Point,
+                Transform::from_translation(*pt),
+                ChildOf(parent_id)
+            )).id(),
+        })
         .collect::<Vec<_>>();
 
     if looped {
         point_ids.push(point_ids[0]);
     }
 
-    let segment_ids: Vec<_> = point_ids.windows(2).map(|w| {
+    let segment_ids: Vec<_> = point_ids.windows(2).zip(shapes).map(|(w, shape)| {
         let [pt1, pt2, ..] = w else { panic!("Expect window of size 2") };
         commands.spawn((
             Segment {
                 from_point: *pt1,
                 to_point: *pt2,
+                shape: *shape,
                 length: 0.0,
                 rendered_id: None,
             },
@@ -80,3 +147,43 @@ pub fn create_track(
 
     (parent_id, point_ids, segment_ids)
 }
+
+/**
+ * Ranges of the terrain whose height has changed since the last update, so the track over
+ * them can be checked for bridges and tunnels again.
+ */
+#[derive(Default, Resource)]
+pub struct TerrainChanges(pub Vec<Range2>);
+
+impl TerrainChanges {
+    /**
+     * Whether the terrain a curve is laid over has changed.
+     */
+    pub fn under(&self, curve: &SegmentCurve) -> bool {
+        if self.0.is_empty() { return false; }
+
+        let path = curve.path(rendering::CURVE_STEP);
+        let positions = path.iter().map(|frame| frame.translation.xz());
+        let min = positions.clone().fold(Vec2::MAX, Vec2::min) - Vec2::splat(TERRAIN_REACH);
+        let max = positions.fold(Vec2::MIN, Vec2::max) + Vec2::splat(TERRAIN_REACH);
+        let range = Range2(
+            min.y.floor().max(0.0) as usize..max.y.ceil().max(0.0) as usize + 1,
+            min.x.floor().max(0.0) as usize..max.x.ceil().max(0.0) as usize + 1,
+        );
+        self.0.iter().any(|changed| changed.overlaps(&range))
+    }
+}
+
+fn collect_terrain_changes(
+    terrain_data: Option<Single<&mut TerrainData, With<LevelLabel>>>,
+    mut changes: ResMut<TerrainChanges>,
+) {
+    changes.0.clear();
+    let Some(mut terrain_data) = terrain_data else { return; };
+    if !terrain_data.block_info.iter().any(|bi| bi.track_dirty) { return; }
+
+    for bi in terrain_data.block_info.iter_mut().filter(|bi| bi.track_dirty) {
+        bi.track_dirty = false;
+        changes.0.push(bi.range.clone());
+    }
+}
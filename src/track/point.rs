@@ -3,6 +3,7 @@
 use bevy::math::Vec3;
 use bevy::prelude::{info, Changed, Component, DetectChangesMut, Entity, Quat, Query, Reflect, Transform, With, Without};
 
+use crate::track::curve::SegmentCurve;
 use crate::track::segment::Segment;
 
 #[derive(Component, Reflect)]
@@ -26,7 +27,7 @@ pub fn move_points(
 
 pub fn update_point_angles(
     mut points: Query<(Entity, &mut Transform), Changed<Point>>,
-    segments: Query<(&Segment, &Transform), Without<Point>>,
+    segments: Query<(&Segment, &SegmentCurve), Without<Point>>,
 ) {
     let mut angles = HashMap::new();
     for (id, _) in points.iter_mut() {
@@ -37,20 +38,27 @@ pub fn update_point_angles(
 
     info!("Updating angles for {} points", angles.len());
 
-    for (segment, seg_transform) in segments.iter() {
+    /* Segments face back along the track, so this is each curve's backward direction at the point */
+    for (segment, curve) in segments.iter() {
         if angles.contains_key(&segment.to_point) {
-            *(angles.get_mut(&segment.to_point).unwrap()) += seg_transform.forward().as_vec3();
+            *(angles.get_mut(&segment.to_point).unwrap()) -= curve.direction_at(curve.length());
         }
         if angles.contains_key(&segment.from_point) {
-            *(angles.get_mut(&segment.from_point).unwrap()) += seg_transform.forward().as_vec3();
+            *(angles.get_mut(&segment.from_point).unwrap()) -= curve.direction_at(0.0);
         }
     }
 
     for (id, mut transform) in points.iter_mut() {
-        let angle = angles[&id].normalize();
-        let new_rotation = Quat::from_rotation_arc(Vec3::Z, angle);
+        let new_rotation = point_rotation(angles[&id]);
         if transform.rotation != new_rotation {
             transform.rotation = new_rotation;
         }
     }
 }
+
+/**
+ * Rotation of a point, given the sum of the forward directions of the segments it joins.
+ */
+pub fn point_rotation(directions: Vec3) -> Quat {
+    Quat::from_rotation_arc(Vec3::Z, directions.normalize())
+}
@@ -1,89 +1,160 @@
+use std::collections::{HashMap, HashSet};
+
 use bevy::prelude::*;
 use bevy::render::mesh::{Indices, PrimitiveTopology, VertexAttributeValues};
 use bevy::render::render_asset::RenderAssetUsages;
+use bevy::render::view::VisibilityRange;
 
+use crate::track::curve::SegmentCurve;
 use crate::track::point::Point;
 use crate::track::segment::{Segment, SegmentLinkage};
+use crate::track::style::{TrackStyle, TrackStyleHandle};
+use crate::track::switch::{order_branches, Switch};
 
 /**
- * Tracks are rendered with:
- *    - rail height
- *    - sleeper height
- *    - bed height
- *
- * These fill up the space between ground level and RAIL_HEIGHT.
- * The bed height is expanded below ground level to fully occupy dips in the terrain.
+ * Longest stretch of a curve rendered as one straight piece.
+ */
+pub const CURVE_STEP: f32 = 2.0;
+
+/**
+ * Switch blades run this far from the switch, and the two positions they lie in are this
+ * far from the inside of the stock rails.
+ */
+const BLADE_LENGTH: f32 = 8.0;
+const BLADE_CLOSED: f32 = 0.06;
+const BLADE_OPEN: f32 = 0.18;
+const BLADE_WIDTH: f32 = 0.06;
+
+/**
+ * Furthest from the camera that sleepers, and rails, are drawn.  Beyond that only the
+ * bed of the track is seen.
  */
-#[derive(Resource)]
+const SLEEPER_DISTANCE: f32 = 150.0;
+const RAIL_DISTANCE: f32 = 400.0;
+
+/**
+ * How far the frog's legs run past the point where the rails cross.
+ */
+const FROG_LENGTH: f32 = 2.0;
+
+/**
+ * Materials for the parts of the track in one style.
+ */
+struct StyleMaterials {
+    rail: Handle<StandardMaterial>,
+    sleeper: Handle<StandardMaterial>,
+    bed: Handle<StandardMaterial>,
+}
+
+impl StyleMaterials {
+    fn new(style: &TrackStyle, materials: &mut Assets<StandardMaterial>) -> Self {
+        StyleMaterials {
+            rail: materials.add(style.rail_material.material()),
+            sleeper: materials.add(style.sleeper_material.material()),
+            bed: materials.add(style.bed_material.material()),
+        }
+    }
+}
+
+/**
+ * The default style, and the materials for each style in use, which is `None` for the
+ * default.
+ */
+#[derive(Default, Resource)]
 pub struct TrackRenderParams {
-    rail_material: Handle<StandardMaterial>,
-    rail_height: f32,
-    rail_profile: Vec<Vec2>,
-    sleeper_material: Handle<StandardMaterial>,
-    sleeper_dims: Vec3,
-    sleeper_height: f32,
-    sleeper_spacing: f32,
-    sleeper_mesh: Handle<Mesh>,
-    bed_material: Handle<StandardMaterial>,
-    bed_profile: Vec<Vec2>,
-}
-
-pub fn init_render_params(
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
-    mut commands: Commands,
-) {
-    let mut rail_material = StandardMaterial::from(Color::srgb(0.8, 0.8, 0.8));
-    rail_material.metallic = 0.8;
-
-    let rail_points_half = [(-6.5, 0.0), (-6.5, 1.0), (-1.0, 2.0), (-0.75, 11.0), (-3.0, 12.0), (-3.0, 14.0), (-2.0, 15.0)];
-    let rail_points_otherhalf = rail_points_half.iter().rev().map(|(x, y)| (-x, *y));
-    let rail_points = rail_points_half.iter().copied().chain(rail_points_otherhalf);
-    let rail_profile = rail_points.map(|(x, y)| Vec2::new(x * 0.01, y * 0.01));
-
-    let rail_height = 0.2 + 0.15;
-
-    let mut sleeper_material = StandardMaterial::from(Color::srgb(0.5, 0.25, 0.1));
-    sleeper_material.perceptual_roughness = 0.7;
-
-    let sleeper_dims = Vec3::new(2.0, 0.15, 0.2);
-    let sleeper_height = 0.2;
-    let sleeper_spacing = 0.7;
-
-    let sleeper_mesh = create_sleeper_mesh(sleeper_dims);
-    let sleeper_mesh = meshes.add(sleeper_mesh);
-
-    let mut bed_material = StandardMaterial::from(Color::srgb(0.6, 0.6, 0.5));
-    bed_material.perceptual_roughness = 0.5;
-
-    let bed_points_half = [(-2.5, -0.3), (-1.5, 0.2)];
-    let bed_points_otherhalf = bed_points_half.iter().rev().map(|(x, y)| (-x, *y));
-    let bed_points = bed_points_half.iter().copied().chain(bed_points_otherhalf);
-    let bed_profile = bed_points.map(|(x, y)| Vec2::new(x, y));
-
-    let params = TrackRenderParams {
-        rail_material: materials.add(rail_material),
-        rail_profile: rail_profile.collect(),
-        rail_height,
-        sleeper_material: materials.add(sleeper_material),
-        sleeper_dims,
-        sleeper_height,
-        sleeper_spacing,
-        sleeper_mesh,
-        bed_material: materials.add(bed_material),
-        bed_profile: bed_profile.collect(),
-    };
-    commands.insert_resource(params);
+    default_style: TrackStyle,
+    materials: HashMap<Option<AssetId<TrackStyle>>, StyleMaterials>,
+}
+
+/**
+ * The style of the track an entity belongs to, going by its parent.  Tracks whose style
+ * hasn't loaded yet are shown in the default style until it has.
+ */
+pub(crate) fn track_style<'a>(
+    parent: Option<&ChildOf>,
+    tracks: &Query<&TrackStyleHandle>,
+    styles: &'a Assets<TrackStyle>,
+    default_style: &'a TrackStyle,
+) -> (Option<AssetId<TrackStyle>>, &'a TrackStyle) {
+    parent.and_then(|parent| tracks.get(parent.parent()).ok())
+        .and_then(|handle| styles.get(&handle.0).map(|style| (Some(handle.0.id()), style)))
+        .unwrap_or((None, default_style))
+}
+
+/**
+ * What a segment's track was built from, kept so the track is only rebuilt when its shape
+ * or the way its ends are cut has changed.
+ */
+#[derive(Component, PartialEq)]
+pub struct TrackMeshKey {
+    style: TrackStyle,
+    curve: SegmentCurve,
+    transform: Transform,
+    open_start: bool,
+    open_end: bool,
+    start_normal: Vec3,
+    end_normal: Vec3,
+}
+
+fn rail_visibility(distance: f32) -> VisibilityRange {
+    VisibilityRange { use_aabb: true, ..VisibilityRange::abrupt(0.0, distance) }
 }
 
+/**
+ * Each segment's track is drawn as three meshes: its rails, its sleepers, and its bed.
+ * Segments are looked at again when they, the points at their ends, or any style
+ * change, and rebuilt only if that changed their track.
+ */
 pub fn update_track_meshes(
-    mut segments: Query<(Entity, &mut Segment, &SegmentLinkage, &Transform), Or<(Changed<Segment>, Changed<SegmentLinkage>, Changed<Transform>)>>,
+    mut segments: Query<(Entity, &mut Segment, &SegmentLinkage, &SegmentCurve, &Transform, Option<&ChildOf>, Option<&TrackMeshKey>)>,
+    changed_segments: Query<Entity, (With<Segment>, Or<(Changed<Segment>, Changed<SegmentLinkage>, Changed<Transform>, Changed<ChildOf>)>)>,
+    changed_points: Query<Entity, (With<Point>, Changed<Transform>)>,
     points: Query<&Transform, With<Point>>,
-    params: Res<TrackRenderParams>,
+    tracks: Query<&TrackStyleHandle>,
+    styles: Res<Assets<TrackStyle>>,
+    mut style_events: EventReader<AssetEvent<TrackStyle>>,
+    mut params: ResMut<TrackRenderParams>,
     mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
     mut commands: Commands,
 ) {
-    for (segment_id, mut segment, linkage, segment_transform) in segments.iter_mut() {
+    let TrackRenderParams { default_style, materials: style_materials } = &mut *params;
+
+    let mut any_style_changed = false;
+    for event in style_events.read() {
+        if let AssetEvent::Modified { id } = event {
+            style_materials.remove(&Some(*id));
+        }
+        any_style_changed = true;
+    }
+
+    let mut candidates: HashSet<Entity> = changed_segments.iter().collect();
+    if any_style_changed {
+        candidates.extend(segments.iter().map(|(id, ..)| id));
+    } else if !changed_points.is_empty() {
+        let moved: HashSet<Entity> = changed_points.iter().collect();
+        candidates.extend(segments.iter()
+            .filter(|(_, seg, ..)| moved.contains(&seg.from_point) || moved.contains(&seg.to_point))
+            .map(|(id, ..)| id));
+    }
+
+    for segment_id in candidates {
+        let Ok((_, mut segment, linkage, curve, segment_transform, parent, old_key)) = segments.get_mut(segment_id)
+        else { continue; };
+        let (Ok(from_transform), Ok(to_transform)) = (points.get(segment.from_point), points.get(segment.to_point))
+        else { continue; };
+        let (style_id, style) = track_style(parent, &tracks, &styles, default_style);
+
+        let key = TrackMeshKey {
+            style: style.clone(),
+            curve: curve.clone(),
+            transform: *segment_transform,
+            open_start: linkage.prev_segment.is_some(),
+            open_end: linkage.next_segment.is_some(),
+            start_normal: cut_normal(curve.transform_at(0.0).rotation, from_transform.rotation),
+            end_normal: cut_normal(curve.transform_at(curve.length()).rotation, to_transform.rotation),
+        };
+        if old_key == Some(&key) { continue; }
 
         if let Some(rendered_id) = segment.rendered_id {
             commands.entity(rendered_id).despawn_related::<Children>();
@@ -97,60 +168,262 @@ pub fn update_track_meshes(
         }
         let parent_id = segment.rendered_id.unwrap();
 
-        let open_start = linkage.prev_segment.is_some();
-        let open_end = linkage.next_segment.is_some();
-
-        let point_transform = points.get(segment.from_point).unwrap();
-        let mut transform = Transform::from_rotation(segment_transform.rotation);
-        transform.rotate(point_transform.rotation.inverse());
-        let start_normal = transform.forward().as_vec3();
-
-        let point_transform = points.get(segment.to_point).unwrap();
-        let mut transform = Transform::from_rotation(segment_transform.rotation);
-        transform.rotate(point_transform.rotation.inverse());
-        let end_normal = transform.forward().as_vec3();
+        let path = segment_path(curve, segment_transform);
+        let style_materials = style_materials.entry(style_id)
+            .or_insert_with(|| StyleMaterials::new(style, &mut materials));
 
-        let rail_mesh = create_rail_mesh(&params, segment.length, open_start, open_end, start_normal, end_normal);
+        let rail_mesh = create_rail_mesh(style, &path, key.open_start, key.open_end, key.start_normal, key.end_normal);
         commands.spawn((
             Mesh3d(meshes.add(rail_mesh)),
-            MeshMaterial3d(params.rail_material.clone()),
+            MeshMaterial3d(style_materials.rail.clone()),
+            rail_visibility(RAIL_DISTANCE),
             ChildOf(parent_id)
         ));
 
-        let num_sleepers = f32::round(segment.length / params.sleeper_spacing) as usize;
-        let sleeper_offset = segment.length / (num_sleepers as f32);
-        for i in 0..num_sleepers {
-            let sleeper_transform = Transform::from_xyz(0.0, params.sleeper_height + params.sleeper_dims.y/2.0, sleeper_offset * (i as f32 + 0.5));
+        let frame_at = |distance| to_segment_space(segment_transform, curve.transform_at(distance));
+        let sleepers = sleeper_transforms(style, segment.length, frame_at);
+        if let Some(sleepers_mesh) = create_sleepers_mesh(&create_sleeper_mesh(style.sleeper_dims), &sleepers) {
             commands.spawn((
-                Mesh3d(params.sleeper_mesh.clone()),
-                MeshMaterial3d(params.sleeper_material.clone()),
-                sleeper_transform,
+                Mesh3d(meshes.add(sleepers_mesh)),
+                MeshMaterial3d(style_materials.sleeper.clone()),
+                rail_visibility(SLEEPER_DISTANCE),
                 ChildOf(parent_id)
             ));
         }
 
-        let bed_mesh = create_bed_mesh(&params, segment.length, open_start, open_end, start_normal, end_normal);
+        let bed_mesh = create_bed_mesh(style, &path, key.open_start, key.open_end, key.start_normal, key.end_normal);
         commands.spawn((
             Mesh3d(meshes.add(bed_mesh)),
-            MeshMaterial3d(params.bed_material.clone()),
+            MeshMaterial3d(style_materials.bed.clone()),
             ChildOf(parent_id)
         ));
+
+        commands.entity(segment_id).insert(key);
     }
 }
 
-fn create_rail_mesh(params: &TrackRenderParams, length: f32, open_start: bool, open_end: bool, start_normal: Vec3, end_normal: Vec3) -> Mesh {
-    const GAUGE: f32 = 1.435;
+/**
+ * Switches are drawn with a blade on each side and a frog where the rails of neighbouring
+ * routes cross, over the ordinary rails of the segments leaving them.  Of the two blades,
+ * the left follows the rightmost route and the right the leftmost, and each lies closed
+ * against its stock rail when that route is set.  On dual gauge track, only the widest
+ * gauge has blades and frogs.  Whichever side of the point the routes diverge on, they
+ * are ordered as the segments' linkage orders them, and are redrawn when any of them
+ * moves.
+ */
+pub fn update_switch_meshes(
+    mut switches: Query<(Entity, &mut Switch, Ref<Transform>, Option<&ChildOf>)>,
+    segments: Query<(Ref<Segment>, Ref<SegmentCurve>)>,
+    tracks: Query<&TrackStyleHandle>,
+    styles: Res<Assets<TrackStyle>>,
+    mut style_events: EventReader<AssetEvent<TrackStyle>>,
+    mut params: ResMut<TrackRenderParams>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut commands: Commands,
+) {
+    let TrackRenderParams { default_style, materials: style_materials } = &mut *params;
+
+    let any_style_changed = style_events.read().count() > 0;
 
-    let rail_profile = BoxedPolyline2d::new(params.rail_profile.clone());
-    let verts: Vec<_> = rail_profile.vertices.iter().map(|pt| Vec2::new(pt.x + GAUGE/2.0, pt.y + params.rail_height)).collect::<Vec<_>>();
-    let mut mesh = extrusion(&verts, length, open_start, open_end, start_normal, end_normal);
+    for (point_id, mut switch, point_transform, parent) in switches.iter_mut() {
+        let touching = || segments.iter().filter(move |(seg, _)| seg.from_point == point_id || seg.to_point == point_id);
+        let segment_changed = touching().any(|(seg, curve)| seg.is_changed() || curve.is_changed());
+        if !any_style_changed && !segment_changed && !switch.is_changed() && !point_transform.is_changed() { continue; }
 
-    let verts: Vec<_> = rail_profile.vertices.iter().map(|pt| Vec2::new(pt.x - GAUGE/2.0, pt.y + params.rail_height)).collect::<Vec<_>>();
-    mesh.merge(&extrusion(&verts, length, open_start, open_end, start_normal, end_normal)).expect("mesh.merge");
+        let (style_id, style) = track_style(parent, &tracks, &styles, default_style);
+        let rail_material = style_materials.entry(style_id)
+            .or_insert_with(|| StyleMaterials::new(style, &mut materials))
+            .rail.clone();
+
+        /* Drawn in the track's space, undoing the point's transform */
+        let transform = Transform::from_matrix(point_transform.compute_matrix().inverse());
+        if let Some(rendered_id) = switch.rendered_id {
+            commands.entity(rendered_id).despawn_related::<Children>();
+            commands.entity(rendered_id).insert(transform);
+        } else {
+            switch.rendered_id = Some(commands.spawn((
+                Name::new("Switch"),
+                transform,
+                Visibility::default(),
+                ChildOf(point_id),
+            )).id());
+        }
+        let parent_id = switch.rendered_id.unwrap();
+
+        /* Segments leaving and arriving, each followed away from the point */
+        let leaving: Vec<_> = touching()
+            .filter(|(seg, _)| seg.from_point == point_id)
+            .map(|(_, curve)| (curve.clone(), curve.direction_at(0.0)))
+            .collect();
+        let arriving: Vec<_> = touching()
+            .filter(|(seg, _)| seg.to_point == point_id)
+            .map(|(_, curve)| curve.reversed())
+            .map(|curve| { let direction = curve.direction_at(0.0); (curve, direction) })
+            .collect();
+
+        let through = |branches: &Vec<(SegmentCurve, Vec3)>| -branches.iter().map(|(_, dir)| *dir).sum::<Vec3>();
+        let (leaving_through, arriving_through) = (through(&arriving), through(&leaving));
+        for (mut branches, through) in [(leaving, leaving_through), (arriving, arriving_through)] {
+            if branches.len() < 2 { continue; }
+            order_branches(&mut branches, through);
+            let route = switch.route.min(branches.len() - 1);
+
+            /* Routes from left to right, keeping their route numbers */
+            let through = branches[0].1;
+            let turn = |dir: Vec3| through.cross(dir).y;
+            let mut sides: Vec<_> = branches.iter().enumerate().map(|(i, (curve, dir))| (i, curve, turn(*dir))).collect();
+            sides.sort_by(|a, b| b.2.total_cmp(&a.2));
+            let (leftmost, rightmost) = (sides[0], sides[sides.len() - 1]);
+
+            for ((i, curve, _), side) in [(rightmost, 1.0), (leftmost, -1.0)] {
+                let inset = if i == route { BLADE_CLOSED } else { BLADE_OPEN };
+                let path = curve.path_between(0.0, BLADE_LENGTH.min(curve.length()), CURVE_STEP / 2.0);
+                let blade_mesh = create_blade_mesh(style, &path, side * (style.gauge() / 2.0 - inset));
+                commands.spawn((
+                    Mesh3d(meshes.add(blade_mesh)),
+                    MeshMaterial3d(rail_material.clone()),
+                    rail_visibility(RAIL_DISTANCE),
+                    ChildOf(parent_id)
+                ));
+            }
+
+            for pair in sides.windows(2) {
+                let [(_, left, _), (_, right, _)] = pair else { unreachable!() };
+                if let Some(frog_mesh) = create_frog_mesh(style, left, right) {
+                    commands.spawn((
+                        Mesh3d(meshes.add(frog_mesh)),
+                        MeshMaterial3d(rail_material.clone()),
+                        rail_visibility(RAIL_DISTANCE),
+                        ChildOf(parent_id)
+                    ));
+                }
+            }
+        }
+    }
+}
+
+/**
+ * A switch blade along a path, offset to one side of its centre line.
+ */
+pub fn create_blade_mesh(style: &TrackStyle, path: &[Transform], offset: f32) -> Mesh {
+    let top = style.rail_height + style.rail_profile.iter().map(|pt| pt.y).fold(0.0, f32::max);
+    let profile = [
+        Vec2::new(offset - BLADE_WIDTH / 2.0, style.rail_height),
+        Vec2::new(offset - BLADE_WIDTH / 2.0, top),
+        Vec2::new(offset + BLADE_WIDTH / 2.0, top),
+        Vec2::new(offset + BLADE_WIDTH / 2.0, style.rail_height),
+    ];
+    extrusion(&profile, path, false, false, Vec3::Z, Vec3::Z)
+}
+
+/**
+ * The frog where the right rail of one route crosses the left rail of the route to its
+ * right: a wedge from the crossing back along each rail.  Routes that never draw a gauge
+ * apart have no frog.
+ */
+pub fn create_frog_mesh(style: &TrackStyle, left: &SegmentCurve, right: &SegmentCurve) -> Option<Mesh> {
+    const STEP: f32 = 0.25;
+
+    let max_distance = left.length().min(right.length()) - FROG_LENGTH;
+    let crossing = (0..).map(|i| i as f32 * STEP)
+        .take_while(|d| *d < max_distance)
+        .find(|d| left.position_at(*d).xz().distance(right.position_at(*d).xz()) >= style.gauge())?;
+
+    let rail_at = |curve: &SegmentCurve, distance: f32, side: f32| {
+        curve.transform_at(distance).transform_point(Vec3::X * side * style.gauge() / 2.0)
+    };
+    let apex = (rail_at(left, crossing, -1.0) + rail_at(right, crossing, 1.0)) / 2.0;
+    let left_leg = rail_at(left, crossing + FROG_LENGTH, -1.0);
+    let right_leg = rail_at(right, crossing + FROG_LENGTH, 1.0);
+
+    /* A prism from the top of the sleepers to the top of the rails */
+    let top = style.rail_height + style.rail_profile.iter().map(|pt| pt.y).fold(0.0, f32::max);
+    let [a0, b0, c0] = [apex, left_leg, right_leg].map(|pt| pt + Vec3::Y * style.rail_height);
+    let [a1, b1, c1] = [apex, left_leg, right_leg].map(|pt| pt + Vec3::Y * top);
+
+    let mut tris = Vec::new();
+    for (p, q) in [(a1, b1), (b1, c1), (c1, a1)] {
+        let (p0, q0) = (p - Vec3::Y * (top - style.rail_height), q - Vec3::Y * (top - style.rail_height));
+        tris.extend([p0, p, q0]);
+        tris.extend([p, q, q0]);
+    }
+    tris.extend([a1, c1, b1]);
+    tris.extend([a0, b0, c0]);
+
+    /* Wind each face outward, whichever side of the track the legs ended up */
+    if (b0 - a0).cross(c0 - a0).y > 0.0 {
+        for tri in tris.chunks_mut(3) {
+            tri.swap(1, 2);
+        }
+    }
+
+    Some(Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::default())
+        .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, tris)
+        .with_computed_flat_normals())
+}
+
+/**
+ * A frame along a segment's curve, moved from world space into the segment's space.
+ */
+pub fn to_segment_space(segment_transform: &Transform, frame: Transform) -> Transform {
+    let inverse = segment_transform.rotation.inverse();
+    Transform::from_translation(inverse * (frame.translation - segment_transform.translation))
+        .with_rotation(inverse * frame.rotation)
+}
+
+/**
+ * Frames along a segment's curve to extrude its track along, in the segment's space.
+ */
+pub fn segment_path(curve: &SegmentCurve, segment_transform: &Transform) -> Vec<Transform> {
+    curve.path(CURVE_STEP).into_iter()
+        .map(|frame| to_segment_space(segment_transform, frame))
+        .collect()
+}
+
+/**
+ * Normal of the plane that cuts the end of a segment where it meets a point, in the
+ * space of the segment's frame at that end.
+ */
+pub fn cut_normal(segment_rotation: Quat, point_rotation: Quat) -> Vec3 {
+    let mut transform = Transform::from_rotation(segment_rotation);
+    transform.rotate(point_rotation.inverse());
+    transform.forward().as_vec3()
+}
+
+/**
+ * A rail at each of the style's offsets from the centre line.
+ */
+pub fn create_rail_mesh(style: &TrackStyle, path: &[Transform], open_start: bool, open_end: bool, start_normal: Vec3, end_normal: Vec3) -> Mesh {
+    let rail_profile = BoxedPolyline2d::new(style.rail_profile.clone());
+    let rail = |offset: f32| {
+        let verts: Vec<_> = rail_profile.vertices.iter().map(|pt| Vec2::new(pt.x + offset, pt.y + style.rail_height)).collect();
+        extrusion(&verts, path, open_start, open_end, start_normal, end_normal)
+    };
+
+    /* The shared rail is always first, whatever the gauges */
+    let offsets = style.rail_offsets();
+    let mut mesh = rail(offsets[0]);
+    for offset in &offsets[1..] {
+        mesh.merge(&rail(*offset)).expect("mesh.merge");
+    }
 
     mesh
 }
 
+/**
+ * Where each sleeper goes along a segment, spread evenly along its length.  The frame
+ * function gives the track's position and direction a distance along the segment.
+ */
+pub fn sleeper_transforms(style: &TrackStyle, length: f32, frame_at: impl Fn(f32) -> Transform) -> Vec<Transform> {
+    let num_sleepers = f32::round(length / style.sleeper_spacing) as usize;
+    let sleeper_offset = length / (num_sleepers as f32);
+    (0..num_sleepers)
+        .map(|i| frame_at(sleeper_offset * (i as f32 + 0.5))
+            .mul_transform(Transform::from_xyz(0.0, style.sleeper_height + style.sleeper_dims.y/2.0, 0.0)))
+        .collect()
+}
+
 /**
  * Project a 2D point onto a plane represented by a normal vector.
  * The origin is assumed to be in the plane.
@@ -166,20 +439,38 @@ fn project_point(point: Vec2, plane_normal: Vec3) -> Vec3 {
     ray + t * ray_dest
 }
 
-fn extrusion(profile: &[Vec2], length: f32, open_start: bool, open_end: bool, start_normal: Vec3, end_normal: Vec3) -> Mesh {
-    /* Transform points to be cut according to the plane normal at each end */
-    let start_points: Vec<_> = profile.iter().map(|pt| project_point(*pt, start_normal)).collect();
-    let end_points: Vec<_> = profile.iter().map(|pt| project_point(*pt, end_normal) + length * Vec3::Z).collect();
+/**
+ * Sweep a profile along a path of frames, each with its local z-axis along the track.
+ * The ends are cut by planes with the given normals, in the space of the end frames.
+ */
+pub fn extrusion(profile: &[Vec2], path: &[Transform], open_start: bool, open_end: bool, start_normal: Vec3, end_normal: Vec3) -> Mesh {
+    /* Place the profile at each frame, cut according to the plane normal at each end */
+    let last = path.len() - 1;
+    let rings: Vec<Vec<Vec3>> = path.iter().enumerate().map(|(i, frame)| {
+        profile.iter().map(|pt| {
+            let pt = match i {
+                0 => project_point(*pt, start_normal),
+                i if i == last => project_point(*pt, end_normal),
+                _ => pt.extend(0.0),
+            };
+            frame.transform_point(pt)
+        }).collect()
+    }).collect();
+    let start_points = &rings[0];
+    let end_points = &rings[last];
 
     let mut tris = Vec::new();
-    for i in 0..start_points.len() - 1 {
-        let start0 = start_points[i];
-        let start1 = start_points[i + 1];
-        let end0 = end_points[i];
-        let end1 = end_points[i + 1];
-
-        tris.extend([start0, end0, start1]);
-        tris.extend([start1, end0, end1]);
+    for ring in rings.windows(2) {
+        let [start_ring, end_ring] = ring else { unreachable!() };
+        for i in 0..start_ring.len() - 1 {
+            let start0 = start_ring[i];
+            let start1 = start_ring[i + 1];
+            let end0 = end_ring[i];
+            let end1 = end_ring[i + 1];
+
+            tris.extend([start0, end0, start1]);
+            tris.extend([start1, end0, end1]);
+        }
     }
 
     /* Close ends if necessary */
@@ -207,12 +498,24 @@ fn extrusion(profile: &[Vec2], length: f32, open_start: bool, open_end: bool, st
         .with_computed_flat_normals()
 }
 
-fn create_bed_mesh(params: &TrackRenderParams, length: f32, open_start: bool, open_end: bool, start_normal: Vec3, end_normal: Vec3) -> Mesh {
-    let bed_profile = BoxedPolyline2d::new(params.bed_profile.clone());
-    extrusion(&bed_profile.vertices, length, open_start, open_end, start_normal, end_normal)
+pub fn create_bed_mesh(style: &TrackStyle, path: &[Transform], open_start: bool, open_end: bool, start_normal: Vec3, end_normal: Vec3) -> Mesh {
+    let bed_profile = BoxedPolyline2d::new(style.bed_profile.clone());
+    extrusion(&bed_profile.vertices, path, open_start, open_end, start_normal, end_normal)
+}
+
+/**
+ * A copy of the sleeper mesh at each of the transforms, all in one mesh.
+ */
+pub fn create_sleepers_mesh(sleeper_mesh: &Mesh, transforms: &[Transform]) -> Option<Mesh> {
+    let (first, rest) = transforms.split_first()?;
+    let mut mesh = sleeper_mesh.clone().transformed_by(*first);
+    for transform in rest {
+        mesh.merge(&sleeper_mesh.clone().transformed_by(*transform)).expect("mesh.merge");
+    }
+    Some(mesh)
 }
 
-fn create_sleeper_mesh(sleeper_dims: Vec3) -> Mesh {
+pub fn create_sleeper_mesh(sleeper_dims: Vec3) -> Mesh {
     let mut mesh: Mesh = Cuboid::from_size(sleeper_dims).into();
 
     /* Remove bottom face */
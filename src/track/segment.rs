@@ -1,64 +1,145 @@
 use std::collections::HashMap;
 
 use bevy::log::info;
-use bevy::prelude::{Changed, Component, DetectChanges, DetectChangesMut, Entity, Or, Query, Ref, Reflect, Transform, Vec3, Visibility, Without};
+use bevy::prelude::{Changed, Commands, Component, DetectChanges, DetectChangesMut, Entity, Or, Query, Ref, Reflect, Transform, Vec3, Visibility, Without};
 
+use crate::track::curve::{SegmentCurve, SegmentShape};
 use crate::track::point::Point;
+use crate::track::switch::{order_branches, Switch};
 
+/**
+ * A piece of track between two points.  Its length is measured along its curve, which
+ * is what trains use to follow it.
+ */
 #[derive(Component, Reflect)]
-#[require(SegmentLinkage, Transform, Visibility)]
+#[require(SegmentLinkage, SegmentCurve, Transform, Visibility)]
 pub struct Segment {
     pub from_point: Entity,
     pub to_point: Entity,
+    pub shape: SegmentShape,
     pub length: f32,
     pub rendered_id: Option<Entity>,
 }
 
-#[derive(Component, Default, Reflect)]
+/**
+ * How a segment joins the track at each end.  At a junction the candidates are ordered
+ * straightest first, and the next and previous segments are those the switch is set to.
+ */
+#[derive(Component, Default, PartialEq, Reflect)]
 pub struct SegmentLinkage {
     pub next_segment: Option<Entity>,
     pub prev_segment: Option<(Entity, f32)>,
+    pub next_segments: Vec<Entity>,
+    pub prev_segments: Vec<(Entity, f32)>,
 }
 
 pub fn update_segment_linkage(
-    mut segments: Query<(Entity, Ref<Segment>, &mut SegmentLinkage)>,
+    mut segments: Query<(Entity, Ref<Segment>, &SegmentCurve, &mut SegmentLinkage)>,
+    mut switches: Query<(Entity, &mut Switch)>,
+    mut commands: Commands,
 ) {
-    if !segments.iter().any(|(_, s, _)| s.is_changed()) {
+    let switch_changed = switches.iter_mut().any(|(_, s)| s.is_changed());
+    if !switch_changed && !segments.iter().any(|(_, s, ..)| s.is_changed()) {
         return;
     }
 
     info!("Calculating segment linkage");
 
-    let mut point_begins = HashMap::new();
-    let mut point_ends = HashMap::new();
-    for (seg_id, seg, _) in segments.iter() {
-        point_begins.insert(seg.from_point, seg_id);
-        point_ends.insert(seg.to_point, (seg_id, seg.length));
+    /* Segments leaving and arriving at each point, with their directions there */
+    let mut point_begins: HashMap<Entity, Vec<(Entity, Vec3)>> = HashMap::new();
+    let mut point_ends: HashMap<Entity, Vec<((Entity, f32), Vec3)>> = HashMap::new();
+    for (seg_id, seg, curve, _) in segments.iter() {
+        point_begins.entry(seg.from_point).or_default().push((seg_id, curve.direction_at(0.0)));
+        point_ends.entry(seg.to_point).or_default().push(((seg_id, seg.length), curve.direction_at(curve.length())));
     }
 
-    for (_, seg, mut linkage) in segments.iter_mut() {
-        linkage.next_segment = point_begins.get(&seg.to_point).copied();
-        linkage.prev_segment = point_ends.get(&seg.from_point).copied();
+    /* Order the branches at each junction against the track on its other side */
+    fn through<T>(branches: Option<&Vec<(T, Vec3)>>) -> Vec3 {
+        branches.map(|b| b.iter().map(|(_, dir)| *dir).sum()).unwrap_or_default()
+    }
+    let begin_through: HashMap<_, _> = point_begins.keys().map(|pt| (*pt, through(point_ends.get(pt)))).collect();
+    let end_through: HashMap<_, _> = point_ends.keys().map(|pt| (*pt, through(point_begins.get(pt)))).collect();
+    for (pt, branches) in point_begins.iter_mut() {
+        order_branches(branches, begin_through[pt]);
+    }
+    for (pt, branches) in point_ends.iter_mut() {
+        order_branches(branches, end_through[pt]);
+    }
+
+    /* Every junction has a switch, and nothing else does */
+    let mut routes = HashMap::new();
+    for pt in point_begins.keys().chain(point_ends.keys()) {
+        let num_routes = point_begins.get(pt).map_or(0, Vec::len).max(point_ends.get(pt).map_or(0, Vec::len));
+        if num_routes > 1 {
+            routes.insert(*pt, num_routes);
+        }
+    }
+    for (pt, num_routes) in &routes {
+        if let Ok((_, mut switch)) = switches.get_mut(*pt) {
+            if switch.routes != *num_routes {
+                switch.routes = *num_routes;
+                switch.route = switch.route.min(num_routes - 1);
+            }
+        } else {
+            info!("Adding switch with {num_routes} routes");
+            commands.entity(*pt).insert(Switch { routes: *num_routes, ..Switch::default() });
+        }
+    }
+
+    for (pt, switch) in switches.iter() {
+        if routes.contains_key(&pt) { continue; }
+        info!("Removing switch");
+        if let Some(rendered_id) = switch.rendered_id {
+            commands.entity(rendered_id).try_despawn();
+        }
+        commands.entity(pt).try_remove::<Switch>();
+    }
+
+    let route = |pt: Entity| switches.get(pt).map_or(0, |(_, s)| s.route);
+    let select = |len: usize, route: usize| route.min(len.saturating_sub(1));
+    for (_, seg, _, mut linkage) in segments.iter_mut() {
+        let next_segments: Vec<_> = point_begins.get(&seg.to_point)
+            .map(|b| b.iter().map(|(id, _)| *id).collect())
+            .unwrap_or_default();
+        let prev_segments: Vec<_> = point_ends.get(&seg.from_point)
+            .map(|b| b.iter().map(|(id, _)| *id).collect())
+            .unwrap_or_default();
+
+        linkage.set_if_neq(SegmentLinkage {
+            next_segment: next_segments.get(select(next_segments.len(), route(seg.to_point))).copied(),
+            prev_segment: prev_segments.get(select(prev_segments.len(), route(seg.from_point))).copied(),
+            next_segments,
+            prev_segments,
+        });
     }
 }
 
 pub fn update_segments(
-    mut segments: Query<(&mut Segment, &mut Transform), Or<(Changed<Segment>, Changed<Transform>)>>,
+    mut segments: Query<(&mut Segment, &mut SegmentCurve, &mut Transform), Or<(Changed<Segment>, Changed<Transform>)>>,
     mut all_points: Query<(&Transform, &mut Point), Without<Segment>>,
 ) {
     if segments.is_empty() { return; }
 
     info!("Updating segments");
 
-    for (mut seg, mut transform) in segments.iter_mut() {
+    for (mut seg, mut curve, mut transform) in segments.iter_mut() {
         let (pt1, _) = all_points.get(seg.from_point).unwrap();
         let (pt2, _) = all_points.get(seg.to_point).unwrap();
-        seg.length = pt1.translation.distance(pt2.translation);
+        *curve = SegmentCurve::new(pt1.translation, pt2.translation, seg.shape);
+        seg.length = curve.length();
 
-        transform.translation = pt1.translation;
-        transform.look_to(pt1.translation - pt2.translation, Vec3::Y);
+        let new_transform = segment_transform(pt1.translation, pt2.translation);
+        transform.translation = new_transform.translation;
+        transform.rotation = new_transform.rotation;
 
         all_points.get_mut(seg.from_point).unwrap().1.set_changed();
         all_points.get_mut(seg.to_point).unwrap().1.set_changed();
     }
 }
+
+/**
+ * Place a segment at its first point, with its local z-axis pointing towards the second.
+ */
+pub fn segment_transform(from: Vec3, to: Vec3) -> Transform {
+    Transform::from_translation(from).looking_to(from - to, Vec3::Y)
+}
@@ -0,0 +1,291 @@
+use bevy::app::{App, Plugin, Startup, Update};
+use bevy::asset::{AssetEvent, Assets, Handle};
+use bevy::color::Color;
+use bevy::math::{Vec2, Vec3};
+use bevy::pbr::{MeshMaterial3d, StandardMaterial};
+use bevy::prelude::{Added, Camera, ChildOf, Children, Commands, Component, DetectChanges, DetectChangesMut, Entity, EventReader, GlobalTransform, IntoScheduleConfigs, Mesh, Mesh3d, Name, Node, PositionType, Query, Ref, Reflect, ReflectComponent, ReflectResource, Res, ResMut, Resource, Single, Text, TextColor, TextFont, Transform, Val, Visibility, With};
+use serde::Deserialize;
+
+use crate::theme::Theme;
+use crate::track::curve::SegmentCurve;
+use crate::track::graph::TrackPosition;
+use crate::track::rendering::{extrusion, to_segment_space, track_style, CURVE_STEP};
+use crate::track::segment;
+use crate::track::style::{TrackStyle, TrackStyleHandle};
+
+pub struct StationPlugin;
+
+impl Plugin for StationPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .register_type::<PlatformRenderParams>()
+            .register_type::<Station>()
+            .register_type::<Platform>()
+            .init_resource::<PlatformRenderParams>()
+            .add_systems(Startup, init_render_params)
+            .add_systems(Update, (render_platforms, remove_platform_meshes).after(segment::update_segments))
+            .add_systems(Update, (create_station_labels, update_station_labels).chain());
+    }
+}
+
+/**
+ * Height of the platform surface above the level the track is laid at, and how far the
+ * platform is sunk into the ground below it.
+ */
+const PLATFORM_HEIGHT: f32 = 1.2;
+const PLATFORM_DEPTH: f32 = 0.5;
+
+const PLATFORM_WIDTH: f32 = 4.0;
+
+const PLATFORM_COLOUR: Color = Color::srgb(0.7, 0.7, 0.65);
+
+/**
+ * Height above the platform the station's name is shown at.
+ */
+const LABEL_HEIGHT: f32 = 5.0;
+
+const LABEL_COLOUR: Color = Color::srgb(1.0, 1.0, 0.8);
+
+#[derive(Default, Reflect, Resource)]
+#[reflect(Resource)]
+pub struct PlatformRenderParams {
+    platform_material: Handle<StandardMaterial>,
+}
+
+/**
+ * A place trains stop, with its platforms as children.
+ */
+#[derive(Component, Reflect)]
+#[reflect(Component)]
+#[require(Transform, Visibility)]
+pub struct Station {
+    pub name: String,
+}
+
+/**
+ * Which side of the track a platform is on, looking forward along its segment.
+ */
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Reflect)]
+pub enum PlatformSide {
+    #[default]
+    Left,
+    Right,
+}
+
+/**
+ * A platform beside a stretch of a segment, between two distances along it.
+ */
+#[derive(Component, Clone, Debug, Reflect)]
+#[reflect(Component)]
+pub struct Platform {
+    pub segment: Entity,
+    pub start: f32,
+    pub end: f32,
+    pub side: PlatformSide,
+    pub rendered_id: Option<Entity>,
+}
+
+impl Platform {
+    /**
+     * Where trains stop at the platform, halfway along it.
+     */
+    pub fn stop_position(&self) -> TrackPosition {
+        TrackPosition { segment: self.segment, distance: (self.start + self.end) / 2.0 }
+    }
+}
+
+/**
+ * A platform's mesh, which is drawn as part of its segment rather than its platform.
+ */
+#[derive(Component)]
+struct PlatformMesh(Entity);
+
+/**
+ * The name shown over a station.
+ */
+#[derive(Component)]
+struct StationLabel(Entity);
+
+fn init_render_params(
+    mut params: ResMut<PlatformRenderParams>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    params.platform_material = materials.add(StandardMaterial {
+        perceptual_roughness: 0.9,
+        ..StandardMaterial::from(PLATFORM_COLOUR)
+    });
+}
+
+pub fn create_station(
+    name: &str,
+    platforms: &[Platform],
+    commands: &mut Commands,
+) -> (Entity, Vec<Entity>) {
+    let station_id = commands.spawn((
+        Name::new(format!("Station:{name}")),
+        Station { name: name.to_owned() },
+    )).id();
+
+    let platform_ids = platforms.iter().enumerate().map(|(i, platform)| {
+        commands.spawn((
+            Name::new(format!("Platform:{name} {}", i + 1)),
+            platform.clone(),
+            ChildOf(station_id),
+        )).id()
+    }).collect();
+
+    (station_id, platform_ids)
+}
+
+/**
+ * A platform's cross-section, standing just outside the track's bed.
+ */
+fn platform_profile(side: PlatformSide, style: &TrackStyle) -> [Vec2; 4] {
+    let inner = style.bed_half_width();
+    let outer = inner + PLATFORM_WIDTH;
+    let (x0, x1) = match side {
+        PlatformSide::Left => (inner, outer),
+        PlatformSide::Right => (-outer, -inner),
+    };
+    [
+        Vec2::new(x0, -PLATFORM_DEPTH),
+        Vec2::new(x0, PLATFORM_HEIGHT),
+        Vec2::new(x1, PLATFORM_HEIGHT),
+        Vec2::new(x1, -PLATFORM_DEPTH),
+    ]
+}
+
+/**
+ * Platforms are drawn as part of the segment they stand beside, so they follow it when
+ * its points are moved.
+ */
+fn render_platforms(
+    mut platforms: Query<(Entity, &mut Platform)>,
+    segments: Query<(Ref<SegmentCurve>, &Transform, Option<&ChildOf>)>,
+    tracks: Query<&TrackStyleHandle>,
+    styles: Res<Assets<TrackStyle>>,
+    mut style_events: EventReader<AssetEvent<TrackStyle>>,
+    params: Res<PlatformRenderParams>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut commands: Commands,
+) {
+    let any_style_changed = style_events.read().count() > 0;
+    let default_style = TrackStyle::default();
+
+    for (platform_id, mut platform) in platforms.iter_mut() {
+        let Ok((curve, seg_transform, parent)) = segments.get(platform.segment) else { continue; };
+        if !platform.is_changed() && !curve.is_changed() && !any_style_changed { continue; }
+
+        if let Some(rendered_id) = platform.rendered_id {
+            commands.entity(rendered_id).try_despawn();
+        }
+
+        let (_, style) = track_style(parent, &tracks, &styles, &default_style);
+        let path: Vec<_> = curve.path_between(platform.start, platform.end, CURVE_STEP).into_iter()
+            .map(|frame| to_segment_space(seg_transform, frame))
+            .collect();
+        let mesh = extrusion(&platform_profile(platform.side, style), &path, false, false, Vec3::Z, Vec3::Z);
+        platform.rendered_id = Some(commands.spawn((
+            Name::new("Platform"),
+            PlatformMesh(platform_id),
+            Mesh3d(meshes.add(mesh)),
+            MeshMaterial3d(params.platform_material.clone()),
+            ChildOf(platform.segment),
+        )).id());
+    }
+}
+
+/**
+ * Platform meshes outlive their platforms only until the next update.
+ */
+fn remove_platform_meshes(
+    meshes: Query<(Entity, &PlatformMesh)>,
+    platforms: Query<(), With<Platform>>,
+    mut commands: Commands,
+) {
+    for (mesh_id, mesh) in meshes.iter() {
+        if !platforms.contains(mesh.0) {
+            commands.entity(mesh_id).despawn();
+        }
+    }
+}
+
+fn create_station_labels(
+    stations: Query<(Entity, &Station), Added<Station>>,
+    theme: Res<Theme>,
+    mut commands: Commands,
+) {
+    for (station_id, station) in stations.iter() {
+        commands.spawn((
+            Name::new(format!("Label:{}", station.name)),
+            StationLabel(station_id),
+            Text(station.name.clone()),
+            TextFont {
+                font: theme.font.clone(),
+                font_size: 16.0,
+                ..TextFont::default()
+            },
+            TextColor(LABEL_COLOUR),
+            Node {
+                position_type: PositionType::Absolute,
+                ..Node::default()
+            },
+        ));
+    }
+}
+
+/**
+ * Keep each station's name over its first platform, hidden when that is out of view.
+ * Labels outlive their stations only until the next update.
+ */
+fn update_station_labels(
+    mut labels: Query<(Entity, &StationLabel, &mut Node, &mut Visibility)>,
+    stations: Query<&Children, With<Station>>,
+    platforms: Query<&Platform>,
+    curves: Query<&SegmentCurve>,
+    camera: Single<(&Camera, &GlobalTransform)>,
+    mut commands: Commands,
+) {
+    let (camera, camera_transform) = *camera;
+
+    for (label_id, label, mut node, mut visibility) in labels.iter_mut() {
+        let Ok(children) = stations.get(label.0) else {
+            commands.entity(label_id).despawn();
+            continue;
+        };
+
+        let position = children.iter()
+            .find_map(|child| platforms.get(*child).ok())
+            .and_then(|platform| curves.get(platform.segment).ok().map(|curve| curve.position_at(platform.stop_position().distance)))
+            .and_then(|position| camera.world_to_viewport(camera_transform, position + Vec3::Y * LABEL_HEIGHT).ok());
+
+        match position {
+            Some(position) => {
+                node.left = Val::Px(position.x);
+                node.top = Val::Px(position.y);
+                visibility.set_if_neq(Visibility::Inherited);
+            }
+            None => {
+                visibility.set_if_neq(Visibility::Hidden);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_platform_profile() {
+        let style = TrackStyle::default();
+        let left = platform_profile(PlatformSide::Left, &style);
+        assert_eq!(left[0].x, 2.5);
+        assert_eq!(left[3].x, 2.5 + PLATFORM_WIDTH);
+
+        /* Mirrored, but still in the same order across the top as the bed's profile */
+        let right = platform_profile(PlatformSide::Right, &style);
+        assert_eq!(right.map(|pt| pt.x), [-6.5, -6.5, -2.5, -2.5]);
+        assert!(right.iter().all(|pt| pt.y == PLATFORM_HEIGHT || pt.y == -PLATFORM_DEPTH));
+    }
+}
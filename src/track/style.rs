@@ -0,0 +1,205 @@
+use bevy::asset::{Asset, AssetLoader, AsyncReadExt, Handle, LoadContext};
+use bevy::asset::io::Reader;
+use bevy::color::Color;
+use bevy::math::{Vec2, Vec3};
+use bevy::pbr::StandardMaterial;
+use bevy::prelude::{Component, TypePath};
+use serde::Deserialize;
+use thiserror::Error;
+
+/**
+ * How a track is built: its gauge, the shapes and sizes of its rails, sleepers and bed,
+ * and what they are made of.  Lengths are in metres, and profiles are across the track
+ * with y up.
+ *
+ * Tracks are rendered with:
+ *    - rail height
+ *    - sleeper height
+ *    - bed height
+ *
+ * These fill up the space between ground level and the rail height.
+ * The bed height is expanded below ground level to fully occupy dips in the terrain.
+ */
+#[derive(Asset, Clone, Debug, Deserialize, PartialEq, TypePath)]
+#[serde(default)]
+pub struct TrackStyle {
+    /**
+     * Distances between the centres of the rails.  Track with more than one gauge has a
+     * rail on the left for each, and one rail on the right that they share.
+     */
+    pub gauges: Vec<f32>,
+    pub rail_height: f32,
+    pub rail_profile: Vec<Vec2>,
+    pub sleeper_dims: Vec3,
+    pub sleeper_height: f32,
+    pub sleeper_spacing: f32,
+    pub bed_profile: Vec<Vec2>,
+    pub rail_material: MaterialStyle,
+    pub sleeper_material: MaterialStyle,
+    pub bed_material: MaterialStyle,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub struct MaterialStyle {
+    pub colour: (f32, f32, f32),
+    #[serde(default = "default_roughness")]
+    pub roughness: f32,
+    #[serde(default)]
+    pub metallic: f32,
+}
+
+fn default_roughness() -> f32 { 0.5 }
+
+impl MaterialStyle {
+    pub fn material(&self) -> StandardMaterial {
+        let (r, g, b) = self.colour;
+        let mut material = StandardMaterial::from(Color::srgb(r, g, b));
+        material.perceptual_roughness = self.roughness;
+        material.metallic = self.metallic;
+        material
+    }
+}
+
+impl Default for TrackStyle {
+    /**
+     * Standard gauge track with flat-bottomed rails on timber sleepers.
+     */
+    fn default() -> Self {
+        let rail_points_half = [(-6.5, 0.0), (-6.5, 1.0), (-1.0, 2.0), (-0.75, 11.0), (-3.0, 12.0), (-3.0, 14.0), (-2.0, 15.0)];
+        let rail_points_otherhalf = rail_points_half.iter().rev().map(|(x, y)| (-x, *y));
+        let rail_points = rail_points_half.iter().copied().chain(rail_points_otherhalf);
+        let rail_profile = rail_points.map(|(x, y)| Vec2::new(x * 0.01, y * 0.01));
+
+        let bed_points_half = [(-2.5, -0.3), (-1.5, 0.2)];
+        let bed_points_otherhalf = bed_points_half.iter().rev().map(|(x, y)| (-x, *y));
+        let bed_points = bed_points_half.iter().copied().chain(bed_points_otherhalf);
+        let bed_profile = bed_points.map(|(x, y)| Vec2::new(x, y));
+
+        TrackStyle {
+            gauges: vec![1.435],
+            rail_height: 0.2 + 0.15,
+            rail_profile: rail_profile.collect(),
+            sleeper_dims: Vec3::new(2.0, 0.15, 0.2),
+            sleeper_height: 0.2,
+            sleeper_spacing: 0.7,
+            bed_profile: bed_profile.collect(),
+            rail_material: MaterialStyle { colour: (0.8, 0.8, 0.8), roughness: 0.5, metallic: 0.8 },
+            sleeper_material: MaterialStyle { colour: (0.5, 0.25, 0.1), roughness: 0.7, metallic: 0.0 },
+            bed_material: MaterialStyle { colour: (0.6, 0.6, 0.5), roughness: 0.5, metallic: 0.0 },
+        }
+    }
+}
+
+impl TrackStyle {
+    /**
+     * Check the style can be built: it has at least one gauge, and none are zero or less.
+     */
+    pub fn validate(&self) -> Result<(), TrackStyleLoaderError> {
+        if self.gauges.is_empty() || !self.gauges.iter().all(|gauge| *gauge > 0.0) {
+            return Err(TrackStyleLoaderError::Gauges(self.gauges.clone()));
+        }
+        Ok(())
+    }
+
+    /**
+     * The widest gauge, which the track is centred on.
+     */
+    pub fn gauge(&self) -> f32 {
+        self.gauges.iter().copied().fold(0.0, f32::max)
+    }
+
+    /**
+     * Offset of each rail's centre from the centre line, positive to the left.  The
+     * shared rail comes first.
+     */
+    pub fn rail_offsets(&self) -> Vec<f32> {
+        let right = -self.gauge() / 2.0;
+        [right].into_iter()
+            .chain(self.gauges.iter().map(|gauge| right + gauge))
+            .collect()
+    }
+
+    /**
+     * Distance from the centre line to the outside edge of the bed, which platforms are
+     * built alongside.
+     */
+    pub fn bed_half_width(&self) -> f32 {
+        self.bed_profile.iter().map(|pt| pt.x.abs()).fold(0.0, f32::max)
+    }
+}
+
+/**
+ * The style a track is built in, on the track's entity.  Tracks without one are built
+ * in the default style.
+ */
+#[derive(Component, Clone, Debug)]
+pub struct TrackStyleHandle(pub Handle<TrackStyle>);
+
+#[non_exhaustive]
+#[derive(Debug, Error)]
+pub enum TrackStyleLoaderError {
+    #[error("Could not load asset: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Could not deserialise track style: {0}")]
+    Ron(#[from] ron::de::SpannedError),
+    #[error("Track style needs at least one gauge, and all gauges must be positive: {0:?}")]
+    Gauges(Vec<f32>),
+}
+
+#[derive(Default)]
+pub struct TrackStyleLoader;
+
+impl AssetLoader for TrackStyleLoader {
+    type Asset = TrackStyle;
+    type Settings = ();
+    type Error = TrackStyleLoaderError;
+
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _settings: &Self::Settings,
+        _load_context: &mut LoadContext<'_>
+    ) -> Result<Self::Asset, Self::Error> {
+        let mut str = String::new();
+        reader.read_to_string(&mut str).await?;
+        let style: TrackStyle = ron::from_str(&str)?;
+        style.validate()?;
+        Ok(style)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["ron"]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rail_offsets() {
+        let style = TrackStyle { gauges: vec![1.067], ..TrackStyle::default() };
+        assert_eq!(style.rail_offsets(), [-0.5335, 0.5335]);
+
+        /* Dual gauge has three rails, the narrow gauge's inside the standard's on the left */
+        let style = TrackStyle { gauges: vec![1.435, 1.067], ..TrackStyle::default() };
+        let offsets = style.rail_offsets();
+        assert_eq!(offsets.len(), 3);
+        assert_eq!(offsets[0], -0.7175);
+        assert_eq!(offsets[1], 0.7175);
+        assert!((offsets[2] - 0.3495).abs() < 1e-6);
+
+        let style: TrackStyle = ron::from_str("(gauges: [1.067], sleeper_spacing: 0.6)").unwrap();
+        assert_eq!(style.gauge(), 1.067);
+        assert_eq!(style.sleeper_spacing, 0.6);
+        assert_eq!(style.rail_profile, TrackStyle::default().rail_profile);
+
+        let style: TrackStyle = ron::from_str(include_str!("../../assets/data/styles/dual_gauge.ron")).unwrap();
+        assert_eq!(style.rail_offsets().len(), 3);
+
+        /* A style that would leave nothing to build is rejected */
+        assert!(TrackStyle::default().validate().is_ok());
+        assert!(TrackStyle { gauges: vec![], ..TrackStyle::default() }.validate().is_err());
+        assert!(TrackStyle { gauges: vec![1.435, -1.0], ..TrackStyle::default() }.validate().is_err());
+    }
+}
@@ -0,0 +1,88 @@
+use bevy::input::ButtonInput;
+use bevy::log::info;
+use bevy::math::{Vec3, Vec3Swizzles};
+use bevy::prelude::{Component, Entity, MouseButton, Query, Reflect, ReflectComponent, Res, Transform};
+
+use crate::level::selection::SelectedPoint;
+
+/**
+ * How close to a switch a click has to be to change it.
+ */
+const SWITCH_CLICK_RADIUS: f32 = 5.0;
+
+/**
+ * A point where the track divides, and the route trains take through it.
+ *
+ * Routes are numbered from the straightest.  They choose between the segments leaving the
+ * point for trains running forward, and between those arriving at it for trains running
+ * backward.  Switches are added to junctions as the track is linked up.
+ */
+#[derive(Component, Default, Reflect)]
+#[reflect(Component)]
+pub struct Switch {
+    pub route: usize,
+    /** Number of routes through the junction, kept up to date with the track. */
+    pub routes: usize,
+    pub rendered_id: Option<Entity>,
+}
+
+impl Switch {
+    pub fn next_route(&mut self) {
+        self.route = (self.route + 1) % self.routes.max(1);
+    }
+}
+
+/**
+ * Order the branches on one side of a junction, straightest first, given the direction of
+ * the track on the other side.  All directions point along the track.
+ */
+pub fn order_branches<T>(branches: &mut [(T, Vec3)], through: Vec3) {
+    let through = through.xz().normalize_or_zero();
+    branches.sort_by(|a, b| {
+        let a = a.1.xz().normalize_or_zero().dot(through);
+        let b = b.1.xz().normalize_or_zero().dot(through);
+        b.total_cmp(&a)
+    });
+}
+
+/**
+ * Change the route of the switch nearest a click, if there is one close enough.
+ */
+pub fn click_switch(
+    buttons: Res<ButtonInput<MouseButton>>,
+    selected_point: Res<SelectedPoint>,
+    mut switches: Query<(&mut Switch, &Transform)>,
+) {
+    if !buttons.just_pressed(MouseButton::Left) { return; }
+
+    let click = selected_point.point.xz();
+    let nearest = switches.iter_mut()
+        .map(|(switch, transform)| (transform.translation.xz().distance(click), switch))
+        .filter(|(distance, _)| *distance < SWITCH_CLICK_RADIUS)
+        .min_by(|a, b| a.0.total_cmp(&b.0));
+
+    if let Some((_, mut switch)) = nearest {
+        switch.next_route();
+        info!("Switch set to route {} of {}", switch.route, switch.routes);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_order_branches() {
+        let mut branches = vec![
+            ("left", Vec3::new(1.0, 0.0, -0.2)),
+            ("right", Vec3::new(1.0, 0.1, 0.5)),
+            ("through", Vec3::new(1.0, 0.2, 0.0)),
+        ];
+        order_branches(&mut branches, Vec3::X);
+        assert_eq!(branches.iter().map(|b| b.0).collect::<Vec<_>>(), ["through", "left", "right"]);
+
+        let mut switch = Switch { route: 2, routes: 3, rendered_id: None };
+        switch.next_route();
+        assert_eq!(switch.route, 0);
+    }
+}
@@ -0,0 +1,466 @@
+use std::collections::HashSet;
+use std::f32::consts::PI;
+
+use bevy::app::{App, Plugin, Startup, Update};
+use bevy::asset::{Assets, Handle};
+use bevy::color::Color;
+use bevy::math::{Vec2, Vec3, Vec3Swizzles};
+use bevy::pbr::{MeshMaterial3d, StandardMaterial};
+use bevy::prelude::{Changed, ChildOf, Children, Commands, Component, Cuboid, DetectChanges, Entity, IntoScheduleConfigs, Mesh, Mesh3d, Name, Or, Query, Ref, Reflect, ReflectComponent, ReflectResource, RemovedComponents, Res, ResMut, Resource, Single, Transform, Visibility, With};
+use bevy::render::mesh::PrimitiveTopology;
+use bevy::render::render_asset::RenderAssetUsages;
+
+use crate::level::LevelLabel;
+use crate::terrain::utils::Range2;
+use crate::terrain::TerrainData;
+use crate::track::bridge;
+use crate::track::curve::SegmentCurve;
+use crate::track::rendering::{extrusion, to_segment_space, CURVE_STEP};
+use crate::track::TerrainChanges;
+
+pub struct TunnelPlugin;
+
+impl Plugin for TunnelPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .register_type::<TunnelRenderParams>()
+            .register_type::<TunnelParams>()
+            .register_type::<Tunnel>()
+            .init_resource::<TunnelRenderParams>()
+            .init_resource::<TunnelParams>()
+            .add_systems(Startup, init_render_params)
+            .add_systems(Update, (
+                detect_tunnels,
+                update_terrain_holes,
+                render_tunnels,
+            ).chain().after(bridge::detect_bridges));
+    }
+}
+
+/**
+ * Spacing of the checks for cover along each segment.
+ */
+const SAMPLE_STEP: f32 = 1.0;
+
+/**
+ * Inside of the tunnel: straight walls this far either side of the track, with a round
+ * arch springing from the top of them.
+ */
+const HALF_WIDTH: f32 = 4.0;
+const WALL_HEIGHT: f32 = 3.0;
+const ARCH_SEGMENTS: usize = 12;
+
+/**
+ * Height of the camera's focus above the track in a tunnel, and how far the camera keeps
+ * from the lining, so the near plane never cuts through it.
+ */
+const EYE_HEIGHT: f32 = 2.0;
+const CAMERA_CLEARANCE: f32 = 0.5;
+const CAMERA_STEP: f32 = 0.1;
+
+const PORTAL_WIDTH: f32 = 2.0;
+const PORTAL_HEIGHT: f32 = 9.0;
+const PORTAL_DEPTH: f32 = 1.0;
+
+const LINING_COLOUR: Color = Color::srgb(0.45, 0.43, 0.4);
+
+/**
+ * Where a segment goes through the ground.  Track deeper below the surface than the
+ * cover is bored through in a tunnel.  Shallower than that, down to the least depth,
+ * the ground is opened up over it in a cutting.
+ */
+#[derive(Clone, Debug, Reflect, Resource)]
+#[reflect(Resource)]
+pub struct TunnelParams {
+    pub min_cover: f32,
+    pub min_depth: f32,
+}
+
+impl Default for TunnelParams {
+    fn default() -> Self {
+        TunnelParams {
+            min_cover: 8.0,
+            min_depth: 0.5,
+        }
+    }
+}
+
+#[derive(Default, Reflect, Resource)]
+#[reflect(Resource)]
+pub struct TunnelRenderParams {
+    lining_material: Handle<StandardMaterial>,
+    portal_meshes: Vec<Handle<Mesh>>,
+}
+
+/**
+ * A stretch of a segment, as distances along the segment.
+ */
+#[derive(Clone, Debug, PartialEq, Reflect)]
+pub struct TunnelSpan {
+    pub start: f32,
+    pub end: f32,
+}
+
+/**
+ * Tunnels along a segment, and the cuttings leading to them, found from its depth below
+ * the terrain.  Portals stand where the track goes into or comes out of a tunnel.
+ */
+#[derive(Component, Default, Reflect)]
+#[reflect(Component)]
+pub struct Tunnel {
+    pub bores: Vec<TunnelSpan>,
+    pub cuttings: Vec<TunnelSpan>,
+    pub portals: Vec<f32>,
+    pub rendered_id: Option<Entity>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Cover {
+    Open,
+    Cutting,
+    Bore,
+}
+
+fn init_render_params(
+    mut params: ResMut<TunnelRenderParams>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    /* Seen from inside the tunnel as well as out */
+    params.lining_material = materials.add(StandardMaterial {
+        double_sided: true,
+        cull_mode: None,
+        perceptual_roughness: 0.9,
+        ..StandardMaterial::from(LINING_COLOUR)
+    });
+
+    /* A portal is a pier each side of the opening, and a lintel over it */
+    let crown = WALL_HEIGHT + HALF_WIDTH;
+    let pier = Cuboid::from_size(Vec3::new(PORTAL_WIDTH, PORTAL_HEIGHT, PORTAL_DEPTH));
+    let lintel = Cuboid::from_size(Vec3::new(HALF_WIDTH * 2.0, PORTAL_HEIGHT - crown, PORTAL_DEPTH));
+    params.portal_meshes = [
+        Mesh::from(pier).translated_by(Vec3::new(HALF_WIDTH + PORTAL_WIDTH / 2.0, PORTAL_HEIGHT / 2.0, 0.0)),
+        Mesh::from(pier).translated_by(Vec3::new(-HALF_WIDTH - PORTAL_WIDTH / 2.0, PORTAL_HEIGHT / 2.0, 0.0)),
+        Mesh::from(lintel).translated_by(Vec3::new(0.0, (PORTAL_HEIGHT + crown) / 2.0, 0.0)),
+    ].map(|mesh| meshes.add(mesh)).to_vec();
+}
+
+/**
+ * Find the tunnels and cuttings along a curve, and the portals between them.  Depths
+ * beyond the ends of the curve are found by carrying on straight, so a portal where a
+ * tunnel starts at a point isn't lost between the segments either side of it.
+ */
+pub fn find_tunnel_spans(curve: &SegmentCurve, ground: impl Fn(Vec2) -> f32, params: &TunnelParams) -> Tunnel {
+    let length = curve.length();
+    let cover_at = |distance: f32| {
+        let pos = curve.position_at(distance);
+        let depth = ground(pos.xz()) - pos.y;
+        if depth > params.min_cover {
+            Cover::Bore
+        } else if depth > params.min_depth {
+            Cover::Cutting
+        } else {
+            Cover::Open
+        }
+    };
+
+    let steps = ((length / SAMPLE_STEP).ceil() as usize).max(1);
+    let mut runs: Vec<(Cover, f32, f32)> = Vec::new();
+    for i in 0..=steps {
+        let distance = length * i as f32 / steps as f32;
+        let cover = cover_at(distance);
+        match runs.last_mut() {
+            Some(run) if run.0 == cover => run.2 = distance,
+            Some(run) => {
+                run.2 = distance;
+                runs.push((cover, distance, distance));
+            }
+            None => runs.push((cover, distance, distance)),
+        }
+    }
+
+    let mut tunnel = Tunnel::default();
+    for (cover, start, end) in runs {
+        let span = TunnelSpan { start, end };
+        match cover {
+            Cover::Bore => {
+                if start > 0.0 || cover_at(-SAMPLE_STEP) != Cover::Bore {
+                    tunnel.portals.push(start);
+                }
+                if end < length || cover_at(length + SAMPLE_STEP) != Cover::Bore {
+                    tunnel.portals.push(end);
+                }
+                tunnel.bores.push(span);
+            }
+            Cover::Cutting => tunnel.cuttings.push(span),
+            Cover::Open => {}
+        }
+    }
+    tunnel
+}
+
+/**
+ * Frame of the track under a position, if the position is over a tunnel and within its
+ * lining.
+ */
+pub fn bore_frame<'a>(pos: Vec2, tunnels: impl IntoIterator<Item = (&'a Tunnel, &'a SegmentCurve)>) -> Option<Transform> {
+    tunnels.into_iter().find_map(|(tunnel, curve)| {
+        let (distance, offset) = curve.nearest(Vec3::new(pos.x, 0.0, pos.y), SAMPLE_STEP);
+        let in_bore = tunnel.bores.iter().any(|bore| (bore.start..=bore.end).contains(&distance));
+        (in_bore && offset < HALF_WIDTH).then(|| curve.transform_at(distance))
+    })
+}
+
+/**
+ * Keep a camera looking at a position in a tunnel inside the lining.  The focus is put at
+ * eye height over the track, and the camera is brought in towards it until it is clear of
+ * the walls and arch.  Returns the focus, and the camera's distance from it.
+ */
+pub fn camera_in_bore(frame: &Transform, focus: Vec2, to_camera: Vec3, distance: f32) -> (Vec3, f32) {
+    let inner = HALF_WIDTH - CAMERA_CLEARANCE;
+    let inside = |pt: Vec3| {
+        pt.x.abs() <= inner && pt.y >= CAMERA_CLEARANCE
+            && (pt.y <= WALL_HEIGHT || Vec2::new(pt.x, pt.y - WALL_HEIGHT).length() <= inner)
+    };
+
+    let to_track = frame.compute_affine().inverse();
+    let local_focus = to_track.transform_point3(Vec3::new(focus.x, 0.0, focus.y));
+    let local_focus = Vec3::new(local_focus.x.clamp(-inner, inner), EYE_HEIGHT, local_focus.z);
+    let local_dir = frame.rotation.inverse() * to_camera;
+
+    let steps = (distance / CAMERA_STEP).ceil() as usize;
+    let reach = (1..=steps)
+        .map(|i| distance * i as f32 / steps as f32)
+        .take_while(|t| inside(local_focus + local_dir * *t))
+        .last()
+        .unwrap_or(0.0);
+    (frame.transform_point(local_focus), reach)
+}
+
+/**
+ * Put segments that run deep below the terrain in tunnels, and open up the ground over
+ * shallower track.  Earthworks are dug first, so their cuttings are already open.  As with
+ * bridges, segments are checked again when the terrain under them changes.
+ */
+pub fn detect_tunnels(
+    mut segments: Query<(Entity, Ref<SegmentCurve>, Option<&mut Tunnel>)>,
+    terrain_data: Option<Single<&TerrainData, With<LevelLabel>>>,
+    terrain_changes: Res<TerrainChanges>,
+    params: Res<TunnelParams>,
+    mut commands: Commands,
+) {
+    let ground = |pos: Vec2| terrain_data.as_ref().map_or(0.0, |data| data.elevation_at(pos));
+
+    for (segment_id, curve, tunnel) in segments.iter_mut() {
+        if !curve.is_changed() && !terrain_changes.under(&curve) { continue; }
+
+        let found = find_tunnel_spans(&curve, ground, &params);
+        let none = found.bores.is_empty() && found.cuttings.is_empty();
+        match tunnel {
+            Some(tunnel) if none => {
+                if let Some(rendered_id) = tunnel.rendered_id {
+                    commands.entity(rendered_id).try_despawn();
+                }
+                commands.entity(segment_id).try_remove::<Tunnel>();
+            }
+            Some(mut tunnel) if (&tunnel.bores, &tunnel.cuttings, &tunnel.portals) != (&found.bores, &found.cuttings, &found.portals) => {
+                tunnel.bores = found.bores;
+                tunnel.cuttings = found.cuttings;
+                tunnel.portals = found.portals;
+            }
+            None if !none => {
+                commands.entity(segment_id).insert(found);
+            }
+            _ => {}
+        }
+    }
+}
+
+/**
+ * Cut holes in the terrain over the cuttings, so the track in them can be seen.  The
+ * blocks of terrain where holes have come or gone are rebuilt.
+ */
+pub fn update_terrain_holes(
+    tunnels: Query<(&Tunnel, &SegmentCurve)>,
+    changed: Query<(), Or<(Changed<Tunnel>, Changed<SegmentCurve>)>>,
+    mut removed: RemovedComponents<Tunnel>,
+    mut terrain_data: Single<&mut TerrainData, With<LevelLabel>>,
+) {
+    let any_removed = removed.read().count() > 0;
+    if changed.is_empty() && !any_removed { return; }
+
+    let mut holes = HashSet::new();
+    for (tunnel, curve) in tunnels.iter() {
+        for cutting in &tunnel.cuttings {
+            let steps = ((cutting.end - cutting.start) / 0.5).ceil() as usize;
+            for i in 0..=steps {
+                let pos = curve.position_at(cutting.start + (cutting.end - cutting.start) * i as f32 / steps.max(1) as f32);
+                let reach = HALF_WIDTH.ceil() as isize;
+                for dr in -reach..=reach {
+                    for dc in -reach..=reach {
+                        let (row, col) = (pos.z.round() as isize + dr, pos.x.round() as isize + dc);
+                        if row < 0 || col < 0 { continue; }
+                        if Vec2::new(col as f32, row as f32).distance(pos.xz()) > HALF_WIDTH { continue; }
+                        holes.insert((row as usize, col as usize));
+                    }
+                }
+            }
+        }
+    }
+
+    if holes == terrain_data.holes { return; }
+
+    let changes: Vec<_> = holes.symmetric_difference(&terrain_data.holes).copied().collect();
+    terrain_data.holes = holes;
+    for (row, col) in changes {
+        terrain_data.dirty_mesh_range(Range2(row..row + 1, col..col + 1));
+    }
+}
+
+/**
+ * Tunnels are lined with walls and an arch, and have a portal at each mouth.  Cuttings
+ * have walls on each side, up to the ground.
+ */
+fn render_tunnels(
+    mut tunnels: Query<(Entity, &mut Tunnel, &SegmentCurve, &Transform), Or<(Changed<Tunnel>, Changed<SegmentCurve>)>>,
+    terrain_data: Option<Single<&TerrainData, With<LevelLabel>>>,
+    params: Res<TunnelRenderParams>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut commands: Commands,
+) {
+    let ground = |pos: Vec2| terrain_data.as_ref().map_or(0.0, |data| data.elevation_at(pos));
+
+    let arch = (0..=ARCH_SEGMENTS).map(|i| {
+        let angle = PI * i as f32 / ARCH_SEGMENTS as f32;
+        Vec2::new(HALF_WIDTH * angle.cos(), WALL_HEIGHT + HALF_WIDTH * angle.sin())
+    });
+    let lining_profile: Vec<_> = [Vec2::new(HALF_WIDTH, 0.0)].into_iter()
+        .chain(arch)
+        .chain([Vec2::new(-HALF_WIDTH, 0.0)])
+        .collect();
+
+    for (seg_id, mut tunnel, curve, seg_transform) in tunnels.iter_mut() {
+        if let Some(rendered_id) = tunnel.rendered_id {
+            commands.entity(rendered_id).despawn_related::<Children>();
+        } else {
+            tunnel.rendered_id = Some(commands.spawn((
+                Name::new("Tunnel"),
+                Transform::default(),
+                Visibility::default(),
+                ChildOf(seg_id),
+            )).id());
+        }
+        let parent_id = tunnel.rendered_id.unwrap();
+
+        for bore in &tunnel.bores {
+            let path: Vec<_> = curve.path_between(bore.start, bore.end, CURVE_STEP).into_iter()
+                .map(|frame| to_segment_space(seg_transform, frame))
+                .collect();
+            let mesh = extrusion(&lining_profile, &path, true, true, Vec3::Z, Vec3::Z);
+            commands.spawn((
+                Mesh3d(meshes.add(mesh)),
+                MeshMaterial3d(params.lining_material.clone()),
+                ChildOf(parent_id),
+            ));
+        }
+
+        for cutting in &tunnel.cuttings {
+            let path = curve.path_between(cutting.start, cutting.end, CURVE_STEP);
+            let mesh = create_cutting_mesh(&path, seg_transform, ground);
+            commands.spawn((
+                Mesh3d(meshes.add(mesh)),
+                MeshMaterial3d(params.lining_material.clone()),
+                ChildOf(parent_id),
+            ));
+        }
+
+        for distance in &tunnel.portals {
+            let portal = to_segment_space(seg_transform, curve.transform_at(*distance));
+            for mesh in &params.portal_meshes {
+                commands.spawn((
+                    Mesh3d(mesh.clone()),
+                    MeshMaterial3d(params.lining_material.clone()),
+                    ChildOf(parent_id),
+                    portal,
+                ));
+            }
+        }
+    }
+}
+
+/**
+ * Walls either side of a cutting, from the level of the track up to the ground.
+ */
+fn create_cutting_mesh(path: &[Transform], seg_transform: &Transform, ground: impl Fn(Vec2) -> f32) -> Mesh {
+    let inverse = seg_transform.rotation.inverse();
+    let wall = |frame: &Transform, side: f32| {
+        let bottom = frame.transform_point(Vec3::X * side * HALF_WIDTH);
+        let top = bottom.with_y(ground(bottom.xz()).max(bottom.y));
+        [bottom, top].map(|p| inverse * (p - seg_transform.translation))
+    };
+
+    let mut tris = Vec::new();
+    for side in [-1.0, 1.0] {
+        for pair in path.windows(2) {
+            let [b0, t0] = wall(&pair[0], side);
+            let [b1, t1] = wall(&pair[1], side);
+            tris.extend([b0, b1, t0, t0, b1, t1]);
+        }
+    }
+
+    Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::default())
+        .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, tris)
+        .with_computed_flat_normals()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::track::curve::SegmentShape;
+
+    use super::*;
+
+    #[test]
+    fn test_find_tunnel_spans() {
+        let params = TunnelParams::default();
+        let curve = SegmentCurve::new(Vec3::ZERO, Vec3::new(100.0, 0.0, 0.0), SegmentShape::Straight);
+
+        /* A hill with a steep core, which the track cuts into before tunnelling through */
+        let hill = |pos: Vec2| if (39.5..60.5).contains(&pos.x) {
+            10.0
+        } else if (29.5..70.5).contains(&pos.x) {
+            4.0
+        } else {
+            0.0
+        };
+        let near = |spans: &[TunnelSpan], expected: &[(f32, f32)]| spans.len() == expected.len()
+            && spans.iter().zip(expected).all(|(span, (start, end))| (span.start - start).abs() < 0.1 && (span.end - end).abs() < 0.1);
+        let tunnel = find_tunnel_spans(&curve, hill, &params);
+        assert!(near(&tunnel.bores, &[(40.0, 61.0)]));
+        assert!(near(&tunnel.cuttings, &[(30.0, 40.0), (61.0, 71.0)]));
+        assert_eq!(tunnel.portals.len(), 2);
+
+        /* Deep all the way along, carrying on into the next segments, needs no portals */
+        let tunnel = find_tunnel_spans(&curve, |_| 20.0, &params);
+        assert!(near(&tunnel.bores, &[(0.0, 100.0)]));
+        assert!(tunnel.portals.is_empty());
+    }
+
+    #[test]
+    fn test_camera_in_bore() {
+        let curve = SegmentCurve::new(Vec3::new(0.0, -20.0, 0.0), Vec3::new(100.0, -20.0, 0.0), SegmentShape::Straight);
+        let tunnel = find_tunnel_spans(&curve, |_| 0.0, &TunnelParams::default());
+
+        assert!(bore_frame(Vec2::new(50.0, 10.0), [(&tunnel, &curve)]).is_none());
+        let frame = bore_frame(Vec2::new(50.0, 1.0), [(&tunnel, &curve)]).unwrap();
+
+        /* Looking down from above and behind, the camera stops short of the arch */
+        let to_camera = Vec3::new(-1.0, 1.0, 0.0).normalize();
+        let (focus, distance) = camera_in_bore(&frame, Vec2::new(50.0, 1.0), to_camera, 100.0);
+        assert_eq!(focus.y, -20.0 + EYE_HEIGHT);
+        assert!(distance > 1.0 && focus.y + distance * to_camera.y < -20.0 + WALL_HEIGHT + HALF_WIDTH);
+
+        /* Looking along the tunnel, it doesn't need to */
+        let (_, distance) = camera_in_bore(&frame, Vec2::new(50.0, 1.0), Vec3::X, 30.0);
+        assert!((distance - 30.0).abs() < 1e-3);
+    }
+}
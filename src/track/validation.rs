@@ -0,0 +1,313 @@
+use std::fmt::{Display, Formatter};
+
+use bevy::color::Color;
+use bevy::math::{Vec3, Vec3Swizzles};
+use bevy::prelude::{Changed, DetectChanges, DetectChangesMut, Entity, Gizmos, Isometry3d, Or, Query, Reflect, ReflectResource, RemovedComponents, Res, ResMut, Resource};
+use serde::Deserialize;
+
+use crate::track::curve::SegmentCurve;
+use crate::track::rendering::CURVE_STEP;
+use crate::track::segment::{Segment, SegmentLinkage};
+use crate::track::TRACK_HEIGHT;
+
+/**
+ * Distance between the centres of the rails, across which cant is measured.
+ */
+const RAIL_CENTRES: f32 = 1.5;
+
+/**
+ * Most cant a curve is given, however tight it is.
+ */
+const MAX_CANT: f32 = 0.15;
+
+const GRAVITY: f32 = 9.81;
+
+/**
+ * Spacing of the checks along each segment.  A sudden change at a point is taken to
+ * happen over this distance.
+ */
+const CHECK_STEP: f32 = 1.0;
+
+const VIOLATION_COLOUR: Color = Color::srgb(1.0, 0.1, 0.1);
+
+/**
+ * Design limits for the track in a level.
+ */
+#[derive(Clone, Debug, Deserialize, PartialEq, Reflect, Resource)]
+#[reflect(Resource)]
+#[serde(default)]
+pub struct TrackLimits {
+    /** Steepest grade, as rise over horizontal distance. */
+    pub max_gradient: f32,
+    /** Tightest curve radius, in metres. */
+    pub min_radius: f32,
+    /** Speed trains run at through curves, in metres per second, which sets their cant. */
+    pub design_speed: f32,
+    /** Fastest the cant may change, in metres of cant per metre along the track. */
+    pub max_cant_change: f32,
+}
+
+impl Default for TrackLimits {
+    fn default() -> Self {
+        TrackLimits {
+            max_gradient: 0.035,
+            min_radius: 150.0,
+            design_speed: 80.0 / 3.6,
+            max_cant_change: 0.0025,
+        }
+    }
+}
+
+impl TrackLimits {
+    /**
+     * Height of the outer rail above the inner one that balances a train going round a
+     * curve at the design speed, up to the most cant allowed.
+     */
+    pub fn cant(&self, curvature: f32) -> f32 {
+        (RAIL_CENTRES * self.design_speed.powi(2) * curvature.abs() / GRAVITY).min(MAX_CANT)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ViolationKind {
+    Gradient,
+    Radius,
+    CantChange,
+}
+
+/**
+ * Where the track breaks one of the limits, and by how much.  The entity is the segment,
+ * or the point where two segments meet.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Violation {
+    pub kind: ViolationKind,
+    pub entity: Entity,
+    pub location: Vec3,
+    pub value: f32,
+    pub limit: f32,
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.kind {
+            ViolationKind::Gradient =>
+                write!(f, "Gradient {:.1}% (max {:.1}%)", self.value * 100.0, self.limit * 100.0),
+            ViolationKind::Radius =>
+                write!(f, "Radius {:.0} m (min {:.0} m)", self.value, self.limit),
+            ViolationKind::CantChange =>
+                write!(f, "Cant change {:.1} mm/m (max {:.1} mm/m)", self.value * 1000.0, self.limit * 1000.0),
+        }
+    }
+}
+
+/**
+ * Violations found in the track, kept up to date as it changes.
+ */
+#[derive(Default, PartialEq, Resource)]
+pub struct TrackValidation {
+    pub violations: Vec<Violation>,
+}
+
+/**
+ * The worst break of each limit along a segment's curve.
+ */
+pub fn check_curve(entity: Entity, curve: &SegmentCurve, limits: &TrackLimits) -> Vec<Violation> {
+    let mut violations = Vec::new();
+
+    let direction = curve.direction_at(0.0);
+    let horizontal = direction.xz().length();
+    let gradient = if horizontal > 0.0 { direction.y.abs() / horizontal } else { f32::INFINITY };
+    if gradient > limits.max_gradient {
+        violations.push(Violation {
+            kind: ViolationKind::Gradient,
+            entity,
+            location: curve.position_at(curve.length() / 2.0),
+            value: gradient,
+            limit: limits.max_gradient,
+        });
+    }
+
+    let steps = ((curve.length() / CHECK_STEP).ceil() as usize).max(1);
+    let samples: Vec<_> = (0..=steps)
+        .map(|i| curve.length() * i as f32 / steps as f32)
+        .map(|d| (d, curve.curvature_at(d)))
+        .collect();
+
+    let tightest = samples.iter().max_by(|a, b| a.1.abs().total_cmp(&b.1.abs())).unwrap();
+    if tightest.1.abs() * limits.min_radius > 1.0 {
+        violations.push(Violation {
+            kind: ViolationKind::Radius,
+            entity,
+            location: curve.position_at(tightest.0),
+            value: 1.0 / tightest.1.abs(),
+            limit: limits.min_radius,
+        });
+    }
+
+    let cant_change = samples.windows(2)
+        .map(|w| (w[0].0, (limits.cant(w[1].1) - limits.cant(w[0].1)).abs() / (w[1].0 - w[0].0)))
+        .filter(|(_, change)| *change > limits.max_cant_change)
+        .max_by(|a, b| a.1.total_cmp(&b.1));
+    if let Some((distance, change)) = cant_change {
+        violations.push(Violation {
+            kind: ViolationKind::CantChange,
+            entity,
+            location: curve.position_at(distance),
+            value: change,
+            limit: limits.max_cant_change,
+        });
+    }
+
+    violations
+}
+
+/**
+ * Breaks of the limits where one segment's curve runs into the next at a point: a kink
+ * in the direction, or a sudden change in cant.
+ */
+pub fn check_joint(entity: Entity, curve: &SegmentCurve, next: &SegmentCurve, limits: &TrackLimits) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    let location = curve.position_at(curve.length());
+
+    let (a, b) = (curve.direction_at(curve.length()).xz(), next.direction_at(0.0).xz());
+    let kink = a.perp_dot(b).atan2(a.dot(b)).abs();
+    if kink * limits.min_radius > CHECK_STEP {
+        violations.push(Violation {
+            kind: ViolationKind::Radius,
+            entity,
+            location,
+            value: CHECK_STEP / kink,
+            limit: limits.min_radius,
+        });
+    }
+
+    let change = (limits.cant(curve.curvature_at(curve.length())) - limits.cant(next.curvature_at(0.0))).abs() / CHECK_STEP;
+    if change > limits.max_cant_change {
+        violations.push(Violation {
+            kind: ViolationKind::CantChange,
+            entity,
+            location,
+            value: change,
+            limit: limits.max_cant_change,
+        });
+    }
+
+    violations
+}
+
+pub fn validate_track(
+    segments: Query<(Entity, &Segment, &SegmentCurve, &SegmentLinkage)>,
+    changed: Query<(), Or<(Changed<SegmentCurve>, Changed<SegmentLinkage>)>>,
+    mut removed: RemovedComponents<Segment>,
+    limits: Res<TrackLimits>,
+    mut validation: ResMut<TrackValidation>,
+) {
+    let any_removed = removed.read().count() > 0;
+    if changed.is_empty() && !any_removed && !limits.is_changed() { return; }
+
+    let mut violations = Vec::new();
+    for (segment_id, segment, curve, linkage) in segments.iter() {
+        violations.extend(check_curve(segment_id, curve, &limits));
+
+        /* Only the straight route through a junction is checked; the others turn off it by design */
+        let Some(next_id) = linkage.next_segments.first() else { continue; };
+        let Ok((_, _, next, next_linkage)) = segments.get(*next_id) else { continue; };
+        if next_linkage.prev_segments.first().is_some_and(|(prev_id, _)| *prev_id == segment_id) {
+            violations.extend(check_joint(segment.to_point, curve, next, &limits));
+        }
+    }
+
+    validation.set_if_neq(TrackValidation { violations });
+}
+
+/**
+ * Show where the track breaks the limits, in red.
+ */
+pub fn show_violations(
+    validation: Res<TrackValidation>,
+    curves: Query<&SegmentCurve>,
+    mut gizmos: Gizmos,
+) {
+    for violation in &validation.violations {
+        if let Ok(curve) = curves.get(violation.entity) {
+            let positions = curve.path(CURVE_STEP).into_iter().map(|frame| frame.translation + Vec3::Y * TRACK_HEIGHT);
+            gizmos.linestrip(positions, VIOLATION_COLOUR);
+        }
+        gizmos.sphere(Isometry3d::from_translation(violation.location + Vec3::Y * TRACK_HEIGHT), 1.0, VIOLATION_COLOUR);
+    }
+}
+
+/**
+ * Colour for a curve being laid, red if it breaks the limits.
+ */
+pub fn curve_colour(curve: &SegmentCurve, limits: &TrackLimits, colour: Color) -> Color {
+    if check_curve(Entity::PLACEHOLDER, curve, limits).is_empty() { colour } else { VIOLATION_COLOUR }
+}
+
+#[cfg(test)]
+mod test {
+    use bevy::ecs::system::RunSystemOnce;
+    use bevy::prelude::World;
+
+    use crate::track::curve::SegmentShape;
+
+    use super::*;
+
+    #[test]
+    fn test_check_curve() {
+        let limits = TrackLimits::default();
+
+        /* A gentle straight climb is fine; a steep one is too steep */
+        let gentle = SegmentCurve::new(Vec3::ZERO, Vec3::new(100.0, 3.0, 0.0), SegmentShape::Straight);
+        assert!(check_curve(Entity::PLACEHOLDER, &gentle, &limits).is_empty());
+
+        let steep = SegmentCurve::new(Vec3::ZERO, Vec3::new(100.0, 6.0, 0.0), SegmentShape::Straight);
+        let violations = check_curve(Entity::PLACEHOLDER, &steep, &limits);
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].kind, ViolationKind::Gradient);
+        assert!((violations[0].value - 0.06).abs() < 0.001);
+
+        /* A tight arc is too tight, but has an even cant along it */
+        let tight = SegmentCurve::new(Vec3::ZERO, Vec3::new(50.0, 0.0, -50.0), SegmentShape::Arc { radius: 50.0 });
+        let violations = check_curve(Entity::PLACEHOLDER, &tight, &limits);
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].kind, ViolationKind::Radius);
+        assert!((violations[0].value - 50.0).abs() < 1.0);
+
+        /* Running straight onto it, the cant changes all at once */
+        let straight = SegmentCurve::new(Vec3::new(-100.0, 0.0, 0.0), Vec3::ZERO, SegmentShape::Straight);
+        let violations = check_joint(Entity::PLACEHOLDER, &straight, &tight, &limits);
+        assert_eq!(violations.iter().map(|v| v.kind).collect::<Vec<_>>(), [ViolationKind::CantChange]);
+    }
+
+    #[test]
+    fn test_diverging_branch() {
+        let mut world = World::new();
+        world.init_resource::<TrackLimits>();
+        world.init_resource::<TrackValidation>();
+
+        /* A straight line, with a branch turning sharply off it at the junction */
+        let (p0, p1, p2, p3) = (world.spawn_empty().id(), world.spawn_empty().id(), world.spawn_empty().id(), world.spawn_empty().id());
+        let mut spawn_segment = |from, to, curve: SegmentCurve| world.spawn((
+            Segment { from_point: from, to_point: to, shape: curve.shape(), length: curve.length(), rendered_id: None },
+            curve,
+        )).id();
+        let approach = spawn_segment(p0, p1, SegmentCurve::new(Vec3::ZERO, Vec3::new(100.0, 0.0, 0.0), SegmentShape::Straight));
+        let main = spawn_segment(p1, p2, SegmentCurve::new(Vec3::new(100.0, 0.0, 0.0), Vec3::new(200.0, 0.0, 0.0), SegmentShape::Straight));
+        let branch = spawn_segment(p1, p3, SegmentCurve::new(Vec3::new(100.0, 0.0, 0.0), Vec3::new(200.0, 0.0, 20.0), SegmentShape::Straight));
+
+        world.entity_mut(approach).insert(SegmentLinkage { next_segments: vec![main, branch], ..SegmentLinkage::default() });
+        for id in [main, branch] {
+            world.entity_mut(id).insert(SegmentLinkage { prev_segments: vec![(approach, 100.0)], ..SegmentLinkage::default() });
+        }
+        world.run_system_once(validate_track).unwrap();
+        assert!(world.resource::<TrackValidation>().violations.is_empty());
+
+        /* Laid as the straight route, the same kink is too sharp */
+        world.entity_mut(approach).insert(SegmentLinkage { next_segments: vec![branch, main], ..SegmentLinkage::default() });
+        world.run_system_once(validate_track).unwrap();
+        let violations = &world.resource::<TrackValidation>().violations;
+        assert_eq!(violations.iter().map(|v| (v.kind, v.entity)).collect::<Vec<_>>(), [(ViolationKind::Radius, p1)]);
+    }
+}
@@ -1,5 +1,6 @@
 use bevy::prelude::*;
 
+use crate::track::graph::{Route, TrackPosition};
 use crate::train::rendering::{render_trains, setup_render_params, TrainRenderParams};
 
 mod movement;
@@ -16,6 +17,24 @@ pub struct TrainCar {
     pub length: f32,
 }
 
+/**
+ * Where a train is to stop next, such as at a platform.  It is removed once the train
+ * has come to rest there.
+ */
+#[derive(Component, Reflect)]
+pub struct TrainStop {
+    pub position: TrackPosition,
+    /** The way there, found again when the track changes or the train strays from it */
+    #[reflect(ignore)]
+    pub route: Option<Route>,
+}
+
+impl TrainStop {
+    pub fn new(position: TrackPosition) -> Self {
+        Self { position, route: None }
+    }
+}
+
 #[derive(Default)]
 pub struct TrainPlugin;
 
@@ -23,10 +42,12 @@ impl Plugin for TrainPlugin {
     fn build(&self, app: &mut App) {
         app
             .register_type::<TrainCar>()
+            .register_type::<TrainStop>()
             .init_resource::<TrainRenderParams>()
             .add_systems(Startup, setup_render_params)
             .add_systems(Update, render_trains)
-            .add_systems(Update, (movement::move_train, movement::update_train_position));
+            .add_systems(Update, (movement::move_train, movement::update_train_position))
+            .add_systems(Update, movement::approach_stop.before(movement::move_train));
     }
 }
 
@@ -1,24 +1,124 @@
-use std::f32::consts::PI;
-
-use bevy::log::debug;
-use bevy::math::{Quat, Vec3};
-use bevy::prelude::{Query, Res, Time, Transform, Without};
+use bevy::ecs::change_detection::DetectChanges;
+use bevy::log::{debug, info};
+use bevy::math::Vec3;
+use bevy::prelude::{Commands, Entity, Has, Query, Res, Time, Transform, Without};
 
+use crate::track::curve::SegmentCurve;
+use crate::track::graph::{Route, TrackGraph, TrackPosition, TravelDirection};
 use crate::track::segment::{Segment, SegmentLinkage};
-use crate::train::TrainCar;
+use crate::track::switch::Switch;
+use crate::train::{TrainCar, TrainStop};
+
+/**
+ * Most segments a car's position is followed across, when finding where its ends are.
+ */
+const MAX_SEGMENTS_SPANNED: usize = 16;
+
+/**
+ * How close a train has to get to its stop to have arrived.
+ */
+const STOP_TOLERANCE: f32 = 0.1;
+
+/**
+ * Head trains with a stop towards it over the track, and brake them so they come to rest
+ * there.  If the stop is behind a train, it is brought to a halt before setting off back
+ * the other way.  The switch at the end of the segment a train is on is set for its route.
+ */
+pub fn approach_stop(
+    time: Res<Time>,
+    mut trains: Query<(Entity, &mut TrainCar, &mut TrainStop)>,
+    graph: Res<TrackGraph>,
+    segments: Query<(&Segment, &SegmentLinkage)>,
+    mut switches: Query<&mut Switch>,
+    mut commands: Commands,
+) {
+    for (train_id, mut car, mut stop) in trains.iter_mut() {
+        let here = TrackPosition { segment: car.segment_id, distance: car.segment_position };
+        let mut remaining = stop.route.as_ref().and_then(|route| route.remaining(here, &graph));
+        let strayed = stop.route.is_some() && remaining.is_none();
+        if graph.is_changed() || stop.is_added() || strayed {
+            stop.route = graph.route(here, stop.position);
+            remaining = stop.route.as_ref().map(|route| route.length);
+        }
+        let (Some(route), Some(remaining)) = (&stop.route, remaining) else { continue; };
 
+        if remaining < STOP_TOLERANCE {
+            car.speed = 0.0;
+            commands.entity(train_id).remove::<TrainStop>();
+            info!("Train arrived at its stop");
+            continue;
+        }
+
+        set_next_switch(route, car.segment_id, &segments, &mut switches);
+
+        let Some((_, leg_direction)) = route.legs.iter().find(|(segment, _)| *segment == car.segment_id)
+        else { continue; };
+        let direction = match leg_direction {
+            TravelDirection::Forward => 1.0,
+            TravelDirection::Backward => -1.0,
+        };
+
+        /* Going the wrong way, so stop before turning round */
+        let change = car.acceleration * time.delta_secs();
+        if car.speed * direction < 0.0 {
+            car.speed = (car.speed.abs() - change).max(0.0) * car.speed.signum();
+            continue;
+        }
+
+        /* Fastest it can go and still stop in time */
+        let braking_speed = (2.0 * car.acceleration * remaining).sqrt();
+        car.speed = (car.speed.abs() + change).min(car.max_speed).min(braking_speed) * direction;
+    }
+}
+
+/**
+ * Set the switch at the end of the route's leg along a segment, if the route carries on
+ * past it through a junction.  Switches already set the right way are left alone.
+ */
+fn set_next_switch(
+    route: &Route,
+    segment_id: Entity,
+    segments: &Query<(&Segment, &SegmentLinkage)>,
+    switches: &mut Query<&mut Switch>,
+) {
+    let Some(index) = route.legs.iter().position(|(segment, _)| *segment == segment_id) else { return; };
+    let Some([(from_id, direction), (to_id, _)]) = route.legs.get(index..index + 2) else { return; };
+    let Ok((segment, linkage)) = segments.get(*from_id) else { return; };
+
+    /* Only where the route is one of several ways on does the switch matter */
+    let (point_id, candidates) = match direction {
+        TravelDirection::Forward => (segment.to_point, linkage.next_segments.clone()),
+        TravelDirection::Backward => (segment.from_point, linkage.prev_segments.iter().map(|(id, _)| *id).collect()),
+    };
+    if candidates.len() < 2 { return; }
+
+    let Some(index) = candidates.iter().position(|id| id == to_id) else { return; };
+    if let Ok(mut switch) = switches.get_mut(point_id) {
+        if switch.route != index {
+            debug!("Setting switch for train's route");
+            switch.route = index;
+        }
+    }
+}
+
+/**
+ * Move trains along the track, following the switches as they are set.  Trains without a
+ * stop to head for keep accelerating up to their top speed.
+ */
 pub fn move_train(
     time: Res<Time>,
-    mut trains: Query<&mut TrainCar>,
+    mut trains: Query<(&mut TrainCar, Has<TrainStop>)>,
     segments: Query<(&Segment, &SegmentLinkage), Without<TrainCar>>,
 ) {
-    for mut car in trains.iter_mut() {
+    for (mut car, has_stop) in trains.iter_mut() {
         let Ok((segment, linkage)) = segments.get(car.segment_id)
         else { continue };
 
         if segment.length == 0.0 { continue; }
 
-        if car.speed < 0.0 && car.speed >= -car.max_speed {
+        if has_stop {
+            /* Left to approach_stop */
+        } else if car.speed < 0.0 && car.speed >= -car.max_speed {
             car.speed -= car.acceleration * time.delta_secs();
         } else if car.speed > 0.0 && car.speed <= car.max_speed {
             car.speed += car.acceleration * time.delta_secs();
@@ -49,79 +149,50 @@ pub fn move_train(
     }
 }
 
+/**
+ * Place each car with its ends on the track, half its length either side of its position.
+ * On curves the car is a chord between those ends, so it cuts slightly inside the curve.
+ */
 pub fn update_train_position(
     mut trains: Query<(&TrainCar, &mut Transform)>,
-    segments: Query<(&Segment, &SegmentLinkage, &Transform), Without<TrainCar>>,
+    segments: Query<(&SegmentLinkage, &SegmentCurve), Without<TrainCar>>,
 ) {
     for (car, mut transform) in trains.iter_mut() {
-        let Ok((seg, linkage, seg_transform)) = segments.get(car.segment_id)
+        let front = track_position(car.segment_id, car.segment_position + car.length / 2.0, &segments);
+        let rear = track_position(car.segment_id, car.segment_position - car.length / 2.0, &segments);
+        let (Some(front), Some(rear)) = (front, rear)
         else { continue; };
 
-        /* Cutoff position of the car where it is turning from/to an adjacent segment */
-        let p1 = if linkage.prev_segment.is_some() { car.length / 2.0 } else { 0.0 };
-        let p2 = if linkage.next_segment.is_some() { seg.length - car.length / 2.0 } else { seg.length };
-
-        /* Simple case: car is fully within this one segment */
-        if (p1..=p2).contains(&car.segment_position) {
-            *transform = Transform::default();
-            transform.translation.y += crate::track::TRACK_HEIGHT;
-            transform.translation.z = car.segment_position;
-
-            *transform = seg_transform.mul_transform(*transform);
-            continue;
-        }
+        *transform = Transform::from_translation((front + rear) / 2.0).looking_to(rear - front, Vec3::Y);
+        transform.translation.y += crate::track::TRACK_HEIGHT;
+    }
+}
 
-        /* Complex case: car is mostly on this segment, but part of it is on an adjacent segment.
-        First figure out if it's the previous or next segment and set some parameters accordingly.
-        Junction is the point where the two segments join; its direction is set along the adjacent
-        segment, towards the junction.  Mix is the proportion of the other segment to use for the
-        car's rotation. */
-        let next_seg: Transform;
-        let mut junction: Transform;
-        let mix: f32;
-        //TODO a hack for 1.0 or -1.0 depending on what end of the train we want to anchor
-        let anchor_dir: f32;
-
-        if car.segment_position < p1 {
-            let Some((_, _, prev_seg_transform)) = linkage.prev_segment
-                .and_then(|(prev, _)| segments.get(prev).ok())
-            else { continue; };
-
-            next_seg = *prev_seg_transform;
-            junction = *seg_transform;
-            junction.rotation = prev_seg_transform.rotation * Quat::from_axis_angle(Vec3::Y, PI);
-            mix = 1.0 - car.segment_position / p1;
-            anchor_dir = -1.0;
+/**
+ * Position on the track a distance along from the start of a segment, following the
+ * linked segments either side.  Past the end of the track, it carries straight on.
+ */
+pub fn track_position(
+    segment_id: Entity,
+    mut distance: f32,
+    segments: &Query<(&SegmentLinkage, &SegmentCurve), Without<TrainCar>>,
+) -> Option<Vec3> {
+    let (mut linkage, mut curve) = segments.get(segment_id).ok()?;
+
+    /* Bounded, in case of loops of zero length */
+    for _ in 0..MAX_SEGMENTS_SPANNED {
+        if distance > curve.length() {
+            let Some(next) = linkage.next_segment else { break };
+            distance -= curve.length();
+            (linkage, curve) = segments.get(next).ok()?;
+        } else if distance < 0.0 {
+            let Some((prev, _)) = linkage.prev_segment else { break };
+            (linkage, curve) = segments.get(prev).ok()?;
+            distance += curve.length();
         } else {
-            let Some((_, _, next_seg_transform)) = linkage.next_segment
-                .and_then(|next| segments.get(next).ok())
-            else { continue; };
-
-            next_seg = *next_seg_transform;
-            junction = *next_seg_transform;
-            junction.rotation = next_seg_transform.rotation;
-            mix = (car.segment_position - p2) / (seg.length - p2);
-            anchor_dir = 1.0;
+            break;
         }
-
-        /* The rotation of the car is interpolated between each segment */
-        let car_rotation = seg_transform.rotation.lerp(next_seg.rotation, mix/2.0);
-
-        /* The placement is solved using the triangle sine identity.  Theta is the angle of the
-        junction; alpha is the angle between the car and the segment it's mostly on; dist is
-        the distance of the opposing point (the one on the adjacent segment) from the junction. */
-        let theta = seg_transform.rotation.angle_between(junction.rotation);
-        let alpha = seg_transform.rotation.angle_between(car_rotation);
-        let dist = (car.length * alpha.sin()) / theta.sin();
-
-        /* connection is where on the adjacent segment we want to connect the train;
-        anchor is the part of the train we want to connect there. */
-        let connection = junction.translation + junction.back() * dist;
-        let anchor = car_rotation * Vec3::Z * (car.length / 2.0) * anchor_dir;
-
-        *transform = Transform::default();
-        transform.translation = connection - anchor;
-        transform.translation.y += crate::track::TRACK_HEIGHT;
-        transform.rotation = car_rotation;
     }
+
+    Some(curve.position_at(distance))
 }
@@ -0,0 +1,346 @@
+use std::ops::Range;
+
+use bevy::asset::RenderAssetUsages;
+use bevy::color::palettes::basic::GRAY;
+use bevy::ecs::children;
+use bevy::prelude::*;
+use bevy::render::render_resource::{Extent3d, TextureDimension, TextureFormat};
+use bevy::ui::RelativeCursorPosition;
+
+use crate::camera::CameraState;
+use crate::level::LevelLabel;
+use crate::screens::Screen;
+use crate::terrain::chunks::ChunkedArray;
+use crate::terrain::{Terrain, TerrainData, TerrainLayer};
+use crate::track::curve::SegmentCurve;
+use crate::track::rendering::CURVE_STEP;
+use crate::track::segment::Segment;
+use crate::train::TrainCar;
+use crate::worker::Worker;
+
+/**
+ * A small overview of the whole level, in the corner of the screen.
+ *
+ * The map is drawn into two images.  The relief image shows the shape of the terrain,
+ * and is only redrawn for blocks that have changed.  The overlay image, on top of it,
+ * shows the tracks, trains, workers and the area the camera can see, and is redrawn
+ * whenever any of those move.
+ */
+pub struct MinimapPlugin;
+
+impl Plugin for MinimapPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, (
+            update_relief,
+            update_overlay,
+            click_minimap,
+        ).run_if(in_state(Screen::Playing)));
+    }
+}
+
+/** Largest size of the minimap, in pixels, along either side. */
+const MINIMAP_SIZE: usize = 256;
+
+const LIGHT_DIRECTION: Vec3 = Vec3::new(-1.0, 1.0, -1.0);
+const AMBIENT_LIGHT: f32 = 0.4;
+
+const WATER_COLOUR: Srgba = Srgba::rgb(0.25, 0.41, 0.88);
+
+/** Colours of the terrain at increasing elevations, blended between. */
+const ELEVATION_TINTS: [(f32, Srgba); 3] = [
+    (0.0, Srgba::rgb(0.3, 0.6, 0.2)),
+    (150.0, Srgba::rgb(0.51, 0.25, 0.03)),
+    (400.0, Srgba::rgb(0.85, 0.85, 0.85)),
+];
+
+const TRACK_COLOUR: [u8; 4] = [32, 32, 32, 255];
+const TRAIN_COLOUR: [u8; 4] = [255, 0, 0, 255];
+const WORKER_COLOUR: [u8; 4] = [255, 255, 0, 255];
+const FRUSTUM_COLOUR: [u8; 4] = [255, 255, 255, 255];
+
+/**
+ * How far the sides of the camera's view are drawn, when they don't meet the ground.
+ */
+const FRUSTUM_FAR_DISTANCE: f32 = 5000.0;
+
+#[derive(Component)]
+pub struct Minimap {
+    /** Spacing between the terrain points shown in adjacent pixels. */
+    step: usize,
+    /** Size of the images, in pixels, as [rows, cols]. */
+    size: [usize; 2],
+    relief: Handle<Image>,
+    overlay: Handle<Image>,
+}
+
+pub(crate) fn create_minimap(
+    mut images: ResMut<Assets<Image>>,
+    level: Single<(&Terrain, &mut TerrainData), With<LevelLabel>>,
+    mut commands: Commands,
+) {
+    let (terrain, mut terrain_data) = level.into_inner();
+
+    let step = terrain.point_dims[0].max(terrain.point_dims[1]).div_ceil(MINIMAP_SIZE).max(1);
+    let size = terrain.point_dims.map(|d| d.div_ceil(step));
+    let relief = images.add(create_image(size));
+    let overlay = images.add(create_image(size));
+
+    /* Draw the whole relief on the first update */
+    for bi in terrain_data.block_info.iter_mut() {
+        bi.minimap_dirty = true;
+    }
+
+    commands.spawn((
+        Name::new("Minimap"),
+        Minimap { step, size, relief: relief.clone(), overlay: overlay.clone() },
+        Node {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(10.0),
+            left: Val::Px(10.0),
+            width: Val::Px(size[1] as f32),
+            height: Val::Px(size[0] as f32),
+            ..default()
+        },
+        Outline::new(Val::Px(1.0), Val::ZERO, Color::Srgba(GRAY)),
+        ImageNode::new(relief),
+        Interaction::default(),
+        RelativeCursorPosition::default(),
+        StateScoped(Screen::Playing),
+        children![(
+            Node {
+                position_type: PositionType::Absolute,
+                width: Val::Percent(100.0),
+                height: Val::Percent(100.0),
+                ..default()
+            },
+            ImageNode::new(overlay),
+        )],
+    ));
+}
+
+fn create_image(size: [usize; 2]) -> Image {
+    let extent = Extent3d {
+        width: size[1] as u32,
+        height: size[0] as u32,
+        depth_or_array_layers: 1,
+    };
+    Image::new_fill(extent, TextureDimension::D2, &[0, 0, 0, 0], TextureFormat::Rgba8UnormSrgb, RenderAssetUsages::default())
+}
+
+fn update_relief(
+    minimap: Single<&Minimap>,
+    mut level: Single<&mut TerrainData, With<LevelLabel>>,
+    mut images: ResMut<Assets<Image>>,
+) {
+    if !level.block_info.iter().any(|bi| bi.minimap_dirty) { return; }
+
+    let Some(elevation) = level.layers.get(&TerrainLayer::Elevation).cloned()
+    else { return; };
+    let Some(image) = images.get_mut(&minimap.relief)
+    else { return; };
+
+    for bi in level.block_info.iter_mut().filter(|bi| bi.minimap_dirty) {
+        bi.minimap_dirty = false;
+
+        let rows = pixel_range(&bi.range.0, minimap.step, minimap.size[0]);
+        let cols = pixel_range(&bi.range.1, minimap.step, minimap.size[1]);
+        draw_relief(image, &elevation, rows, cols, minimap.step);
+    }
+}
+
+/**
+ * Range of pixels affected by a change to a range of points.  Each pixel is shaded by the
+ * slope to the next pixel along, so the pixel before the range is affected too.
+ */
+fn pixel_range(points: &Range<usize>, step: usize, size: usize) -> Range<usize> {
+    let start = points.start.div_ceil(step).saturating_sub(1);
+    let end = points.end.div_ceil(step).min(size);
+    start..end
+}
+
+fn draw_relief(image: &mut Image, elevation: &ChunkedArray, rows: Range<usize>, cols: Range<usize>, step: usize) {
+    if rows.is_empty() || cols.is_empty() { return; }
+
+    /* Take one more sample past the end in each direction, for the slope of the last pixel */
+    let heights = elevation.slice(
+        rows.start * step..rows.end * step + 1,
+        cols.start * step..cols.end * step + 1,
+        step);
+    let (num_rows, num_cols) = heights.dim();
+
+    for i in 0..rows.len() {
+        for j in 0..cols.len() {
+            let height = heights[(i, j)];
+            let dx = (heights[(i, (j + 1).min(num_cols - 1))] - height) / step as f32;
+            let dz = (heights[((i + 1).min(num_rows - 1), j)] - height) / step as f32;
+            let normal = Vec3::new(-dx, 1.0, -dz).normalize();
+            put_pixel(image, (cols.start + j, rows.start + i), relief_colour(height, normal));
+        }
+    }
+}
+
+fn relief_colour(height: f32, normal: Vec3) -> [u8; 4] {
+    if height <= 0.0 {
+        return WATER_COLOUR.to_u8_array();
+    }
+
+    let next = ELEVATION_TINTS.iter()
+        .position(|(e, _)| *e > height)
+        .unwrap_or(ELEVATION_TINTS.len());
+    let tint = match next {
+        0 => ELEVATION_TINTS[0].1,
+        n if n == ELEVATION_TINTS.len() => ELEVATION_TINTS[n - 1].1,
+        n => {
+            let (e0, c0) = ELEVATION_TINTS[n - 1];
+            let (e1, c1) = ELEVATION_TINTS[n];
+            c0.mix(&c1, (height - e0) / (e1 - e0))
+        }
+    };
+
+    let light = normal.dot(LIGHT_DIRECTION.normalize()).max(0.0);
+    let shade = AMBIENT_LIGHT + (1.0 - AMBIENT_LIGHT) * light;
+    (tint * shade).with_alpha(1.0).to_u8_array()
+}
+
+fn update_overlay(
+    minimap: Single<Ref<Minimap>>,
+    camera: Single<(&Camera, &GlobalTransform, &CameraState)>,
+    level: Single<&TerrainData, With<LevelLabel>>,
+    curves: Query<&SegmentCurve>,
+    changed_curves: Query<(), Changed<SegmentCurve>>,
+    trains: Query<&GlobalTransform, With<TrainCar>>,
+    workers: Query<&GlobalTransform, With<Worker>>,
+    moved: Query<(), (Changed<GlobalTransform>, Or<(With<Camera>, With<TrainCar>, With<Worker>)>)>,
+    mut removed_segments: RemovedComponents<Segment>,
+    mut images: ResMut<Assets<Image>>,
+) {
+    let removed = removed_segments.read().count() > 0;
+    if !minimap.is_added() && moved.is_empty() && changed_curves.is_empty() && !removed { return; }
+
+    let Some(image) = images.get_mut(&minimap.overlay)
+    else { return; };
+    if let Some(data) = image.data.as_mut() {
+        data.fill(0);
+    }
+
+    let to_pixel = |point: Vec3| point.xz() / minimap.step as f32;
+
+    for curve in curves.iter() {
+        let path = curve.path(CURVE_STEP);
+        for pair in path.windows(2) {
+            draw_line(image, to_pixel(pair[0].translation), to_pixel(pair[1].translation), TRACK_COLOUR);
+        }
+    }
+
+    let (camera, camera_transform, state) = *camera;
+    let ground = level.elevation_at(state.focus.xz());
+    if let Some(corners) = frustum_footprint(camera, camera_transform, ground) {
+        for i in 0..corners.len() {
+            let next = (i + 1) % corners.len();
+            draw_line(image, corners[i] / minimap.step as f32, corners[next] / minimap.step as f32, FRUSTUM_COLOUR);
+        }
+    }
+
+    for transform in workers.iter() {
+        draw_dot(image, to_pixel(transform.translation()), 0, WORKER_COLOUR);
+    }
+
+    for transform in trains.iter() {
+        draw_dot(image, to_pixel(transform.translation()), 1, TRAIN_COLOUR);
+    }
+}
+
+/**
+ * Where the corners of the camera's view meet the ground, in world space.  Corners that
+ * don't meet the ground are cut off at a fixed distance.
+ */
+fn frustum_footprint(camera: &Camera, transform: &GlobalTransform, ground: f32) -> Option<[Vec2; 4]> {
+    let size = camera.logical_viewport_size()?;
+    let viewport_corners = [Vec2::ZERO, Vec2::new(size.x, 0.0), size, Vec2::new(0.0, size.y)];
+
+    let mut corners = [Vec2::ZERO; 4];
+    for (corner, viewport_corner) in corners.iter_mut().zip(viewport_corners) {
+        let ray = camera.viewport_to_world(transform, viewport_corner).ok()?;
+        let distance = ray.intersect_plane(Vec3::new(0.0, ground, 0.0), InfinitePlane3d::new(Vec3::Y))
+            .map_or(FRUSTUM_FAR_DISTANCE, |d| d.min(FRUSTUM_FAR_DISTANCE));
+        *corner = ray.get_point(distance).xz();
+    }
+
+    Some(corners)
+}
+
+fn click_minimap(
+    minimap: Single<(&Minimap, &Interaction, &RelativeCursorPosition)>,
+    mut camera: Single<&mut CameraState>,
+) {
+    let (minimap, interaction, cursor) = *minimap;
+
+    /* Keep following the cursor while the button is held down */
+    if *interaction != Interaction::Pressed { return; }
+    let Some(position) = cursor.normalized else { return; };
+
+    let map_size = Vec2::new(minimap.size[1] as f32, minimap.size[0] as f32) * minimap.step as f32;
+    let focus = position * map_size;
+    let focus = Vec3::new(focus.x, camera.focus.y, focus.y)
+        .clamp(camera.focus_range.start, camera.focus_range.end);
+    if camera.focus != focus {
+        camera.focus = focus;
+    }
+}
+
+fn put_pixel(image: &mut Image, (x, y): (usize, usize), colour: [u8; 4]) {
+    if let Some(bytes) = image.pixel_bytes_mut(UVec3::new(x as u32, y as u32, 0)) {
+        bytes.copy_from_slice(&colour);
+    }
+}
+
+fn draw_dot(image: &mut Image, centre: Vec2, radius: isize, colour: [u8; 4]) {
+    let (x, y) = (centre.x.floor() as isize, centre.y.floor() as isize);
+    for i in y - radius..=y + radius {
+        for j in x - radius..=x + radius {
+            if i >= 0 && j >= 0 {
+                put_pixel(image, (j as usize, i as usize), colour);
+            }
+        }
+    }
+}
+
+fn draw_line(image: &mut Image, from: Vec2, to: Vec2, colour: [u8; 4]) {
+    let steps = (to - from).abs().max_element().ceil().max(1.0) as usize;
+    for i in 0..=steps {
+        draw_dot(image, from.lerp(to, i as f32 / steps as f32), 0, colour);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pixel_range() {
+        /* A block of points 64..129 at a step of 16 covers pixels 4..9, and changes the
+           shading of pixel 3 */
+        assert_eq!(pixel_range(&(64..129), 16, 100), 3..9);
+        assert_eq!(pixel_range(&(0..65), 16, 100), 0..5);
+        assert_eq!(pixel_range(&(128..193), 16, 10), 7..10);
+    }
+
+    #[test]
+    fn test_draw_relief() {
+        let elevation = ChunkedArray::new([33, 33], 16);
+        elevation.assign((0, 0), ndarray::Array2::from_shape_fn((33, 33), |(_, c)| 40.0 - c as f32).view());
+
+        let mut image = create_image([9, 9]);
+        draw_relief(&mut image, &elevation, 0..9, 0..9, 4);
+
+        /* Slopes facing away from the light are darker than flat ground at the same height */
+        let sloped = image.pixel_bytes(UVec3::new(2, 2, 0)).unwrap().to_vec();
+        let flat = relief_colour(32.0, Vec3::Y);
+        assert!(sloped[1] < flat[1]);
+        assert_eq!(sloped[3], 255);
+
+        /* The last column has no slope to the next pixel, so it's flat */
+        let last = image.pixel_bytes(UVec3::new(8, 2, 0)).unwrap();
+        assert_eq!(last, relief_colour(8.0, Vec3::Y));
+    }
+}
@@ -1 +1,3 @@
+pub mod minimap;
 pub mod toolbar;
+pub mod validation;
@@ -0,0 +1,113 @@
+use bevy::color::palettes::basic::GRAY;
+use bevy::prelude::*;
+
+use crate::camera::CameraState;
+use crate::screens::Screen;
+use crate::theme::Theme;
+use crate::track::validation::TrackValidation;
+
+/**
+ * A list of the places where the track breaks the level's design limits.  Clicking an
+ * entry moves the camera there.
+ */
+pub struct ValidationPanelPlugin;
+
+impl Plugin for ValidationPanelPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, (
+            update_validation_panel,
+            click_violation,
+        ).run_if(in_state(Screen::Playing)));
+    }
+}
+
+/** Most violations listed; the rest are counted. */
+const MAX_ENTRIES: usize = 10;
+
+const VIOLATION_TEXT: Color = Color::srgb(1.0, 0.4, 0.4);
+
+#[derive(Component)]
+pub struct ValidationPanel;
+
+#[derive(Component)]
+struct ViolationEntry(Vec3);
+
+pub(crate) fn create_validation_panel(
+    mut commands: Commands,
+) {
+    commands.spawn((
+        Name::new("Validation"),
+        ValidationPanel,
+        Node {
+            position_type: PositionType::Absolute,
+            top: Val::Px(100.0),
+            right: Val::Px(10.0),
+            flex_direction: FlexDirection::Column,
+            align_items: AlignItems::End,
+            ..default()
+        },
+        StateScoped(Screen::Playing),
+    ));
+}
+
+fn update_validation_panel(
+    validation: Res<TrackValidation>,
+    panel: Single<(Entity, Ref<ValidationPanel>)>,
+    theme: Res<Theme>,
+    mut commands: Commands,
+) {
+    let (panel_id, panel) = panel.into_inner();
+    if !validation.is_changed() && !panel.is_added() { return; }
+
+    commands.entity(panel_id).despawn_related::<Children>();
+
+    let font = TextFont {
+        font: theme.font.clone(),
+        font_size: 16.0,
+        ..default()
+    };
+
+    let count = validation.violations.len();
+    let heading = match count {
+        0 => "Track OK".to_owned(),
+        1 => "Track: 1 problem".to_owned(),
+        n => format!("Track: {n} problems"),
+    };
+    commands.spawn((
+        Text(heading),
+        font.clone(),
+        TextColor(Color::Srgba(GRAY)),
+        ChildOf(panel_id),
+    ));
+
+    for violation in validation.violations.iter().take(MAX_ENTRIES) {
+        commands.spawn((
+            Button,
+            ViolationEntry(violation.location),
+            Text(violation.to_string()),
+            font.clone(),
+            TextColor(VIOLATION_TEXT),
+            ChildOf(panel_id),
+        ));
+    }
+
+    if count > MAX_ENTRIES {
+        commands.spawn((
+            Text(format!("and {} more", count - MAX_ENTRIES)),
+            font,
+            TextColor(Color::Srgba(GRAY)),
+            ChildOf(panel_id),
+        ));
+    }
+}
+
+fn click_violation(
+    entries: Query<(&ViolationEntry, &Interaction), Changed<Interaction>>,
+    mut camera: Single<&mut CameraState>,
+) {
+    for (entry, interaction) in entries.iter() {
+        if *interaction != Interaction::Pressed { continue; }
+
+        camera.focus = entry.0.clamp(camera.focus_range.start, camera.focus_range.end);
+    }
+}